@@ -29,12 +29,12 @@ async fn main() {
     // Retrieve and display query progress events
     let mut progress = client.subscribe_progress();
     let progress_task = tokio::task::spawn(async move {
-        let mut current_query = Uuid::nil();
+        let mut current_query = String::new();
         let mut progress_total = Progress::default();
         while let Ok((query, progress)) = progress.recv().await {
             if query != current_query {
                 progress_total = Progress::default();
-                current_query = query;
+                current_query.clone_from(&query);
             }
             progress_total += progress;
             println!(
@@ -1,16 +1,153 @@
-use std::{collections::VecDeque, str::FromStr};
+use std::{collections::VecDeque, future::Future, str::FromStr};
 
 use crate::Result;
+use futures_util::FutureExt;
 use indexmap::IndexMap;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::{
+    convert::Row,
     io::{ClickhouseRead, ClickhouseWrite},
+    protocol::DBMS_MIN_REVISION_WITH_CUSTOM_SERIALIZATION,
     types::{DeserializerState, SerializerState, Type},
     values::Value,
     KlickhouseError,
 };
 
+const SERIALIZATION_KIND_DEFAULT: u8 = 0;
+const SERIALIZATION_KIND_SPARSE: u8 = 1;
+/// Set on the last group of the positions of a sparse column, which only counts its trailing defaults.
+const END_OF_GRANULE_FLAG: u64 = 1 << 62;
+
+/// Serialization of a column with a custom serialization, from the kinds sent before its data.
+#[derive(Debug, PartialEq)]
+enum Serialization {
+    Default,
+    Sparse,
+    /// A tuple with sparse elements, and the serializations of its elements.
+    Tuple(Vec<Serialization>),
+}
+
+/// Number of serialization kinds sent for a column of `type_`: one, and one per element of tuples.
+fn serialization_kind_count(type_: &Type) -> usize {
+    match type_ {
+        Type::Tuple(elements) => 1 + elements.iter().map(serialization_kind_count).sum::<usize>(),
+        _ => 1,
+    }
+}
+
+/// Reads the serialization kinds of a column with a custom serialization: the kind of the column, followed by the
+/// kinds of its tuple elements, depth first.
+async fn read_serialization<R: ClickhouseRead>(
+    reader: &mut R,
+    name: &str,
+    type_: &Type,
+) -> Result<Serialization> {
+    let mut kinds = Vec::with_capacity(serialization_kind_count(type_));
+    for _ in 0..kinds.capacity() {
+        kinds.push(reader.read_u8().await?);
+    }
+    serialization(name, type_, &mut kinds.into_iter())
+}
+
+fn serialization(
+    name: &str,
+    type_: &Type,
+    kinds: &mut impl Iterator<Item = u8>,
+) -> Result<Serialization> {
+    let kind = kinds.next().unwrap_or(SERIALIZATION_KIND_DEFAULT);
+    match (kind, type_) {
+        (SERIALIZATION_KIND_DEFAULT, Type::Tuple(elements)) => {
+            let elements = elements
+                .iter()
+                .map(|element| serialization(name, element, kinds))
+                .collect::<Result<Vec<_>>>()?;
+            if elements
+                .iter()
+                .all(|element| *element == Serialization::Default)
+            {
+                Ok(Serialization::Default)
+            } else {
+                Ok(Serialization::Tuple(elements))
+            }
+        }
+        (SERIALIZATION_KIND_DEFAULT, _) => Ok(Serialization::Default),
+        // Tuples are never sparse themselves, only their elements.
+        (SERIALIZATION_KIND_SPARSE, type_) if !matches!(type_, Type::Tuple(_)) => {
+            Ok(Serialization::Sparse)
+        }
+        (kind, _) => Err(KlickhouseError::ProtocolError(format!(
+            "unsupported serialization kind {kind} for column {name}"
+        ))),
+    }
+}
+
+/// Reads the data of a column of `serialization`, after the prefix of `type_`. The elements of a tuple are read one
+/// after the other, each with its own serialization.
+fn read_column<'a, R: ClickhouseRead>(
+    reader: &'a mut R,
+    type_: &'a Type,
+    serialization: &'a Serialization,
+    rows: usize,
+    state: &'a mut DeserializerState,
+) -> impl Future<Output = Result<Vec<Value>>> + Send + 'a {
+    async move {
+        match serialization {
+            Serialization::Default => type_.deserialize_column(reader, rows, state).await,
+            Serialization::Sparse => read_sparse_column(reader, type_, rows, state).await,
+            Serialization::Tuple(elements) => {
+                let mut tuples = vec![Vec::with_capacity(elements.len()); rows];
+                for (type_, serialization) in type_.unwrap_tuple().iter().zip(elements) {
+                    let values = read_column(reader, type_, serialization, rows, state).await?;
+                    for (tuple, value) in tuples.iter_mut().zip(values) {
+                        tuple.push(value);
+                    }
+                }
+                Ok(tuples.into_iter().map(Value::Tuple).collect())
+            }
+        }
+    }
+    .boxed()
+}
+
+/// Reads a sparse column, after its prefix: the positions of the values that aren't the default of the type, then
+/// these values.
+async fn read_sparse_column<R: ClickhouseRead>(
+    reader: &mut R,
+    type_: &Type,
+    rows: usize,
+    state: &mut DeserializerState,
+) -> Result<Vec<Value>> {
+    let mut positions = vec![];
+    let mut row = 0u64;
+    loop {
+        // Each group counts the defaults before the next value.
+        let group = reader.read_var_uint().await?;
+        if group & END_OF_GRANULE_FLAG != 0 {
+            row += group & !END_OF_GRANULE_FLAG;
+            break;
+        }
+        row += group;
+        positions.push(row as usize);
+        row += 1;
+    }
+    if row != rows as u64 {
+        return Err(KlickhouseError::ProtocolError(format!(
+            "sparse column has {row} rows, expected {rows}"
+        )));
+    }
+    let mut column = vec![type_.default_value(); rows];
+    if !positions.is_empty() {
+        let values = type_
+            .deserialize_column(reader, positions.len(), state)
+            .await?;
+        for (position, value) in positions.into_iter().zip(values) {
+            column[position] = value;
+        }
+    }
+    Ok(column)
+}
+
 /// Metadata about a block
 #[derive(Debug, Clone)]
 pub struct BlockInfo {
@@ -139,6 +276,31 @@ impl Iterator for BlockRowIntoIter {
 }
 
 impl Block {
+    /// Serializes `rows` into a block, with the given type for each column.
+    /// Columns that are not serialized by any row are left out of the block.
+    pub fn from_rows<T: Row>(rows: Vec<T>, column_types: IndexMap<String, Type>) -> Result<Self> {
+        let mut block = Block {
+            info: BlockInfo::default(),
+            rows: rows.len() as u64,
+            column_types,
+            column_data: IndexMap::new(),
+        };
+        for row in rows {
+            for (key, value) in row.serialize_row(&block.column_types)? {
+                let type_ = block.column_types.get(&*key).ok_or_else(|| {
+                    KlickhouseError::ProtocolError(format!("missing type for data, column: {key}"))
+                })?;
+                type_.validate_value(&value)?;
+                if let Some(column) = block.column_data.get_mut(&*key) {
+                    column.push(value);
+                } else {
+                    block.column_data.insert(key.into_owned(), vec![value]);
+                }
+            }
+        }
+        Ok(block)
+    }
+
     /// Create a borrowing iterator for all rows
     pub fn iter_rows(&self) -> BlockRowIter<'_> {
         BlockRowIter {
@@ -195,15 +357,20 @@ impl Block {
             let name = reader.read_utf8_string().await?;
             let type_name = reader.read_utf8_string().await?;
             let type_ = Type::from_str(&type_name)?;
+            let has_custom = revision >= DBMS_MIN_REVISION_WITH_CUSTOM_SERIALIZATION
+                && reader.read_u8().await? != 0;
+            let serialization = match has_custom {
+                true => read_serialization(reader, &name, &type_).await?,
+                false => Serialization::Default,
+            };
             block.column_types.insert(name.clone(), type_.clone());
             let mut state = DeserializerState {};
-            let row_data = if rows > 0 {
-                type_.deserialize_prefix(reader, &mut state).await?;
-                type_
-                    .deserialize_column(reader, rows as usize, &mut state)
-                    .await?
-            } else {
+            let row_data = if rows == 0 {
                 vec![]
+            } else {
+                // A sparse column has the prefix of its values, and a tuple the prefixes of all its elements.
+                type_.deserialize_prefix(reader, &mut state).await?;
+                read_column(reader, &type_, &serialization, rows as usize, &mut state).await?
             };
             block.column_data.insert(name, row_data);
         }
@@ -232,6 +399,9 @@ impl Block {
         for (name, (type_, data)) in joined {
             writer.write_string(&name).await?;
             writer.write_string(&type_.to_string()).await?;
+            if revision >= DBMS_MIN_REVISION_WITH_CUSTOM_SERIALIZATION {
+                writer.write_u8(0).await?;
+            }
             if data.len() != self.rows as usize {
                 return Err(KlickhouseError::ProtocolError(format!(
                     "row and column length mismatch. {} != {}",
@@ -248,3 +418,88 @@ impl Block {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::DBMS_TCP_PROTOCOL_VERSION;
+
+    #[tokio::test]
+    async fn test_read_sparse_column() {
+        let mut data: Vec<u8> = vec![];
+        BlockInfo::default().write(&mut data).await.unwrap();
+        data.write_var_uint(1).await.unwrap();
+        data.write_var_uint(5).await.unwrap();
+        data.write_string("n").await.unwrap();
+        data.write_string("UInt64").await.unwrap();
+        data.write_u8(1).await.unwrap();
+        data.write_u8(SERIALIZATION_KIND_SPARSE).await.unwrap();
+        // Values at rows 1 and 4, between and followed by defaults.
+        for group in [1, 2, END_OF_GRANULE_FLAG] {
+            data.write_var_uint(group).await.unwrap();
+        }
+        data.write_u64_le(10).await.unwrap();
+        data.write_u64_le(20).await.unwrap();
+
+        let block = Block::read(&mut &data[..], DBMS_TCP_PROTOCOL_VERSION)
+            .await
+            .unwrap();
+        assert_eq!(block.rows, 5);
+        assert_eq!(
+            block.column_data.get("n").unwrap(),
+            &[0u64, 10, 0, 0, 20].map(Value::UInt64).to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_sparse_tuple_element() {
+        let mut data: Vec<u8> = vec![];
+        BlockInfo::default().write(&mut data).await.unwrap();
+        data.write_var_uint(1).await.unwrap();
+        data.write_var_uint(3).await.unwrap();
+        data.write_string("t").await.unwrap();
+        data.write_string("Tuple(UInt64, String)").await.unwrap();
+        data.write_u8(1).await.unwrap();
+        for kind in [
+            SERIALIZATION_KIND_DEFAULT,
+            SERIALIZATION_KIND_SPARSE,
+            SERIALIZATION_KIND_DEFAULT,
+        ] {
+            data.write_u8(kind).await.unwrap();
+        }
+        // The sparse first element has a value at row 2 only, the second element is a full column.
+        for group in [2, END_OF_GRANULE_FLAG] {
+            data.write_var_uint(group).await.unwrap();
+        }
+        data.write_u64_le(7).await.unwrap();
+        for value in ["a", "b", "c"] {
+            data.write_string(value).await.unwrap();
+        }
+
+        let block = Block::read(&mut &data[..], DBMS_TCP_PROTOCOL_VERSION)
+            .await
+            .unwrap();
+        let tuple = |number, string: &str| {
+            Value::Tuple(vec![Value::UInt64(number), Value::String(string.into())])
+        };
+        assert_eq!(
+            block.column_data.get("t").unwrap(),
+            &vec![tuple(0, "a"), tuple(0, "b"), tuple(7, "c")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_sparse_tuple() {
+        let mut data: Vec<u8> = vec![];
+        BlockInfo::default().write(&mut data).await.unwrap();
+        data.write_var_uint(1).await.unwrap();
+        data.write_var_uint(1).await.unwrap();
+        data.write_string("t").await.unwrap();
+        data.write_string("Tuple(UInt64)").await.unwrap();
+        data.write_u8(1).await.unwrap();
+        data.write_u8(SERIALIZATION_KIND_SPARSE).await.unwrap();
+        data.write_u8(SERIALIZATION_KIND_DEFAULT).await.unwrap();
+        let result = Block::read(&mut &data[..], DBMS_TCP_PROTOCOL_VERSION).await;
+        assert!(matches!(result, Err(KlickhouseError::ProtocolError(_))));
+    }
+}
@@ -1,10 +1,16 @@
 use std::collections::VecDeque;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
 use std::time::Duration;
 
 use futures_util::{stream, Stream, StreamExt};
 use indexmap::IndexMap;
 use log::*;
-use protocol::CompressionMethod;
 use tokio::{
     io::{AsyncRead, AsyncWrite, BufReader, BufWriter},
     net::{TcpStream, ToSocketAddrs},
@@ -12,23 +18,29 @@ use tokio::{
     sync::{
         broadcast,
         mpsc::{self, Receiver},
-        oneshot,
+        oneshot, OwnedSemaphorePermit, Semaphore,
     },
+    time::{sleep_until, Instant},
 };
-use tokio_stream::wrappers::ReceiverStream;
 use uuid::Uuid;
 
+#[cfg(feature = "tls")]
+use crate::TlsOptions;
 use crate::{
+    auth::{self, Authentication},
     block::{Block, BlockInfo},
     convert::Row,
     internal_client_in::InternalClientIn,
     internal_client_out::{
-        ClientHello, ClientInfo, InternalClientOut, Query, QueryKind, QueryProcessingStage,
+        BlockEncoding, ClientHello, ClientInfo, InternalClientOut, Query, QueryKind,
+        QueryProcessingStage,
     },
-    io::{ClickhouseRead, ClickhouseWrite},
+    io::{ClickhouseRead, ClickhouseWrite, WriteTimeout},
     progress::Progress,
-    protocol::{self, ServerPacket},
-    KlickhouseError, ParsedQuery, RawRow, Result,
+    protocol::{self, Compression, ServerPacket, TablesStatusResponse},
+    retry::{self, RetryPolicy},
+    KlickhouseError, ParsedQuery, ProfileEvent, QueryBuilder, QueryEvent, QueryHandle,
+    QuerySettings, RawRow, Result, ServerLogRecord,
 };
 
 // Maximum number of progress statuses to keep in memory. New statuses evict old ones.
@@ -37,120 +49,591 @@ const PROGRESS_CAPACITY: usize = 100;
 // Default maximum number of pending queries in the queue.
 const DEFAULT_MAX_PENDING_QUERIES: usize = 10_000;
 
-struct InnerClient<R: ClickhouseRead, W: ClickhouseWrite> {
-    input: InternalClientIn<R>,
+// Default size from which blocks are compressed on the blocking thread pool.
+const DEFAULT_BLOCKING_COMPRESSION_THRESHOLD: usize = 1024 * 1024;
+
+// Default client name sent in the ClientInfo of queries.
+const DEFAULT_CLIENT_NAME: &str = "ClickHouseclient";
+
+/// Writer of every connection, boxed so that a client can reconnect over a new stream.
+type BoxedWriter = BufWriter<Box<dyn AsyncWrite + Unpin + Send + Sync>>;
+
+/// Boxes the writer of a connection, failing writes blocked for longer than [`ClientOptions::write_timeout`].
+fn boxed_writer(
+    writer: impl AsyncWrite + Unpin + Send + Sync + 'static,
+    options: &ClientOptions,
+) -> BoxedWriter {
+    let writer: Box<dyn AsyncWrite + Unpin + Send + Sync> = match options.write_timeout {
+        Some(timeout) => Box::new(WriteTimeout::new(writer, timeout)),
+        None => Box::new(writer),
+    };
+    BufWriter::new(writer)
+}
+
+struct InnerClient<W: ClickhouseWrite> {
+    /// Packets parsed by the reader task, see [`InternalClientIn::run`].
+    packets: mpsc::Receiver<Result<ServerPacket>>,
     output: InternalClientOut<W>,
     options: ClientOptions,
     pending_queries: VecDeque<PendingQuery>,
-    executing_query: Option<(Uuid, mpsc::Sender<Result<Block>>)>,
-    progress: broadcast::Sender<(Uuid, Progress)>,
+    executing_query: Option<ExecutingQuery>,
+    progress: broadcast::Sender<(String, Progress)>,
+    /// Callers of [`Client::ping`] waiting for the next Pong.
+    ping_waiters: Vec<oneshot::Sender<()>>,
+    /// When the unanswered Ping was sent, if any.
+    ping_sent_at: Option<Instant>,
+    /// Last time a packet was sent or received, to schedule idle pings.
+    last_activity: Instant,
+    /// Last time a packet was received, for [`ClientOptions::read_timeout`].
+    last_received: Instant,
+    /// Tables status requests received while a query was executing.
+    pending_tables_status: VecDeque<PendingTablesStatus>,
+    /// Callers of [`Client::tables_status`] waiting for a response, in request order.
+    tables_status_waiters: VecDeque<oneshot::Sender<Result<TablesStatusResponse>>>,
+    /// Successful `SET` and `USE` statements, replayed after reconnecting. Only recorded with
+    /// [`ClientOptions::reconnect`].
+    session: IndexMap<String, ParsedQuery>,
+    /// Failed reconnection attempts since the last successful handshake.
+    reconnect_attempts: u32,
+    /// Salt of the current connection, with [`Authentication::InterserverSecret`].
+    interserver_salt: Vec<u8>,
+    /// Generation of the last dispatched query, carried by its data blocks.
+    query_generation: u64,
+}
+
+struct PendingTablesStatus {
+    tables: Vec<(String, String)>,
+    response: oneshot::Sender<Result<TablesStatusResponse>>,
 }
 
 struct PendingQuery {
-    query: String,
-    response: oneshot::Sender<mpsc::Receiver<Result<Block>>>,
+    query: ParsedQuery,
+    cancel_on_drop: bool,
+    response: oneshot::Sender<Result<QueryHandle>>,
+    /// Queue slot of [`QueueOverflowPolicy::Block`], released once the query is dispatched.
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+struct ExecutingQuery {
+    id: String,
+    /// Distinguishes this query from the other ones of the client, including retries with the same id.
+    generation: u64,
+    sender: mpsc::Sender<Result<QueryEvent>>,
+    /// Whether to cancel the query server-side once the block receiver is dropped.
+    cancel_on_drop: bool,
+    /// Set once a cancel packet was sent, remaining packets are drained until end of stream.
+    cancelled: bool,
+    /// The query to record in the session once it succeeds, if it is a `SET` or `USE` statement.
+    session_statement: Option<(String, ParsedQuery)>,
+    /// When the query times out, see [`ParsedQuery::with_timeout`].
+    deadline: Option<Instant>,
+    /// Running total of the progress events, shared with the query handle.
+    /// Progress events themselves may be dropped when the handle isn't read, this total never misses one.
+    progress: Arc<Mutex<Progress>>,
+}
+
+/// Delivers a final error to a query without waiting for its consumer, whose block channel may be full.
+fn fail_query(sender: mpsc::Sender<Result<QueryEvent>>, error: KlickhouseError) {
+    tokio::spawn(async move {
+        let _ = sender.send(Err(error)).await;
+    });
+}
+
+/// Resolves once the receiver of `sender` is dropped, never without `sender`.
+async fn receiver_dropped(sender: Option<mpsc::Sender<Result<QueryEvent>>>) {
+    match sender {
+        Some(sender) => sender.closed().await,
+        None => std::future::pending().await,
+    }
 }
 
-impl<R: ClickhouseRead + 'static, W: ClickhouseWrite> InnerClient<R, W> {
-    pub fn new(reader: R, writer: W, options: ClientOptions) -> Self {
+/// Returns the key under which `query` is recorded in the session, if it changes the session state.
+/// Only the last `USE` statement matters, while `SET` statements are deduplicated by their text.
+fn session_key(query: &str) -> Option<String> {
+    let query = query.trim().trim_end_matches(';').trim_end();
+    let keyword = query.split_whitespace().next()?;
+    if keyword.eq_ignore_ascii_case("USE") {
+        Some("USE".to_string())
+    } else if keyword.eq_ignore_ascii_case("SET") {
+        Some(query.to_string())
+    } else {
+        None
+    }
+}
+
+impl<W: ClickhouseWrite> InnerClient<W> {
+    pub fn new<R: ClickhouseRead + 'static>(reader: R, writer: W, options: ClientOptions) -> Self {
+        let (packet_sender, packets) = mpsc::channel(1);
+        tokio::spawn(
+            InternalClientIn::new(
+                reader,
+                options.compression.method(),
+                options.blocking_compression_threshold,
+            )
+            .run(packet_sender),
+        );
         Self {
-            input: InternalClientIn::new(reader),
+            packets,
             output: InternalClientOut::new(writer),
             options,
             pending_queries: VecDeque::new(),
             executing_query: None,
             progress: broadcast::channel(PROGRESS_CAPACITY).0,
+            ping_waiters: vec![],
+            ping_sent_at: None,
+            last_activity: Instant::now(),
+            last_received: Instant::now(),
+            pending_tables_status: VecDeque::new(),
+            tables_status_waiters: VecDeque::new(),
+            session: IndexMap::new(),
+            reconnect_attempts: 0,
+            interserver_salt: vec![],
+            query_generation: 0,
+        }
+    }
+
+    /// Moves the state of this client to a new connection. Requests that were sent on the lost connection
+    /// must have been failed with [`InnerClient::connection_lost`] first.
+    fn with_stream<R: ClickhouseRead + 'static>(self, reader: R, writer: W) -> Self {
+        let mut client = InnerClient::new(reader, writer, self.options);
+        client.pending_queries = self.pending_queries;
+        client.progress = self.progress;
+        client.ping_waiters = self.ping_waiters;
+        client.pending_tables_status = self.pending_tables_status;
+        client.session = self.session;
+        client.reconnect_attempts = self.reconnect_attempts;
+        client.query_generation = self.query_generation;
+        client
+    }
+
+    /// Fails the requests that were sent on the lost connection, with [`KlickhouseError::ConnectionLost`].
+    /// Queued requests are kept, to be sent on the next connection.
+    fn connection_lost(&mut self, error: &KlickhouseError) {
+        let lost = || KlickhouseError::ConnectionLost(error.to_string());
+        if let Some(current) = self.executing_query.take() {
+            if !current.cancelled {
+                fail_query(current.sender, lost());
+            }
+        }
+        for waiter in self.tables_status_waiters.drain(..) {
+            let _ = waiter.send(Err(lost()));
         }
+        // Waiting pings are sent again on the next connection.
+        self.ping_sent_at = None;
     }
 
-    async fn dispatch_query(&mut self, query: PendingQuery) -> Result<()> {
-        let id = Uuid::new_v4();
+    async fn send_tables_status(&mut self, request: PendingTablesStatus) -> Result<()> {
+        let revision = self.output.server_hello.revision_version;
+        if revision < protocol::DBMS_MIN_REVISION_WITH_TABLES_STATUS {
+            let _ = request
+                .response
+                .send(Err(KlickhouseError::ProtocolError(format!(
+                    "server revision {revision} is too old for tables status requests"
+                ))));
+            return Ok(());
+        }
+        self.output
+            .send_tables_status_request(&request.tables)
+            .await?;
+        self.tables_status_waiters.push_back(request.response);
+        Ok(())
+    }
+
+    async fn send_ping(&mut self) -> Result<()> {
+        self.output.send_ping().await?;
+        self.ping_sent_at = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Sends the pings and tables status requests received while a query was executing, then dispatches the
+    /// next pending query. The server answers them in order, so their responses are received before the query results.
+    async fn query_finished(&mut self) -> Result<()> {
+        if !self.ping_waiters.is_empty() && self.ping_sent_at.is_none() {
+            self.send_ping().await?;
+        }
+        while let Some(request) = self.pending_tables_status.pop_front() {
+            self.send_tables_status(request).await?;
+        }
+        if let Some(query) = self.pending_queries.pop_front() {
+            self.dispatch_query(query).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether a packet is expected from the server: query results, a Pong or a tables status response.
+    fn awaiting_server(&self) -> bool {
+        self.executing_query.is_some()
+            || self.ping_sent_at.is_some()
+            || !self.tables_status_waiters.is_empty()
+    }
+
+    fn read_deadline(&self) -> Option<Instant> {
+        match self.options.read_timeout {
+            Some(timeout) if self.awaiting_server() => Some(self.last_received + timeout),
+            _ => None,
+        }
+    }
+
+    fn ping_deadline(&self) -> Option<Instant> {
+        if let Some(sent_at) = self.ping_sent_at {
+            return Some(sent_at + self.options.ping_timeout);
+        }
+        match self.options.idle_ping_interval {
+            Some(interval) if self.executing_query.is_none() => Some(self.last_activity + interval),
+            _ => None,
+        }
+    }
+
+    fn query_deadline(&self) -> Option<Instant> {
+        self.executing_query
+            .as_ref()
+            .filter(|current| !current.cancelled)
+            .and_then(|current| current.deadline)
+    }
+
+    /// When the timer of the client task must fire next: the read timeout, the deadline of the unanswered Ping
+    /// or the next idle ping, or the timeout of the executing query.
+    fn next_deadline(&self) -> Option<Instant> {
+        [
+            self.read_deadline(),
+            self.ping_deadline(),
+            self.query_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    async fn handle_deadline(&mut self) -> Result<()> {
+        let now = Instant::now();
+        if self.read_deadline().is_some_and(|deadline| deadline <= now) {
+            return Err(KlickhouseError::Timeout(format!(
+                "no packet received from server for {:?}",
+                self.options.read_timeout.unwrap_or_default()
+            )));
+        }
+        if self
+            .query_deadline()
+            .is_some_and(|deadline| deadline <= now)
+        {
+            if let Some(current) = self.executing_query.as_mut() {
+                debug!("query {} timed out, cancelling", current.id);
+                current.cancelled = true;
+                fail_query(
+                    current.sender.clone(),
+                    KlickhouseError::Timeout(format!("query {} timed out", current.id)),
+                );
+                self.output.send_cancel().await?;
+            }
+        }
+        if self.ping_deadline().is_some_and(|deadline| deadline <= now) {
+            if self.ping_sent_at.is_some() {
+                return Err(KlickhouseError::Timeout(format!(
+                    "no pong received from server after {:?}",
+                    self.options.ping_timeout
+                )));
+            }
+            self.send_ping().await?;
+        }
+        Ok(())
+    }
+
+    /// Why the server can't receive `query`, failing it before anything is sent.
+    fn unsendable(&self, query: &ParsedQuery) -> Option<KlickhouseError> {
+        let revision = self.output.server_hello.revision_version;
+        if revision < protocol::DBMS_MIN_REVISION_WITH_SETTINGS_SERIALIZED_AS_STRINGS
+            && !query.settings.is_empty()
+        {
+            return Some(KlickhouseError::ProtocolError(format!(
+                "server revision {revision} is too old to receive settings with queries, use `SET` statements"
+            )));
+        }
+        None
+    }
+
+    async fn dispatch_query(&mut self, mut query: PendingQuery) -> Result<()> {
+        // Queries the server can't receive fail without ending the connection.
+        while let Some(error) = self.unsendable(&query.query) {
+            let _ = query.response.send(Err(error));
+            match self.pending_queries.pop_front() {
+                Some(next) => query = next,
+                None => return Ok(()),
+            }
+        }
+        let id = query
+            .query
+            .id
+            .clone()
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        let (sender, receiver) = mpsc::channel(self.options.block_channel_size);
+        let progress = Arc::new(Mutex::new(Progress::default()));
+        self.query_generation += 1;
+        let generation = self.query_generation;
+        if query
+            .response
+            .send(Ok(QueryHandle::new(
+                id.clone(),
+                generation,
+                receiver,
+                progress.clone(),
+                self.block_encoding(),
+            )))
+            .is_err()
+        {
+            warn!("query response receiver dropped before block channel was sent");
+        }
+        let session_statement = match &self.options.reconnect {
+            Some(_) => session_key(&query.query.query).map(|key| {
+                let statement = ParsedQuery {
+                    query: query.query.query.clone(),
+                    settings: query.query.settings.clone(),
+                    parameters: query.query.parameters.clone(),
+                    id: None,
+                    external_tables: vec![],
+                    timeout: None,
+                    trace_context: None,
+                    quota_key: query.query.quota_key.clone(),
+                };
+                (key, statement)
+            }),
+            None => None,
+        };
+        self.executing_query = Some(ExecutingQuery {
+            id: id.clone(),
+            generation,
+            sender,
+            cancel_on_drop: query.cancel_on_drop,
+            cancelled: false,
+            session_statement,
+            progress,
+            deadline: query
+                .query
+                .timeout
+                .or(self.options.query_timeout)
+                .map(|timeout| Instant::now() + timeout),
+        });
+        self.send_query_packets(&id, query.query).await
+    }
+
+    /// Sends a Query packet, followed by its external tables and the empty block ending them.
+    async fn send_query_packets(&mut self, id: &str, query: ParsedQuery) -> Result<()> {
+        // Interserver queries run as secondary queries of `username`, which the server trusts.
+        let (kind, initial_user, initial_query_id, interserver_hash) =
+            match &self.options.authentication {
+                Authentication::InterserverSecret { secret, .. } => (
+                    QueryKind::SecondaryQuery,
+                    self.options.username.as_str(),
+                    id,
+                    Some(auth::interserver_hash(
+                        &self.interserver_salt,
+                        self.output.server_hello.nonce,
+                        secret,
+                        &query.query,
+                        id,
+                        &self.options.username,
+                    )),
+                ),
+                _ => (QueryKind::InitialQuery, "", "", None),
+            };
+        // Servers too old to receive settings with queries got the connection settings with `SET` statements.
+        let settings = match self.output.server_hello.revision_version
+            < protocol::DBMS_MIN_REVISION_WITH_SETTINGS_SERIALIZED_AS_STRINGS
+        {
+            true => query.settings.clone(),
+            false => self.connection_settings().merged(&query.settings),
+        };
         self.output
             .send_query(Query {
-                id: &id.to_string(),
+                id,
                 info: ClientInfo {
-                    kind: QueryKind::InitialQuery,
-                    initial_user: "",
-                    initial_query_id: "",
+                    kind,
+                    initial_user,
+                    initial_query_id,
                     initial_address: "0.0.0.0:0",
-                    os_user: "",
-                    client_hostname: "localhost",
-                    client_name: "ClickHouseclient",
+                    os_user: &self.options.os_user,
+                    client_hostname: &self.options.client_hostname,
+                    client_name: &self.options.client_name,
                     client_version_major: crate::VERSION_MAJOR,
                     client_version_minor: crate::VERSION_MINOR,
                     client_tcp_protocol_version: protocol::DBMS_TCP_PROTOCOL_VERSION,
-                    quota_key: "",
+                    quota_key: query
+                        .quota_key
+                        .as_deref()
+                        .unwrap_or(&self.options.quota_key),
                     distributed_depth: 1,
                     client_version_patch: 1,
-                    open_telemetry: None,
+                    open_telemetry: query.trace_context.as_ref(),
                 },
+                settings: &settings,
+                parameters: &query.parameters,
+                interserver_hash: interserver_hash.as_deref(),
                 stage: QueryProcessingStage::Complete,
-                compression: CompressionMethod::default(),
+                compression: self.options.compression,
                 query: &query.query,
             })
             .await?;
-
-        let (sender, receiver) = mpsc::channel(self.options.block_channel_size);
-        if query.response.send(receiver).is_err() {
-            warn!("query response receiver dropped before block channel was sent");
+        let encoding = self.block_encoding();
+        for table in query.external_tables {
+            let data = encoding.encode(table.block).await?;
+            self.output.send_data(&data, &table.name, false).await?;
         }
-        self.executing_query = Some((id, sender));
-        self.output
-            .send_data(
-                Block {
-                    info: BlockInfo::default(),
-                    rows: 0,
-                    column_types: IndexMap::new(),
-                    column_data: IndexMap::new(),
-                },
-                CompressionMethod::default(),
-                "",
-                false,
-            )
+        let data = encoding
+            .encode(Block {
+                info: BlockInfo::default(),
+                rows: 0,
+                column_types: IndexMap::new(),
+                column_data: IndexMap::new(),
+            })
             .await?;
+        self.output.send_data(&data, "", false).await?;
         Ok(())
     }
 
+    /// Encoding of the data blocks sent on the current connection.
+    fn block_encoding(&self) -> BlockEncoding {
+        BlockEncoding {
+            revision: self.output.server_hello.revision_version,
+            compression: self.options.compression,
+            blocking_threshold: self.options.blocking_compression_threshold,
+        }
+    }
+
     async fn handle_request(&mut self, request: ClientRequest) -> Result<()> {
         match request.data {
-            ClientRequestData::Query { query, response } => {
-                let query = PendingQuery { query, response };
+            ClientRequestData::Query {
+                query,
+                cancel_on_drop,
+                response,
+                permit,
+            } => {
+                let query = PendingQuery {
+                    query: *query,
+                    cancel_on_drop,
+                    response,
+                    _permit: permit,
+                };
                 if self.pending_queries.is_empty() && self.executing_query.is_none() {
                     self.dispatch_query(query).await?;
-                } else {
-                    if self.pending_queries.len() >= self.options.max_pending_queries {
-                        warn!(
-                            "pending query queue full ({} queries), dropping oldest",
-                            self.options.max_pending_queries
-                        );
-                        self.pending_queries.pop_front();
+                } else if self.pending_queries.len() >= self.options.max_pending_queries {
+                    let max = self.options.max_pending_queries;
+                    match self.options.queue_overflow_policy {
+                        QueueOverflowPolicy::RejectNew => {
+                            let _ =
+                                query
+                                    .response
+                                    .send(Err(KlickhouseError::QueueOverflow(format!(
+                                        "{max} queries already pending, query rejected"
+                                    ))));
+                        }
+                        // With `Block`, callers wait for a queue slot before sending their query, so the queue
+                        // can only be full here if it is shared with other policies.
+                        QueueOverflowPolicy::EvictOldest | QueueOverflowPolicy::Block => {
+                            warn!("pending query queue full ({max} queries), failing oldest");
+                            if let Some(evicted) = self.pending_queries.pop_front() {
+                                let _ = evicted.response.send(Err(KlickhouseError::QueueOverflow(
+                                    format!("{max} queries pending, evicted by a newer query"),
+                                )));
+                            }
+                            self.pending_queries.push_back(query);
+                        }
                     }
+                } else {
                     self.pending_queries.push_back(query);
                 }
             }
-            ClientRequestData::SendData { block, response } => {
-                self.output
-                    .send_data(block, CompressionMethod::default(), "", false)
-                    .await?;
-                if response.send(()).is_err() {
+            ClientRequestData::SendData {
+                generation,
+                data,
+                response,
+            } => {
+                // The query the data belongs to failed, timed out, or was lost with the previous connection.
+                // Its remaining blocks must not be sent as data of the next query.
+                let result = match &self.executing_query {
+                    Some(current) if current.generation == generation => {
+                        self.output.send_data(&data, "", false).await?;
+                        Ok(())
+                    }
+                    _ => {
+                        debug!("query of data block is no longer executing, block discarded");
+                        Err(KlickhouseError::ProtocolError(
+                            "query ended before all of its data was sent".to_string(),
+                        ))
+                    }
+                };
+                if response.send(result).is_err() {
                     warn!("send_data response receiver dropped");
                 }
             }
+            ClientRequestData::TablesStatus { tables, response } => {
+                let request = PendingTablesStatus { tables, response };
+                // Like pings, tables status requests are only accepted by the server between queries.
+                if self.executing_query.is_none() {
+                    self.send_tables_status(request).await?;
+                } else {
+                    self.pending_tables_status.push_back(request);
+                }
+            }
+            ClientRequestData::Ping { response } => {
+                self.ping_waiters.push(response);
+                // Pings can't be sent while a query is executing, they are sent once it finishes.
+                if self.executing_query.is_none() && self.ping_sent_at.is_none() {
+                    self.send_ping().await?;
+                }
+            }
         }
         Ok(())
     }
 
+    /// Sends a cancel packet for the executing query if its block receiver was dropped.
+    /// The server keeps sending packets until `EndOfStream` or `Exception`, which are drained
+    /// before the next pending query is dispatched.
+    async fn cancel_if_dropped(&mut self) -> Result<()> {
+        let Some(current) = self.executing_query.as_mut() else {
+            return Ok(());
+        };
+        if current.cancel_on_drop && !current.cancelled && current.sender.is_closed() {
+            debug!("block receiver dropped, cancelling query {}", current.id);
+            current.cancelled = true;
+            self.output.send_cancel().await?;
+        }
+        Ok(())
+    }
+
+    /// Forwards an event to the executing query, unless it was cancelled.
+    async fn send_event(&self, event: QueryEvent) {
+        if let Some(current) = &self.executing_query {
+            if !current.cancelled && current.sender.send(Ok(event)).await.is_err() {
+                debug!("query event receiver dropped, event discarded");
+            }
+        }
+    }
+
     async fn receive_packet(&mut self, packet: ServerPacket) -> Result<()> {
+        if !matches!(
+            packet,
+            ServerPacket::EndOfStream | ServerPacket::Exception(_)
+        ) {
+            self.cancel_if_dropped().await?;
+        }
         match packet {
-            ServerPacket::Hello(_) => {
+            ServerPacket::Hello(_) | ServerPacket::SshChallenge(_) => {
                 return Err(KlickhouseError::ProtocolError(
-                    "unexpected retransmission of server hello".to_string(),
+                    "unexpected handshake packet after server hello".to_string(),
                 ))
             }
             ServerPacket::Data(block) => {
-                if let Some((_, current)) = self.executing_query.as_ref() {
-                    if current.send(Ok(block.block)).await.is_err() {
+                if let Some(current) = self.executing_query.as_ref() {
+                    if current.cancelled {
+                        return Ok(());
+                    }
+                    if current
+                        .sender
+                        .send(Ok(QueryEvent::Data(block.block)))
+                        .await
+                        .is_err()
+                    {
                         debug!("block receiver dropped, data block discarded (expected if query stream was consumed)");
+                        self.cancel_if_dropped().await?;
                     }
                 } else {
                     return Err(KlickhouseError::ProtocolError(
@@ -159,87 +642,346 @@ impl<R: ClickhouseRead + 'static, W: ClickhouseWrite> InnerClient<R, W> {
                 }
             }
             ServerPacket::Exception(e) => {
-                if let Some((_, current)) = self.executing_query.take() {
-                    if current.send(Err(e.emit())).await.is_err() {
+                if let Some(current) = self.executing_query.take() {
+                    if current.cancelled {
+                        debug!(
+                            "cancelled query {} finished with exception: {}",
+                            current.id, e.message
+                        );
+                    } else if current.sender.send(Err(e.into())).await.is_err() {
                         warn!("block receiver dropped, server exception lost: consider consuming the full query stream");
                     }
-                    if let Some(query) = self.pending_queries.pop_front() {
-                        self.dispatch_query(query).await?;
-                    }
+                    self.query_finished().await?;
                 } else {
-                    return Err(e.emit());
+                    return Err(e.into());
                 }
             }
             ServerPacket::Progress(progress) => {
-                if let Some((id, _)) = &self.executing_query {
-                    let _ = self.progress.send((*id, progress));
+                if let Some(current) = &self.executing_query {
+                    let _ = self.progress.send((current.id.clone(), progress));
+                    *current.progress.lock().unwrap() += progress;
+                    // Progress is best-effort: never block the connection on a consumer that isn't reading events.
+                    let _ = current.sender.try_send(Ok(QueryEvent::Progress(progress)));
+                }
+            }
+            ServerPacket::Pong => {
+                self.ping_sent_at = None;
+                for waiter in self.ping_waiters.drain(..) {
+                    let _ = waiter.send(());
                 }
             }
-            ServerPacket::Pong => {}
             ServerPacket::EndOfStream => {
-                if self.executing_query.take().is_none() {
+                let Some(finished) = self.executing_query.take() else {
                     return Err(KlickhouseError::ProtocolError(
                         "received end of stream, but no executing query".to_string(),
                     ));
+                };
+                if let Some((key, statement)) = finished.session_statement {
+                    self.session.shift_remove(&key);
+                    self.session.insert(key, statement);
                 }
-                if let Some(query) = self.pending_queries.pop_front() {
-                    self.dispatch_query(query).await?;
+                self.query_finished().await?;
+            }
+            ServerPacket::ProfileInfo(info) => {
+                self.send_event(QueryEvent::ProfileInfo(info)).await;
+            }
+            ServerPacket::Totals(data) => {
+                self.send_event(QueryEvent::Totals(data.block)).await;
+            }
+            ServerPacket::Extremes(data) => {
+                self.send_event(QueryEvent::Extremes(data.block)).await;
+            }
+            ServerPacket::TablesStatusResponse(response) => {
+                match self.tables_status_waiters.pop_front() {
+                    Some(waiter) => {
+                        if waiter.send(Ok(response)).is_err() {
+                            debug!("tables status receiver dropped, response discarded");
+                        }
+                    }
+                    None => {
+                        return Err(KlickhouseError::ProtocolError(
+                            "received tables status response, but no pending request".to_string(),
+                        ))
+                    }
+                }
+            }
+            ServerPacket::Log(data) => {
+                let records = match ServerLogRecord::from_block(data.block) {
+                    Ok(records) => records,
+                    Err(e) => {
+                        warn!("failed to parse server log block: {e}");
+                        return Ok(());
+                    }
+                };
+                for record in records {
+                    if self.options.forward_server_logs {
+                        record.forward();
+                    }
+                    if let Some(current) = &self.executing_query {
+                        let _ = current.sender.try_send(Ok(QueryEvent::Log(record)));
+                    }
                 }
             }
-            ServerPacket::ProfileInfo(_) => {}
-            ServerPacket::Totals(_) => {}
-            ServerPacket::Extremes(_) => {}
-            ServerPacket::TablesStatusResponse(_) => {}
-            ServerPacket::Log(_) => {}
             ServerPacket::TableColumns(_) => {}
             ServerPacket::PartUUIDs(_) => {}
             ServerPacket::ReadTaskRequest => {}
+            ServerPacket::ProfileEvents(data) => {
+                let events = match ProfileEvent::from_block(data.block) {
+                    Ok(events) => events,
+                    Err(e) => {
+                        warn!("failed to parse profile events block: {e}");
+                        return Ok(());
+                    }
+                };
+                if let Some(current) = &self.executing_query {
+                    let _ = current
+                        .sender
+                        .try_send(Ok(QueryEvent::ProfileEvents(events)));
+                }
+            }
+            // `DateTime` columns without a timezone are read as UTC, whatever the session timezone.
+            ServerPacket::TimezoneUpdate(timezone) => {
+                debug!("server session timezone changed to {timezone}");
+            }
         }
         Ok(())
     }
 
-    async fn run_inner(mut self, mut input: Receiver<ClientRequest>) -> Result<()> {
-        self.output
-            .send_hello(ClientHello {
+    /// Settings sent with every query of the connection: the ones of the compression method and
+    /// [`ClientOptions::settings`].
+    fn connection_settings(&self) -> QuerySettings {
+        self.options
+            .compression
+            .settings()
+            .merged(&self.options.settings)
+    }
+
+    /// Runs `statement` on the connection, before any queued request is sent.
+    async fn run_statement(&mut self, statement: ParsedQuery) -> Result<()> {
+        self.send_query_packets(&Uuid::new_v4().to_string(), statement)
+            .await?;
+        loop {
+            match self.next_packet().await? {
+                ServerPacket::EndOfStream => return Ok(()),
+                ServerPacket::Exception(e) => return Err(KlickhouseError::ServerException(e)),
+                _ => (),
+            }
+        }
+    }
+
+    /// Applies the connection settings with `SET` statements on servers too old to receive them with queries.
+    async fn set_connection_settings(&mut self) -> Result<()> {
+        if self.output.server_hello.revision_version
+            >= protocol::DBMS_MIN_REVISION_WITH_SETTINGS_SERIALIZED_AS_STRINGS
+        {
+            return Ok(());
+        }
+        for statement in self.connection_settings().set_statements() {
+            debug!("applying connection setting: {statement}");
+            self.run_statement(ParsedQuery::new(statement)).await?;
+        }
+        Ok(())
+    }
+
+    /// Replays the session statements on a new connection, before any queued request is sent.
+    async fn replay_session(&mut self) -> Result<()> {
+        let statements: Vec<ParsedQuery> = self.session.values().cloned().collect();
+        for statement in statements {
+            debug!("replaying session statement: {}", statement.query);
+            let query = statement.query.clone();
+            if let Err(e) = self.run_statement(statement).await {
+                match e {
+                    KlickhouseError::ServerException(e) => {
+                        warn!("failed to replay session statement {query}: {}", e.message)
+                    }
+                    e => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_inner(&mut self, input: &mut Receiver<ClientRequest>) -> Result<()> {
+        let hello = match &self.options.authentication {
+            Authentication::InterserverSecret { cluster, .. } => {
+                self.interserver_salt = auth::interserver_salt();
+                ClientHello {
+                    default_database: &self.options.default_database,
+                    username: auth::USER_INTERSERVER_MARKER,
+                    password: "",
+                    interserver: Some((cluster, &self.interserver_salt)),
+                }
+            }
+            #[cfg(feature = "ssh")]
+            Authentication::SshKey(_) => ClientHello {
+                default_database: &self.options.default_database,
+                username: &format!("{}{}", auth::USER_SSH_KEY_MARKER, self.options.username),
+                password: "",
+                interserver: None,
+            },
+            _ => ClientHello {
                 default_database: &self.options.default_database,
                 username: &self.options.username,
                 password: &self.options.password,
-            })
-            .await?;
-        let hello_response = self.input.receive_hello().await?;
-        self.input.server_hello = hello_response.clone();
+                interserver: None,
+            },
+        };
+        self.output.send_hello(hello).await?;
+        #[cfg(feature = "ssh")]
+        if let Authentication::SshKey(key) = self.options.authentication.clone() {
+            self.output.send_ssh_challenge_request().await?;
+            let challenge = match self.next_packet().await? {
+                ServerPacket::SshChallenge(challenge) => challenge,
+                packet => {
+                    return Err(KlickhouseError::ProtocolError(format!(
+                        "unexpected packet {:?}, expected SSH challenge",
+                        packet
+                    )))
+                }
+            };
+            let signature = auth::ssh_signature(
+                &key,
+                &self.options.default_database,
+                &self.options.username,
+                &challenge,
+            )?;
+            self.output.send_ssh_challenge_response(&signature).await?;
+        }
+        let hello_response = match self.next_packet().await? {
+            ServerPacket::Hello(hello) => hello,
+            packet => {
+                return Err(KlickhouseError::ProtocolError(format!(
+                    "unexpected packet {:?}, expected server hello",
+                    packet
+                )))
+            }
+        };
         self.output.server_hello = hello_response.clone();
+        if hello_response.revision_version >= protocol::DBMS_MIN_PROTOCOL_VERSION_WITH_ADDENDUM {
+            self.output.send_addendum(&self.options.quota_key).await?;
+        }
+        self.set_connection_settings().await?;
+        self.replay_session().await?;
+        self.reconnect_attempts = 0;
+        // Sends the requests queued while the previous connection was lost.
+        self.query_finished().await?;
+        self.last_activity = Instant::now();
 
         loop {
+            let deadline = self.next_deadline();
+            let was_awaiting_server = self.awaiting_server();
+            let cancellable = self
+                .executing_query
+                .as_ref()
+                .filter(|current| current.cancel_on_drop && !current.cancelled)
+                .map(|current| current.sender.clone());
             select! {
                 request = input.recv() => {
                     match request {
                         Some(request) => self.handle_request(request).await?,
                         None => return Ok(()),
                     }
+                    self.last_activity = Instant::now();
                 },
-                packet = self.input.receive_packet() => {
+                packet = self.next_packet() => {
                     let packet = packet?;
+                    self.last_activity = Instant::now();
+                    self.last_received = self.last_activity;
                     self.receive_packet(packet).await?;
                 },
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.handle_deadline().await?;
+                },
+                // Cancels right away, rather than once the server sends its next packet.
+                _ = receiver_dropped(cancellable) => {
+                    self.cancel_if_dropped().await?;
+                },
+            }
+            // The read timeout starts once something is expected from the server.
+            if !was_awaiting_server && self.awaiting_server() {
+                self.last_received = Instant::now();
             }
         }
     }
 
-    pub async fn run(self, input: Receiver<ClientRequest>) {
-        if let Err(e) = self.run_inner(input).await {
-            error!("clickhouse client failed: {:?}", e);
+    /// Receives the next packet from the reader task. Cancel safe.
+    async fn next_packet(&mut self) -> Result<ServerPacket> {
+        self.packets.recv().await.unwrap_or_else(|| {
+            Err(KlickhouseError::ConnectionError(
+                "connection reader stopped".to_string(),
+            ))
+        })
+    }
+}
+
+impl InnerClient<BoxedWriter> {
+    /// Runs the connection until every [`Client`] is dropped. With [`ClientOptions::reconnect`] and a `connector`,
+    /// lost connections are reopened with backoff, otherwise the client is closed.
+    async fn run(mut self, mut input: Receiver<ClientRequest>, connector: Option<Arc<Connector>>) {
+        loop {
+            // Boxed so that rustc proves the connection future Send on its own, it can't from inside this loop.
+            let connection: Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> =
+                Box::pin(self.run_inner(&mut input));
+            let Err(e) = connection.await else {
+                return;
+            };
+            self.connection_lost(&e);
+            let (Some(connector), Some(policy)) = (&connector, self.options.reconnect.clone())
+            else {
+                error!("clickhouse client failed: {:?}", e);
+                return;
+            };
+            warn!("clickhouse connection lost, reconnecting: {e}");
+            let (reader, writer) = loop {
+                if policy
+                    .max_attempts
+                    .is_some_and(|max| self.reconnect_attempts >= max)
+                {
+                    error!(
+                        "clickhouse reconnection failed after {} attempts, closing client",
+                        self.reconnect_attempts
+                    );
+                    return;
+                }
+                tokio::time::sleep(policy.backoff(self.reconnect_attempts)).await;
+                // Every Client was dropped while waiting.
+                if input.is_closed() {
+                    return;
+                }
+                self.reconnect_attempts += 1;
+                match connector.open().await {
+                    Ok(stream) => break stream,
+                    Err(e) => warn!(
+                        "clickhouse reconnection attempt {} failed: {e}",
+                        self.reconnect_attempts
+                    ),
+                }
+            };
+            let writer = boxed_writer(writer, &self.options);
+            self = self.with_stream(BufReader::new(reader), writer);
         }
     }
 }
 
 enum ClientRequestData {
     Query {
-        query: String,
-        response: oneshot::Sender<mpsc::Receiver<Result<Block>>>,
+        query: Box<ParsedQuery>,
+        cancel_on_drop: bool,
+        response: oneshot::Sender<Result<QueryHandle>>,
+        permit: Option<OwnedSemaphorePermit>,
     },
     SendData {
-        block: Block,
+        /// Generation of the query the block belongs to, see [`QueryHandle::generation`].
+        generation: u64,
+        /// Block encoded by the caller, see [`BlockEncoding::encode`].
+        data: Vec<u8>,
+        response: oneshot::Sender<Result<()>>,
+    },
+    TablesStatus {
+        tables: Vec<(String, String)>,
+        response: oneshot::Sender<Result<TablesStatusResponse>>,
+    },
+    Ping {
         response: oneshot::Sender<()>,
     },
 }
@@ -252,7 +994,133 @@ struct ClientRequest {
 #[derive(Clone)]
 pub struct Client {
     sender: mpsc::Sender<ClientRequest>,
-    progress: broadcast::Sender<(Uuid, Progress)>,
+    progress: broadcast::Sender<(String, Progress)>,
+    /// How this client was connected, to open side connections. `None` for [`Client::connect_stream`].
+    connector: Option<Arc<Connector>>,
+    /// Free slots of the pending query queue, with [`QueueOverflowPolicy::Block`].
+    queue_slots: Option<Arc<Semaphore>>,
+    retry: Option<RetryPolicy>,
+    /// Attempts made after a first failed one by the retry policy, see [`Client::retried_attempts`].
+    retried_attempts: Arc<AtomicU64>,
+}
+
+/// What happens to a new query when [`ClientOptions::max_pending_queries`] queries are already waiting for the
+/// connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueueOverflowPolicy {
+    /// The caller waits until a queued query is sent to the server.
+    #[default]
+    Block,
+    /// The new query fails with [`KlickhouseError::QueueOverflow`].
+    RejectNew,
+    /// The oldest pending query fails with [`KlickhouseError::QueueOverflow`], and the new query is queued.
+    EvictOldest,
+}
+
+/// How a [`Client`] reconnects after losing its connection, set in [`ClientOptions::reconnect`].
+///
+/// The query executing when the connection is lost fails with [`KlickhouseError::ConnectionLost`], and can be
+/// retried. Queued queries are sent on the new connection, once the handshake and the successful `SET` and `USE`
+/// statements of the session were replayed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectOptions {
+    /// Delay before the first attempt, doubled after each failed attempt.
+    pub initial_backoff: Duration,
+    /// Maximum delay between two attempts.
+    pub max_backoff: Duration,
+    /// Number of failed attempts in a row after which the client is closed. `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl ReconnectOptions {
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        ReconnectOptions {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+/// Everything needed to open a new connection to the same server as an existing [`Client`].
+struct Connector {
+    destination: Vec<SocketAddr>,
+    #[cfg(feature = "tls")]
+    tls: Option<(
+        rustls_pki_types::ServerName<'static>,
+        tokio_rustls::TlsConnector,
+    )>,
+    options: ClientOptions,
+}
+
+impl Connector {
+    async fn connect(&self) -> Result<Client> {
+        #[cfg(feature = "tls")]
+        if let Some((name, connector)) = &self.tls {
+            return Client::connect_tls(
+                &self.destination[..],
+                self.options.clone(),
+                name.clone(),
+                connector,
+            )
+            .await;
+        }
+        Client::connect(&self.destination[..], self.options.clone()).await
+    }
+
+    /// Opens a new stream to the server, without handshake.
+    async fn open(
+        &self,
+    ) -> Result<(
+        Box<dyn AsyncRead + Unpin + Send + Sync>,
+        Box<dyn AsyncWrite + Unpin + Send + Sync>,
+    )> {
+        let (stream, _) = connect_tcp(&self.destination[..], &self.options).await?;
+        #[cfg(feature = "tls")]
+        if let Some((name, connector)) = &self.tls {
+            let (read, writer) = tokio::io::split(connector.connect(name.clone(), stream).await?);
+            return Ok((Box::new(read), Box::new(writer)));
+        }
+        let (read, writer) = stream.into_split();
+        Ok((Box::new(read), Box::new(writer)))
+    }
+}
+
+/// Outcome of [`Client::kill_query`], from the `kill_status` column returned by `KILL QUERY`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KillQueryStatus {
+    /// No running query has this id. It may have already finished.
+    NotFound,
+    /// The cancellation was sent, and the query will stop at its next cancellation point.
+    Waiting,
+    /// The query was stopped.
+    Finished,
+    /// The query is not initialized yet and can't be cancelled, retry later.
+    Pending,
+    /// The query can't be cancelled.
+    CantCancel,
+    /// Any other status reported by the server.
+    Other(String),
+}
+
+impl KillQueryStatus {
+    fn parse(status: &str) -> Self {
+        match status {
+            "waiting" => KillQueryStatus::Waiting,
+            "finished" => KillQueryStatus::Finished,
+            "pending" => KillQueryStatus::Pending,
+            "cant_cancel" => KillQueryStatus::CantCancel,
+            other => KillQueryStatus::Other(other.to_string()),
+        }
+    }
 }
 
 /// Options set for a Clickhouse connection.
@@ -260,21 +1128,73 @@ pub struct Client {
 pub struct ClientOptions {
     pub username: String,
     pub password: String,
+    /// How the client authenticates, with `username` and `password` by default.
+    pub authentication: Authentication,
     pub default_database: String,
+    /// Client name sent with every query, as seen in the `client_name` column of `system.query_log` and
+    /// `system.processes`.
+    pub client_name: String,
+    /// Hostname of this client, sent with every query (`client_hostname` in `system.query_log`).
+    pub client_hostname: String,
+    /// Operating system user of this client, sent with every query (`os_user` in `system.query_log`).
+    pub os_user: String,
+    /// Key of the quota the queries are accounted to, for quotas keyed by `client_key`.
+    /// Overridden per query with [`ParsedQuery::with_quota_key`].
+    pub quota_key: String,
     pub tcp_nodelay: bool,
     /// Timeout for establishing a TCP connection. `None` means no timeout (OS default).
     pub connect_timeout: Option<Duration>,
     /// TCP keepalive interval. `None` disables keepalive.
     /// Recommended for production to detect dead connections through NAT/firewalls.
     pub tcp_keepalive: Option<Duration>,
+    /// Connect over TLS with these settings in [`Client::connect`] and [`crate::ConnectionManager`]. `None` by
+    /// default, for plaintext TCP.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsOptions>,
+    /// Compression of the data blocks exchanged with the server. LZ4 with the `compression` feature, none otherwise.
+    pub compression: Compression,
     /// Maximum number of queries that can be queued while waiting for the current query to complete.
-    /// When the limit is reached, the oldest pending query is dropped with a warning.
+    /// What happens when the limit is reached is decided by `queue_overflow_policy`.
     pub max_pending_queries: usize,
+    pub queue_overflow_policy: QueueOverflowPolicy,
     /// Size of the mpsc channel buffer for streaming blocks from a single query result.
     /// Larger values reduce backpressure but increase memory usage.
     pub block_channel_size: usize,
     /// Size of the mpsc channel buffer for the client request queue.
     pub request_channel_size: usize,
+    /// Clickhouse settings sent with every query. Per-query settings from [`ParsedQuery::with_setting`] take precedence.
+    /// Defaults to `date_time_input_format = 'best_effort'`. Servers older than revision 54429 receive them as `SET`
+    /// statements when connecting instead, and reject queries with settings of their own.
+    pub settings: QuerySettings,
+    /// Emit the server logs received for queries through the `log` crate, under [`crate::SERVER_LOG_TARGET`].
+    /// Server logs are only sent for queries with a `send_logs_level`, see [`ParsedQuery::with_logs_level`].
+    pub forward_server_logs: bool,
+    /// Interval of inactivity after which a Ping is sent to check that the server is still there.
+    /// `None` disables idle pings.
+    pub idle_ping_interval: Option<Duration>,
+    /// Time to wait for the server to answer a Ping before closing the connection.
+    pub ping_timeout: Duration,
+    /// Default timeout of queries, from the moment they are sent to the server. When it expires, the query is
+    /// cancelled on the server and fails with [`KlickhouseError::Timeout`], while the connection stays usable.
+    /// `None` by default. Overridden per query with [`ParsedQuery::with_timeout`].
+    pub query_timeout: Option<Duration>,
+    /// Time to wait for a packet from the server while one is expected (query results, Pong, tables status),
+    /// before closing the connection as hung. `None` by default. Between queries, hung servers are detected by
+    /// `idle_ping_interval`.
+    pub read_timeout: Option<Duration>,
+    /// Time a write to the socket may stay blocked before the connection is closed as hung. `None` by default.
+    pub write_timeout: Option<Duration>,
+    /// Reconnect automatically when the connection is lost, instead of closing the client. `None` by default.
+    /// Only used by clients created with [`Client::connect`] or [`Client::connect_tls`].
+    pub reconnect: Option<ReconnectOptions>,
+    /// Retry policy of [`Client::execute`], [`Client::query_collect`], [`Client::query_one`], [`Client::query_opt`]
+    /// and [`Client::insert_native_block`], for read-only statements and deduplicated inserts, see [`RetryPolicy`].
+    /// `None` by default. Retrying after the connection was lost requires `reconnect`, or connections of a
+    /// [`crate::ConnectionManager`] pool.
+    pub retry: Option<RetryPolicy>,
+    /// Blocks of at least this many uncompressed bytes are compressed and decompressed on tokio's blocking thread
+    /// pool, instead of the connection tasks. `None` compresses every block inline. Defaults to 1 MiB.
+    pub blocking_compression_threshold: Option<usize>,
 }
 
 impl Default for ClientOptions {
@@ -282,13 +1202,32 @@ impl Default for ClientOptions {
         ClientOptions {
             username: "default".to_string(),
             password: String::new(),
+            authentication: Authentication::Password,
             default_database: String::new(),
+            client_name: DEFAULT_CLIENT_NAME.to_string(),
+            client_hostname: "localhost".to_string(),
+            os_user: String::new(),
+            quota_key: String::new(),
             tcp_nodelay: true,
             connect_timeout: Some(Duration::from_secs(10)),
             tcp_keepalive: Some(Duration::from_secs(60)),
+            #[cfg(feature = "tls")]
+            tls: None,
+            compression: Compression::default(),
             max_pending_queries: DEFAULT_MAX_PENDING_QUERIES,
+            queue_overflow_policy: QueueOverflowPolicy::default(),
             block_channel_size: 32,
             request_channel_size: 1024,
+            settings: QuerySettings::new().with("date_time_input_format", "best_effort"),
+            forward_server_logs: false,
+            idle_ping_interval: None,
+            ping_timeout: Duration::from_secs(10),
+            query_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            reconnect: None,
+            retry: None,
+            blocking_compression_threshold: Some(DEFAULT_BLOCKING_COMPRESSION_THRESHOLD),
         }
     }
 }
@@ -308,6 +1247,81 @@ fn configure_tcp_stream(
     Ok(())
 }
 
+/// Address accepted by [`Client::connect`]: the types of [`ToSocketAddrs`], which also tell the host name to
+/// check the TLS certificate of the server against.
+pub trait Destination: ToSocketAddrs {
+    /// Host name of the address, `None` for IP addresses.
+    fn host_name(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl<T: Destination + ?Sized> Destination for &T {
+    fn host_name(&self) -> Option<&str> {
+        (**self).host_name()
+    }
+}
+
+impl Destination for SocketAddr {}
+impl Destination for SocketAddrV4 {}
+impl Destination for SocketAddrV6 {}
+impl Destination for (IpAddr, u16) {}
+impl Destination for (Ipv4Addr, u16) {}
+impl Destination for (Ipv6Addr, u16) {}
+impl Destination for &[SocketAddr] {}
+
+impl Destination for str {
+    fn host_name(&self) -> Option<&str> {
+        Some(host_name(self)).filter(|host| host.parse::<IpAddr>().is_err())
+    }
+}
+
+impl Destination for String {
+    fn host_name(&self) -> Option<&str> {
+        self.as_str().host_name()
+    }
+}
+
+impl Destination for (&str, u16) {
+    fn host_name(&self) -> Option<&str> {
+        Some(self.0).filter(|host| host.parse::<IpAddr>().is_err())
+    }
+}
+
+impl Destination for (String, u16) {
+    fn host_name(&self) -> Option<&str> {
+        Some(self.0.as_str()).filter(|host| host.parse::<IpAddr>().is_err())
+    }
+}
+
+/// Host name of a `host:port` address.
+pub(crate) fn host_name(address: &str) -> &str {
+    match address.rsplit_once(':') {
+        Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => address,
+    }
+}
+
+/// Resolves `destination` and opens a TCP stream to the first reachable address, within the connect timeout.
+async fn connect_tcp<A: ToSocketAddrs>(
+    destination: A,
+    options: &ClientOptions,
+) -> Result<(TcpStream, Vec<SocketAddr>)> {
+    let connect = async {
+        let destination: Vec<SocketAddr> = tokio::net::lookup_host(destination).await?.collect();
+        let stream = TcpStream::connect(&destination[..]).await?;
+        Ok::<_, std::io::Error>((stream, destination))
+    };
+    let (stream, destination) = match options.connect_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connect).await.map_err(|_| {
+            KlickhouseError::Timeout(format!("TCP connect timed out after {:?}", timeout))
+        })??,
+        None => connect.await?,
+    };
+    configure_tcp_stream(&stream, options)?;
+    Ok((stream, destination))
+}
+
 impl Client {
     /// Consumes a reader and writer to connect to Klickhouse. To be used for exotic setups or TLS. Generally prefer [`Client::connect()`]
     pub async fn connect_stream(
@@ -315,30 +1329,45 @@ impl Client {
         writer: impl AsyncWrite + Unpin + Send + Sync + 'static,
         options: ClientOptions,
     ) -> Result<Self> {
-        Self::start(InnerClient::new(
-            BufReader::new(read),
-            BufWriter::new(writer),
-            options,
-        ))
+        options.compression.check_supported()?;
+        let writer = boxed_writer(writer, &options);
+        Self::start(
+            InnerClient::new(BufReader::new(read), writer, options),
+            None,
+        )
         .await
     }
 
-    /// Connects to a specific socket address over plaintext TCP for Clickhouse.
-    pub async fn connect<A: ToSocketAddrs>(destination: A, options: ClientOptions) -> Result<Self> {
-        let stream = match options.connect_timeout {
-            Some(timeout) => tokio::time::timeout(timeout, TcpStream::connect(destination))
-                .await
-                .map_err(|_| {
-                    KlickhouseError::Timeout(format!("TCP connect timed out after {:?}", timeout))
-                })??,
-            None => TcpStream::connect(destination).await?,
-        };
-        configure_tcp_stream(&stream, &options)?;
+    /// Connects to a specific socket address for Clickhouse, over TLS when [`ClientOptions::tls`] is set and plaintext
+    /// TCP otherwise.
+    pub async fn connect<A: Destination>(destination: A, options: ClientOptions) -> Result<Self> {
+        options.compression.check_supported()?;
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &options.tls {
+            let connector = tls.connector()?;
+            let host = destination.host_name().map(str::to_string);
+            let (stream, destination) = connect_tcp(destination, &options).await?;
+            let name = tls.server_name(host.as_deref(), stream.peer_addr()?.ip())?;
+            return Self::start_tls(stream, destination, options, name, connector).await;
+        }
+        let (stream, destination) = connect_tcp(destination, &options).await?;
         let (read, writer) = stream.into_split();
-        Self::connect_stream(read, writer, options).await
+        let connector = Arc::new(Connector {
+            destination,
+            #[cfg(feature = "tls")]
+            tls: None,
+            options: options.clone(),
+        });
+        let writer = boxed_writer(writer, &options);
+        Self::start(
+            InnerClient::new(BufReader::new(read), writer, options),
+            Some(connector),
+        )
+        .await
     }
 
-    /// Connects to a specific socket address over TLS (rustls) for Clickhouse.
+    /// Connects to a specific socket address over TLS (rustls) for Clickhouse, with a custom rustls configuration.
+    /// Prefer [`ClientOptions::tls`] with [`Client::connect`] otherwise.
     #[cfg(feature = "tls")]
     pub async fn connect_tls<A: ToSocketAddrs>(
         destination: A,
@@ -346,73 +1375,193 @@ impl Client {
         name: rustls_pki_types::ServerName<'static>,
         connector: &tokio_rustls::TlsConnector,
     ) -> Result<Self> {
-        let stream = match options.connect_timeout {
-            Some(timeout) => tokio::time::timeout(timeout, TcpStream::connect(destination))
-                .await
-                .map_err(|_| {
-                    KlickhouseError::Timeout(format!("TCP connect timed out after {:?}", timeout))
-                })??,
-            None => TcpStream::connect(destination).await?,
-        };
-        configure_tcp_stream(&stream, &options)?;
-        let tls_stream = connector.connect(name, stream).await?;
+        options.compression.check_supported()?;
+        let (stream, destination) = connect_tcp(destination, &options).await?;
+        Self::start_tls(stream, destination, options, name, connector.clone()).await
+    }
+
+    #[cfg(feature = "tls")]
+    async fn start_tls(
+        stream: TcpStream,
+        destination: Vec<SocketAddr>,
+        options: ClientOptions,
+        name: rustls_pki_types::ServerName<'static>,
+        connector: tokio_rustls::TlsConnector,
+    ) -> Result<Self> {
+        let tls_stream = connector.connect(name.clone(), stream).await?;
         let (read, writer) = tokio::io::split(tls_stream);
-        Self::connect_stream(read, writer, options).await
+        let connector = Arc::new(Connector {
+            destination,
+            tls: Some((name, connector)),
+            options: options.clone(),
+        });
+        let writer = boxed_writer(writer, &options);
+        Self::start(
+            InnerClient::new(BufReader::new(read), writer, options),
+            Some(connector),
+        )
+        .await
     }
 
-    async fn start<R: ClickhouseRead + 'static, W: ClickhouseWrite>(
-        inner: InnerClient<R, W>,
+    async fn start(
+        inner: InnerClient<BoxedWriter>,
+        connector: Option<Arc<Connector>>,
     ) -> Result<Self> {
         let progress = inner.progress.clone();
+        let retry = inner.options.retry.clone();
         let (sender, receiver) = mpsc::channel(inner.options.request_channel_size);
+        let queue_slots = match inner.options.queue_overflow_policy {
+            QueueOverflowPolicy::Block => {
+                Some(Arc::new(Semaphore::new(inner.options.max_pending_queries)))
+            }
+            _ => None,
+        };
 
-        tokio::spawn(inner.run(receiver));
-        let client = Client { sender, progress };
-        client
-            .execute("SET date_time_input_format='best_effort'")
-            .await?;
-        Ok(client)
+        tokio::spawn(inner.run(receiver, connector.clone()));
+        Ok(Client {
+            sender,
+            progress,
+            connector,
+            queue_slots,
+            retry,
+            retried_attempts: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Runs `operation` with the retry policy of the client, if sending `query` again has no other effect.
+    async fn retrying<T, F, Fut>(&self, query: &ParsedQuery, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        match &self.retry {
+            Some(policy) if retry::is_idempotent(query) => {
+                let result = policy
+                    .run_while(|_| operation(), || !self.is_closed())
+                    .await;
+                let attempts = match &result {
+                    Ok((_, attempts)) => *attempts,
+                    Err(KlickhouseError::RetriesExhausted { attempts, .. }) => *attempts,
+                    Err(_) => 1,
+                };
+                self.retried_attempts
+                    .fetch_add(u64::from(attempts - 1), Ordering::Relaxed);
+                result.map(|(value, _)| value)
+            }
+            _ => operation().await,
+        }
     }
 
     /// Sends a query string and read column blocks over a stream.
     /// You probably want [`Client::query()`]
+    ///
+    /// Dropping the returned stream before it is exhausted cancels the query on the server.
     pub async fn query_raw(
         &self,
         query: impl TryInto<ParsedQuery, Error = KlickhouseError>,
     ) -> Result<impl Stream<Item = Result<Block>>> {
+        Ok(self.query_handle(query).await?.blocks())
+    }
+
+    /// Sends a query string, returning a [`QueryHandle`] that carries the query id and streams every packet
+    /// of the query: data blocks, progress, profile info, totals and extremes.
+    ///
+    /// Dropping the returned handle before it is exhausted cancels the query on the server.
+    pub async fn query_handle(
+        &self,
+        query: impl TryInto<ParsedQuery, Error = KlickhouseError>,
+    ) -> Result<QueryHandle> {
+        self.send_query(query.try_into()?, true).await
+    }
+
+    #[cfg_attr(not(feature = "opentelemetry"), allow(unused_mut))]
+    async fn send_query(
+        &self,
+        mut query: ParsedQuery,
+        cancel_on_drop: bool,
+    ) -> Result<QueryHandle> {
+        // Captured here rather than in the client task, which doesn't run in the caller's span.
+        #[cfg(feature = "opentelemetry")]
+        if query.trace_context.is_none() {
+            query.trace_context = crate::TraceContext::current();
+        }
+        let permit = match &self.queue_slots {
+            Some(slots) => Some(slots.clone().acquire_owned().await.map_err(|e| {
+                KlickhouseError::ProtocolError(format!("failed to acquire queue slot: {e}"))
+            })?),
+            None => None,
+        };
         let (sender, receiver) = oneshot::channel();
         self.sender
             .send(ClientRequest {
                 data: ClientRequestData::Query {
-                    query: query.try_into()?.0,
+                    query: Box::new(query),
+                    cancel_on_drop,
                     response: sender,
+                    permit,
                 },
             })
             .await
             .map_err(|e| KlickhouseError::ProtocolError(format!("failed to send query: {e}")))?;
-        let receiver = receiver.await.map_err(|e| {
+        receiver.await.map_err(|e| {
             KlickhouseError::ProtocolError(format!("failed to receive blocks from upstream: {e}"))
-        })?;
-
-        Ok(ReceiverStream::new(receiver))
+        })?
     }
 
-    async fn send_data(&self, block: Block) -> Result<()> {
+    /// Queues an encoded block of the query of `generation`, returning a receiver notified once it was written.
+    async fn queue_data(
+        &self,
+        generation: u64,
+        data: Vec<u8>,
+    ) -> Result<oneshot::Receiver<Result<()>>> {
         let (sender, receiver) = oneshot::channel();
         self.sender
             .send(ClientRequest {
                 data: ClientRequestData::SendData {
-                    block,
+                    generation,
+                    data,
                     response: sender,
                 },
             })
             .await
             .map_err(|e| KlickhouseError::ProtocolError(format!("failed to send block: {e}")))?;
-        receiver.await.map_err(|e| {
+        Ok(receiver)
+    }
+
+    async fn data_written(written: oneshot::Receiver<Result<()>>) -> Result<()> {
+        written.await.map_err(|e| {
             KlickhouseError::ProtocolError(format!("failed to receive blocks from upstream: {e}"))
-        })?;
+        })?
+    }
 
-        Ok(())
+    /// Sends `blocks` as the data of the query of `handle`, followed by the empty block ending it.
+    /// Each block is encoded while the previous one is being written by the connection task.
+    async fn send_blocks(
+        &self,
+        handle: &QueryHandle,
+        mut blocks: impl Stream<Item = Result<Block>> + Unpin,
+    ) -> Result<()> {
+        let (encoding, generation) = (handle.encoding(), handle.generation());
+        let mut in_flight = None;
+        while let Some(block) = blocks.next().await {
+            let data = encoding.encode(block?).await?;
+            if let Some(written) = in_flight.take() {
+                Self::data_written(written).await?;
+            }
+            in_flight = Some(self.queue_data(generation, data).await?);
+        }
+        if let Some(written) = in_flight.take() {
+            Self::data_written(written).await?;
+        }
+        let data = encoding
+            .encode(Block {
+                info: BlockInfo::default(),
+                rows: 0,
+                column_types: IndexMap::new(),
+                column_data: IndexMap::new(),
+            })
+            .await?;
+        Self::data_written(self.queue_data(generation, data).await?).await
     }
 
     /// Sends a query string with streaming associated data (i.e. insert) over native protocol.
@@ -421,34 +1570,12 @@ impl Client {
     pub async fn insert_native_raw(
         &self,
         query: impl TryInto<ParsedQuery, Error = KlickhouseError>,
-        mut blocks: impl Stream<Item = Block> + Send + Sync + Unpin + 'static,
+        blocks: impl Stream<Item = Block> + Send + Sync + Unpin + 'static,
     ) -> Result<impl Stream<Item = Result<Block>>> {
-        let (sender, receiver) = oneshot::channel();
-        self.sender
-            .send(ClientRequest {
-                data: ClientRequestData::Query {
-                    query: query.try_into()?.0,
-                    response: sender,
-                },
-            })
-            .await
-            .map_err(|e| KlickhouseError::ProtocolError(format!("failed to send query: {e}")))?;
-        let receiver = receiver.await.map_err(|e| {
-            KlickhouseError::ProtocolError(format!("failed to receive blocks from upstream: {e}"))
-        })?;
+        let handle = self.send_query(query.try_into()?, true).await?;
+        self.send_blocks(&handle, blocks.map(Ok)).await?;
 
-        while let Some(block) = blocks.next().await {
-            self.send_data(block).await?;
-        }
-        self.send_data(Block {
-            info: BlockInfo::default(),
-            rows: 0,
-            column_types: IndexMap::new(),
-            column_data: IndexMap::new(),
-        })
-        .await?;
-
-        Ok(ReceiverStream::new(receiver))
+        Ok(handle.blocks())
     }
 
     /// Sends a query string with streaming associated data (i.e. insert) over native protocol.
@@ -459,78 +1586,66 @@ impl Client {
     pub async fn insert_native<T: Row + Send + Sync + 'static>(
         &self,
         query: impl TryInto<ParsedQuery, Error = KlickhouseError>,
-        mut blocks: impl Stream<Item = Vec<T>> + Send + Sync + Unpin + 'static,
+        blocks: impl Stream<Item = Vec<T>> + Send + Sync + Unpin + 'static,
     ) -> Result<()> {
-        let (sender, receiver) = oneshot::channel();
-        self.sender
-            .send(ClientRequest {
-                data: ClientRequestData::Query {
-                    query: query.try_into()?.0.trim().to_string(),
-                    response: sender,
-                },
-            })
-            .await
-            .map_err(|e| KlickhouseError::ProtocolError(format!("failed to send query: {e}")))?;
-        let mut receiver = receiver.await.map_err(|e| {
-            KlickhouseError::ProtocolError(format!("failed to receive blocks from upstream: {e}"))
-        })?;
-        let first_block = receiver.recv().await.ok_or_else(|| {
+        let mut query = query.try_into()?;
+        query.query = query.query.trim().to_string();
+        let mut handle = self.send_query(query, false).await?;
+        let first_block = handle.next_block().await.ok_or_else(|| {
             KlickhouseError::ProtocolError("missing header block from server".to_string())
         })??;
-        while let Some(rows) = blocks.next().await {
-            if rows.is_empty() {
-                continue;
-            }
-            let mut block = Block {
-                info: BlockInfo::default(),
-                rows: rows.len() as u64,
-                column_types: first_block.column_types.clone(),
-                column_data: IndexMap::new(),
-            };
-            let serialized_rows: Vec<_> = rows
-                .into_iter()
-                .map(|x| x.serialize_row(&first_block.column_types))
-                .collect::<Result<Vec<_>>>()?;
-            serialized_rows
-                .into_iter()
-                .try_for_each(|x| -> Result<()> {
-                    for (key, value) in x {
-                        let type_ = first_block.column_types.get(&*key).ok_or_else(|| {
-                            KlickhouseError::ProtocolError(format!(
-                                "missing type for data, column: {key}"
-                            ))
-                        })?;
-                        type_.validate_value(&value)?;
-                        if let Some(column) = block.column_data.get_mut(&*key) {
-                            column.push(value);
-                        } else {
-                            block.column_data.insert(key.into_owned(), vec![value]);
-                        }
-                    }
-                    Ok(())
-                })?;
-            self.send_data(block).await?;
-        }
-        self.send_data(Block {
-            info: BlockInfo::default(),
-            rows: 0,
-            column_types: IndexMap::new(),
-            column_data: IndexMap::new(),
-        })
-        .await?;
-        Ok(())
+        let blocks = blocks
+            .filter(|rows| std::future::ready(!rows.is_empty()))
+            .map(|rows| Block::from_rows(rows, first_block.column_types.clone()));
+        self.send_blocks(&handle, blocks).await
     }
 
     /// Wrapper over [`Client::insert_native`] to send a single block.
     /// Make sure any query you send native data with has a `format native` suffix.
+    ///
+    /// With [`ClientOptions::retry`], the insert is only retried if the query has an `insert_deduplication_token`
+    /// setting, and then waits for the server to end the query, to retry errors sent after the data. The token only
+    /// makes a retried insert a no-op into `Replicated*MergeTree` tables, or `MergeTree` tables with a
+    /// `non_replicated_deduplication_window` setting: other tables may get the rows of both attempts.
     pub async fn insert_native_block<T: Row + Send + Sync + 'static>(
         &self,
         query: impl TryInto<ParsedQuery, Error = KlickhouseError>,
         blocks: Vec<T>,
     ) -> Result<()> {
-        let blocks = Box::pin(async move { blocks });
-        let stream = futures_util::stream::once(blocks);
-        self.insert_native(query, stream).await
+        let query: ParsedQuery = query.try_into()?;
+        if self.retry.is_none() || !retry::is_idempotent(&query) {
+            let blocks = Box::pin(async move { blocks });
+            return self
+                .insert_native(&query, futures_util::stream::once(blocks))
+                .await;
+        }
+        // Rows are serialized once, with the column types of the first attempt, and sent again as is.
+        let rows = std::sync::Mutex::new(Some(blocks));
+        let block = std::sync::Mutex::new(None::<Block>);
+        let (query, rows, block) = (&query, &rows, &block);
+        self.retrying(query, || async move {
+            let mut query = query.clone();
+            query.query = query.query.trim().to_string();
+            let mut handle = self.send_query(query, false).await?;
+            let header = handle.next_block().await.ok_or_else(|| {
+                KlickhouseError::ProtocolError("missing header block from server".to_string())
+            })??;
+            let data = {
+                let mut block = block.lock().unwrap();
+                if let Some(rows) = rows.lock().unwrap().take() {
+                    *block = Some(Block::from_rows(rows, header.column_types)?);
+                }
+                block.clone()
+            };
+            let blocks = data.filter(|block| block.rows > 0).map(Ok);
+            self.send_blocks(&handle, stream::iter(blocks)).await?;
+            // Errors writing the data, i.e. `TOO_MANY_PARTS`, are only sent once all of it was received.
+            while let Some(block) = handle.next_block().await {
+                block?;
+            }
+            Ok(())
+        })
+        .await
     }
 
     /// Runs a query against Clickhouse, returning a stream of deserialized rows.
@@ -557,12 +1672,17 @@ impl Client {
         &self,
         query: impl TryInto<ParsedQuery, Error = KlickhouseError>,
     ) -> Result<Vec<T>> {
-        let mut out = vec![];
-        let mut stream = self.query::<T>(query).await?;
-        while let Some(next) = stream.next().await {
-            out.push(next?);
-        }
-        Ok(out)
+        let query = query.try_into()?;
+        let query = &query;
+        self.retrying(query, || async move {
+            let mut out = vec![];
+            let mut stream = self.query::<T>(query).await?;
+            while let Some(next) = stream.next().await {
+                out.push(next?);
+            }
+            Ok(out)
+        })
+        .await
     }
 
     /// Same as `query`, but returns the first row and discards the rest.
@@ -570,11 +1690,16 @@ impl Client {
         &self,
         query: impl TryInto<ParsedQuery, Error = KlickhouseError>,
     ) -> Result<T> {
-        self.query::<T>(query)
-            .await?
-            .next()
-            .await
-            .unwrap_or_else(|| Err(KlickhouseError::MissingRow))
+        let query = query.try_into()?;
+        let query = &query;
+        self.retrying(query, || async move {
+            self.query::<T>(query)
+                .await?
+                .next()
+                .await
+                .unwrap_or_else(|| Err(KlickhouseError::MissingRow))
+        })
+        .await
     }
 
     /// Same as `query`, but returns the first row, if any, and discards the rest.
@@ -582,7 +1707,12 @@ impl Client {
         &self,
         query: impl TryInto<ParsedQuery, Error = KlickhouseError>,
     ) -> Result<Option<T>> {
-        self.query::<T>(query).await?.next().await.transpose()
+        let query = query.try_into()?;
+        let query = &query;
+        self.retrying(query, || async move {
+            self.query::<T>(query).await?.next().await.transpose()
+        })
+        .await
     }
 
     /// Same as `query`, but discards all returns blocks. Waits until the first block returns from the server to check for errors.
@@ -591,11 +1721,16 @@ impl Client {
         &self,
         query: impl TryInto<ParsedQuery, Error = KlickhouseError>,
     ) -> Result<()> {
-        let mut stream = self.query::<RawRow>(query).await?;
-        while let Some(next) = stream.next().await {
-            next?;
-        }
-        Ok(())
+        let query = query.try_into()?;
+        let query = &query;
+        self.retrying(query, || async move {
+            let mut stream = self.query::<RawRow>(query).await?;
+            while let Some(next) = stream.next().await {
+                next?;
+            }
+            Ok(())
+        })
+        .await
     }
 
     /// Same as `execute`, but doesn't wait for a server response. The query could get aborted if the connection is closed quickly.
@@ -603,10 +1738,91 @@ impl Client {
         &self,
         query: impl TryInto<ParsedQuery, Error = KlickhouseError>,
     ) -> Result<()> {
-        let _ = self.query::<RawRow>(query).await?;
+        let _ = self.send_query(query.try_into()?, false).await?;
         Ok(())
     }
 
+    /// Stops a running query by id with `KILL QUERY`, i.e. a query started with [`ParsedQuery::with_query_id`].
+    ///
+    /// The `KILL QUERY` statement is sent over a new connection to the same server, so that it isn't queued behind
+    /// the query it is meant to stop. Not available for clients created with [`Client::connect_stream`].
+    pub async fn kill_query(&self, id: &str) -> Result<KillQueryStatus> {
+        let connector = self.connector.as_ref().ok_or_else(|| {
+            KlickhouseError::ConnectionError(
+                "kill_query requires a client created with connect or connect_tls".to_string(),
+            )
+        })?;
+        let client = connector.connect().await?;
+        let row = client
+            .query_opt::<RawRow>(QueryBuilder::new("KILL QUERY WHERE query_id = $1").arg(id))
+            .await?;
+        match row {
+            Some(mut row) => Ok(KillQueryStatus::parse(
+                &row.try_get::<_, String>("kill_status")?,
+            )),
+            None => Ok(KillQueryStatus::NotFound),
+        }
+    }
+
+    /// Returns the replication status of `tables`, as `(database, table)` pairs, i.e. to skip lagging replicas.
+    /// Tables that don't exist on the server are missing from the response.
+    ///
+    /// Like [`Client::ping`], the request waits for the queries already sent to the connection to finish.
+    pub async fn tables_status(
+        &self,
+        tables: &[(impl AsRef<str>, impl AsRef<str>)],
+    ) -> Result<TablesStatusResponse> {
+        let (sender, receiver) = oneshot::channel();
+        let tables = tables
+            .iter()
+            .map(|(database, table)| (database.as_ref().to_string(), table.as_ref().to_string()))
+            .collect();
+        self.sender
+            .send(ClientRequest {
+                data: ClientRequestData::TablesStatus {
+                    tables,
+                    response: sender,
+                },
+            })
+            .await
+            .map_err(|e| {
+                KlickhouseError::ProtocolError(format!("failed to send tables status request: {e}"))
+            })?;
+        receiver.await.map_err(|e| {
+            KlickhouseError::ConnectionError(format!(
+                "connection closed before tables status response: {e}"
+            ))
+        })?
+    }
+
+    /// Checks that the server answers a Ping within `timeout`.
+    ///
+    /// Pings can't be sent while a query is executing, so this waits for the queries already sent to the
+    /// connection to finish.
+    pub async fn ping(&self, timeout: Duration) -> Result<()> {
+        let ping = async {
+            let (sender, receiver) = oneshot::channel();
+            self.sender
+                .send(ClientRequest {
+                    data: ClientRequestData::Ping { response: sender },
+                })
+                .await
+                .map_err(|e| KlickhouseError::ProtocolError(format!("failed to send ping: {e}")))?;
+            receiver.await.map_err(|e| {
+                KlickhouseError::ConnectionError(format!("connection closed before pong: {e}"))
+            })
+        };
+        tokio::time::timeout(timeout, ping)
+            .await
+            .map_err(|_| KlickhouseError::Timeout(format!("no pong received after {timeout:?}")))?
+    }
+
+    /// Number of attempts made by [`ClientOptions::retry`] after a failed one, over all the queries of this client
+    /// and its clones. Queries that succeeded on their first attempt don't count.
+    pub fn retried_attempts(&self) -> u64 {
+        self.retried_attempts.load(Ordering::Relaxed)
+    }
+
     /// true if the Client is closed
     pub fn is_closed(&self) -> bool {
         self.sender.is_closed()
@@ -614,11 +1830,9 @@ impl Client {
 
     /// Receive progress on the queries as they execute.
     ///
-    /// TODO: There is currently no way to retrieve the ID of a query launched
-    ///       with `query` or `execute.`
-    ///       The signature of these functions should be modified to also return
-    ///       an ID (and possibly directly the streaming broadcast).
-    pub fn subscribe_progress(&self) -> broadcast::Receiver<(Uuid, Progress)> {
+    /// To follow the progress of a single query, prefer [`Client::query_handle`], which carries the query id
+    /// and its own progress events.
+    pub fn subscribe_progress(&self) -> broadcast::Receiver<(String, Progress)> {
         self.progress.subscribe()
     }
 }
@@ -632,13 +1846,60 @@ mod tests {
         let opts = ClientOptions::default();
         assert_eq!(opts.username, "default");
         assert!(opts.password.is_empty());
+        assert_eq!(opts.authentication, Authentication::Password);
         assert!(opts.default_database.is_empty());
+        assert_eq!(opts.client_name, DEFAULT_CLIENT_NAME);
+        assert_eq!(opts.client_hostname, "localhost");
+        assert!(opts.os_user.is_empty());
+        assert!(opts.quota_key.is_empty());
         assert!(opts.tcp_nodelay);
         assert_eq!(opts.connect_timeout, Some(Duration::from_secs(10)));
         assert_eq!(opts.tcp_keepalive, Some(Duration::from_secs(60)));
+        #[cfg(feature = "tls")]
+        assert!(opts.tls.is_none());
+        #[cfg(any(feature = "compression", feature = "lz4_flex"))]
+        assert_eq!(opts.compression, Compression::Lz4);
         assert_eq!(opts.max_pending_queries, DEFAULT_MAX_PENDING_QUERIES);
+        assert_eq!(opts.queue_overflow_policy, QueueOverflowPolicy::Block);
         assert_eq!(opts.block_channel_size, 32);
         assert_eq!(opts.request_channel_size, 1024);
+        assert!(!opts.forward_server_logs);
+        assert!(opts.idle_ping_interval.is_none());
+        assert_eq!(opts.ping_timeout, Duration::from_secs(10));
+        assert!(opts.query_timeout.is_none());
+        assert!(opts.read_timeout.is_none());
+        assert!(opts.write_timeout.is_none());
+        assert!(opts.reconnect.is_none());
+        assert!(opts.retry.is_none());
+        assert_eq!(
+            opts.blocking_compression_threshold,
+            Some(DEFAULT_BLOCKING_COMPRESSION_THRESHOLD)
+        );
+        assert_eq!(
+            opts.settings.get("date_time_input_format"),
+            Some(&crate::SettingValue::from("best_effort"))
+        );
+    }
+
+    #[test]
+    fn test_destination_host_name() {
+        assert_eq!(
+            "ch-1.example.com:9440".host_name(),
+            Some("ch-1.example.com")
+        );
+        assert_eq!(
+            "ch-1.example.com:9440".to_string().host_name(),
+            Some("ch-1.example.com")
+        );
+        assert_eq!(
+            ("ch-1.example.com", 9440).host_name(),
+            Some("ch-1.example.com")
+        );
+        assert_eq!("10.0.0.1:9440".host_name(), None);
+        assert_eq!("[::1]:9440".host_name(), None);
+        assert_eq!(("::1", 9440).host_name(), None);
+        let address: SocketAddr = "10.0.0.1:9440".parse().unwrap();
+        assert_eq!(address.host_name(), None);
     }
 
     #[test]
@@ -646,23 +1907,561 @@ mod tests {
         let opts = ClientOptions {
             username: "admin".to_string(),
             password: "secret".to_string(),
+            authentication: Authentication::InterserverSecret {
+                cluster: "cluster".to_string(),
+                secret: "cluster-secret".to_string(),
+            },
             default_database: "mydb".to_string(),
+            client_name: "billing-service".to_string(),
+            client_hostname: "billing-1".to_string(),
+            os_user: "billing".to_string(),
+            quota_key: "tenant-1".to_string(),
             tcp_nodelay: false,
             connect_timeout: Some(Duration::from_secs(5)),
             tcp_keepalive: None,
+            #[cfg(feature = "tls")]
+            tls: Some(TlsOptions {
+                server_name: Some("clickhouse.example.com".to_string()),
+                ..Default::default()
+            }),
+            compression: Compression::Zstd(3),
             max_pending_queries: 500,
+            queue_overflow_policy: QueueOverflowPolicy::RejectNew,
             block_channel_size: 64,
             request_channel_size: 2048,
+            settings: QuerySettings::new().with("max_threads", 4u64),
+            forward_server_logs: true,
+            idle_ping_interval: Some(Duration::from_secs(30)),
+            ping_timeout: Duration::from_secs(1),
+            query_timeout: Some(Duration::from_secs(60)),
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(20)),
+            reconnect: Some(ReconnectOptions::default()),
+            retry: Some(RetryPolicy::default()),
+            blocking_compression_threshold: None,
         };
         assert_eq!(opts.username, "admin");
         assert_eq!(opts.password, "secret");
+        assert!(matches!(
+            opts.authentication,
+            Authentication::InterserverSecret { .. }
+        ));
         assert_eq!(opts.default_database, "mydb");
+        assert_eq!(opts.client_name, "billing-service");
+        assert_eq!(opts.client_hostname, "billing-1");
+        assert_eq!(opts.os_user, "billing");
+        assert_eq!(opts.quota_key, "tenant-1");
         assert!(!opts.tcp_nodelay);
         assert_eq!(opts.connect_timeout, Some(Duration::from_secs(5)));
         assert!(opts.tcp_keepalive.is_none());
+        #[cfg(feature = "tls")]
+        assert_eq!(
+            opts.tls.and_then(|tls| tls.server_name).as_deref(),
+            Some("clickhouse.example.com")
+        );
+        assert_eq!(opts.compression, Compression::Zstd(3));
         assert_eq!(opts.max_pending_queries, 500);
+        assert_eq!(opts.queue_overflow_policy, QueueOverflowPolicy::RejectNew);
         assert_eq!(opts.block_channel_size, 64);
         assert_eq!(opts.request_channel_size, 2048);
+        assert_eq!(opts.settings.len(), 1);
+        assert!(opts.forward_server_logs);
+        assert_eq!(opts.idle_ping_interval, Some(Duration::from_secs(30)));
+        assert_eq!(opts.ping_timeout, Duration::from_secs(1));
+        assert_eq!(opts.query_timeout, Some(Duration::from_secs(60)));
+        assert_eq!(opts.read_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(opts.write_timeout, Some(Duration::from_secs(20)));
+        assert_eq!(opts.reconnect, Some(ReconnectOptions::default()));
+        assert_eq!(opts.retry, Some(RetryPolicy::default()));
+        assert!(opts.blocking_compression_threshold.is_none());
+    }
+
+    #[test]
+    fn test_kill_query_status_parse() {
+        assert_eq!(KillQueryStatus::parse("waiting"), KillQueryStatus::Waiting);
+        assert_eq!(
+            KillQueryStatus::parse("finished"),
+            KillQueryStatus::Finished
+        );
+        assert_eq!(
+            KillQueryStatus::parse("cant_cancel"),
+            KillQueryStatus::CantCancel
+        );
+        assert_eq!(
+            KillQueryStatus::parse("unknown"),
+            KillQueryStatus::Other("unknown".to_string())
+        );
+    }
+
+    /// Answers the client hello with a minimal server hello, then answers pings if `answer_pings` is set.
+    /// Reads the client hello, and answers with a server hello.
+    async fn fake_handshake(stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) -> Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        let mut buf = [0u8; 1024];
+        let _ = stream.read(&mut buf).await?;
+        fake_hello(stream).await
+    }
+
+    /// Sends a minimal server hello.
+    async fn fake_hello(stream: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut hello: Vec<u8> = vec![];
+        hello
+            .write_var_uint(protocol::ServerPacketId::Hello as u64)
+            .await?;
+        hello.write_string("ClickHouse").await?;
+        hello.write_var_uint(23).await?;
+        hello.write_var_uint(8).await?;
+        hello.write_var_uint(54000).await?;
+        stream.write_all(&hello).await?;
+        Ok(())
+    }
+
+    async fn fake_server(
+        mut stream: impl AsyncRead + AsyncWrite + Unpin,
+        answer_pings: bool,
+    ) -> Result<()> {
+        fake_handshake(&mut stream).await?;
+        fake_answers(stream, answer_pings).await
+    }
+
+    /// Answers the packets of a client after the handshake, see [`fake_server`].
+    async fn fake_answers(
+        mut stream: impl AsyncRead + AsyncWrite + Unpin,
+        answer_pings: bool,
+    ) -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            // Cancel packets are flushed on their own.
+            if buf[..n] == [protocol::ClientPacketId::Cancel as u8] {
+                stream
+                    .write_all(&[protocol::ServerPacketId::EndOfStream as u8])
+                    .await?;
+            }
+            if answer_pings && buf[..n].contains(&(protocol::ClientPacketId::Ping as u8)) {
+                stream
+                    .write_all(&[protocol::ServerPacketId::Pong as u8])
+                    .await?;
+            }
+        }
+    }
+
+    /// Options of a client of the fake server, which doesn't answer the `SET` statements of settings.
+    fn fake_options() -> ClientOptions {
+        ClientOptions {
+            settings: QuerySettings::new(),
+            ..Default::default()
+        }
+    }
+
+    async fn fake_client(answer_pings: bool, options: ClientOptions) -> Client {
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        tokio::spawn(fake_server(server_stream, answer_pings));
+        let (read, write) = tokio::io::split(client_stream);
+        Client::connect_stream(read, write, options).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let client = fake_client(true, fake_options()).await;
+        client.ping(Duration::from_secs(5)).await.unwrap();
+        client.ping(Duration::from_secs(5)).await.unwrap();
+        assert!(!client.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_ping_timeout() {
+        let client = fake_client(false, fake_options()).await;
+        let result = client.ping(Duration::from_millis(100)).await;
+        assert!(matches!(result, Err(KlickhouseError::Timeout(_))));
+    }
+
+    #[cfg(feature = "ssh")]
+    #[tokio::test]
+    async fn test_ssh_key_authentication() {
+        use protocol::{ClientPacketId, ServerPacketId};
+        use signature::Verifier;
+        use tokio::io::AsyncWriteExt;
+
+        let key = ssh_key::PrivateKey::from(ssh_key::private::Ed25519Keypair::from_seed(&[7; 32]));
+        let public_key = key.public_key().clone();
+        let (client_stream, mut server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            assert_eq!(server.read_var_uint().await?, ClientPacketId::Hello as u64);
+            // Client name, version and revision.
+            server.read_string().await?;
+            for _ in 0..3 {
+                server.read_var_uint().await?;
+            }
+            let database = server.read_utf8_string().await?;
+            let user = server.read_utf8_string().await?;
+            assert_eq!(server.read_string().await?, b"");
+            let user = user.strip_prefix(auth::USER_SSH_KEY_MARKER).unwrap();
+
+            assert_eq!(
+                server.read_var_uint().await?,
+                ClientPacketId::SshChallengeRequest as u64
+            );
+            let mut challenge: Vec<u8> = vec![];
+            challenge
+                .write_var_uint(ServerPacketId::SshChallenge as u64)
+                .await?;
+            challenge.write_string("nonce").await?;
+            server.write_all(&challenge).await?;
+            assert_eq!(
+                server.read_var_uint().await?,
+                ClientPacketId::SshChallengeResponse as u64
+            );
+            let signature = ssh_key::Signature::try_from(&server.read_string().await?[..]).unwrap();
+            let message = format!(
+                "{}{database}{user}nonce",
+                protocol::DBMS_TCP_PROTOCOL_VERSION
+            );
+            Verifier::verify(&public_key, message.as_bytes(), &signature).unwrap();
+
+            fake_hello(&mut server).await?;
+            assert_eq!(server.read_var_uint().await?, ClientPacketId::Ping as u64);
+            server.write_all(&[ServerPacketId::Pong as u8]).await?;
+            Ok::<_, KlickhouseError>(server)
+        });
+
+        let options = ClientOptions {
+            settings: QuerySettings::new(),
+            username: "alice".to_string(),
+            default_database: "db".to_string(),
+            authentication: Authentication::SshKey(Box::new(key)),
+            ..Default::default()
+        };
+        let (read, write) = tokio::io::split(client_stream);
+        let client = Client::connect_stream(read, write, options).await.unwrap();
+        client.ping(Duration::from_secs(5)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_settings_old_revision() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client_stream, mut server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            fake_handshake(&mut server).await?;
+            // The connection settings are applied with `SET` statements.
+            let statement = b"SET date_time_input_format = 'best_effort'";
+            let mut received = vec![];
+            while !received.windows(statement.len()).any(|w| w == statement) {
+                let mut buf = [0u8; 1024];
+                let n = server.read(&mut buf).await?;
+                received.extend_from_slice(&buf[..n]);
+            }
+            server
+                .write_all(&[protocol::ServerPacketId::EndOfStream as u8])
+                .await?;
+            fake_answers(server, true).await
+        });
+        let (read, write) = tokio::io::split(client_stream);
+        let client = Client::connect_stream(read, write, ClientOptions::default())
+            .await
+            .unwrap();
+        client.ping(Duration::from_secs(5)).await.unwrap();
+
+        // Settings of a query can't be sent, the connection stays usable.
+        let query = ParsedQuery::new("SELECT 1").with_setting("max_threads", 1u64);
+        let result = client.execute(&query).await;
+        assert!(matches!(result, Err(KlickhouseError::ProtocolError(_))));
+        client.ping(Duration::from_secs(5)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tables_status_old_revision() {
+        let client = fake_client(true, fake_options()).await;
+        assert!(client.tables_status(&[("db", "table")]).await.is_err());
+        // The connection stays usable.
+        client.ping(Duration::from_secs(5)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idle_ping_closes_unresponsive_client() {
+        let options = ClientOptions {
+            settings: QuerySettings::new(),
+            idle_ping_interval: Some(Duration::from_millis(50)),
+            ping_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let client = fake_client(false, options.clone()).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(client.is_closed());
+
+        let client = fake_client(true, options).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!client.is_closed());
+    }
+
+    /// Client whose first query never finishes, with a single pending query slot.
+    async fn busy_client(queue_overflow_policy: QueueOverflowPolicy) -> (Client, QueryHandle) {
+        let options = ClientOptions {
+            max_pending_queries: 1,
+            queue_overflow_policy,
+            settings: QuerySettings::new(),
+            ..Default::default()
+        };
+        let client = fake_client(true, options).await;
+        let executing = client.query_handle("SELECT 1").await.unwrap();
+        (client, executing)
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn test_send_blocks() {
+        use crate::testing::{Expectation, MockServer};
+
+        let server = MockServer::start().await.unwrap();
+        server.expect(
+            Expectation::query("INSERT INTO t FORMAT Native")
+                .accept_insert([("id", crate::Type::UInt64)]),
+        );
+        let options = ClientOptions {
+            blocking_compression_threshold: Some(0),
+            ..Default::default()
+        };
+        let client = server.client_with_options(options).await.unwrap();
+        let mut handle = client
+            .query_handle("INSERT INTO t FORMAT Native")
+            .await
+            .unwrap();
+        let blocks = (0..3u64).map(|i| {
+            let mut row = RawRow::default();
+            row.set("id", i);
+            Block::from_rows(
+                vec![row],
+                [("id".to_string(), crate::Type::UInt64)]
+                    .into_iter()
+                    .collect(),
+            )
+        });
+        client
+            .send_blocks(&handle, stream::iter(blocks))
+            .await
+            .unwrap();
+        while let Some(event) = handle.next().await {
+            event.unwrap();
+        }
+
+        // Each block was received whole and in order, without the empty block ending the data.
+        let inserted = server.inserted_blocks();
+        assert_eq!(inserted.len(), 3);
+        assert!(inserted.iter().all(|block| block.rows == 1));
+        let ids = server.queries()[0]
+            .inserted_rows::<crate::UnitValue<u64>>()
+            .unwrap();
+        assert_eq!(
+            ids.into_iter().map(|id| id.0).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queue_overflow_reject_new() {
+        let (client, _executing) = busy_client(QueueOverflowPolicy::RejectNew).await;
+        let pending = tokio::spawn({
+            let client = client.clone();
+            async move { client.query_handle("SELECT 2").await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let result = client.query_handle("SELECT 3").await;
+        assert!(matches!(result, Err(KlickhouseError::QueueOverflow(_))));
+        assert!(!pending.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_queue_overflow_evict_oldest() {
+        let (client, _executing) = busy_client(QueueOverflowPolicy::EvictOldest).await;
+        let evicted = tokio::spawn({
+            let client = client.clone();
+            async move { client.query_handle("SELECT 2").await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let pending = tokio::spawn({
+            let client = client.clone();
+            async move { client.query_handle("SELECT 3").await.map(|_| ()) }
+        });
+        let result = evicted.await.unwrap();
+        assert!(matches!(result, Err(KlickhouseError::QueueOverflow(_))));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!pending.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_queue_overflow_block() {
+        let (client, _executing) = busy_client(QueueOverflowPolicy::Block).await;
+        let pending = tokio::spawn({
+            let client = client.clone();
+            async move { client.query_handle("SELECT 2").await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let blocked =
+            tokio::time::timeout(Duration::from_millis(100), client.query_handle("SELECT 3")).await;
+        assert!(blocked.is_err());
+        assert!(!pending.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_dropped_handle() {
+        let options = ClientOptions {
+            settings: QuerySettings::new(),
+            ..Default::default()
+        };
+        let client = fake_client(true, options).await;
+        // The fake server never answers queries, the cancel can't wait for its next packet.
+        let handle = client.query_handle("SELECT 1").await.unwrap();
+        drop(handle);
+        client.ping(Duration::from_secs(5)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_query_timeout() {
+        let options = ClientOptions {
+            settings: QuerySettings::new(),
+            query_timeout: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let client = fake_client(true, options).await;
+        // The fake server never answers queries.
+        let query = ParsedQuery::new("SELECT 1").with_timeout(Duration::from_millis(100));
+        let mut handle = client.query_handle(&query).await.unwrap();
+        let result = handle.next().await.unwrap();
+        assert!(matches!(result, Err(KlickhouseError::Timeout(_))));
+        assert!(handle.next().await.is_none());
+
+        // The query was cancelled, the connection is usable again.
+        client.ping(Duration::from_secs(5)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_blocks_of_ended_query() {
+        let options = ClientOptions {
+            settings: QuerySettings::new(),
+            ..Default::default()
+        };
+        let client = fake_client(true, options).await;
+        let query = ParsedQuery::new("INSERT INTO t FORMAT native")
+            .with_timeout(Duration::from_millis(100));
+        let mut ended = client.query_handle(&query).await.unwrap();
+        assert!(matches!(
+            ended.next().await.unwrap(),
+            Err(KlickhouseError::Timeout(_))
+        ));
+        // The fake server never answers queries, this one keeps executing.
+        let _executing = client.query_handle("SELECT 1").await.unwrap();
+
+        let mut row = RawRow::default();
+        row.set("id", 1u64);
+        let block = Block::from_rows(
+            vec![row],
+            [("id".to_string(), crate::Type::UInt64)]
+                .into_iter()
+                .collect(),
+        );
+        let result = client.send_blocks(&ended, stream::iter([block])).await;
+        assert!(matches!(result, Err(KlickhouseError::ProtocolError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let options = ClientOptions {
+            settings: QuerySettings::new(),
+            query_timeout: Some(Duration::from_millis(50)),
+            retry: Some(RetryPolicy {
+                initial_backoff: Duration::from_millis(1),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let client = fake_client(true, options).await;
+        // The fake server never answers queries, a query that timed out isn't sent again.
+        let result = client.execute("SELECT 1").await;
+        assert!(matches!(result, Err(KlickhouseError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        let options = ClientOptions {
+            settings: QuerySettings::new(),
+            read_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let client = fake_client(false, options).await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        // Nothing is expected from the server while idle.
+        assert!(!client.is_closed());
+        let result = client.ping(Duration::from_secs(5)).await;
+        assert!(matches!(result, Err(KlickhouseError::ConnectionError(_))));
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn test_session_key() {
+        assert_eq!(session_key("USE db"), Some("USE".to_string()));
+        assert_eq!(session_key("  use other;"), Some("USE".to_string()));
+        assert_eq!(
+            session_key("SET max_threads = 2;\n"),
+            Some("SET max_threads = 2".to_string())
+        );
+        assert_eq!(session_key("SELECT 1"), None);
+        assert_eq!(session_key("SETTINGS"), None);
+        assert_eq!(session_key(""), None);
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let policy = ReconnectOptions {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            max_attempts: None,
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_secs(1));
+        assert_eq!(policy.backoff(100), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // The first connection is closed by the server once a query is received.
+            let (mut stream, _) = listener.accept().await?;
+            fake_handshake(&mut stream).await?;
+            let _ = stream.read(&mut [0u8; 1024]).await?;
+            drop(stream);
+            let (stream, _) = listener.accept().await?;
+            fake_server(stream, true).await
+        });
+
+        let options = ClientOptions {
+            settings: QuerySettings::new(),
+            reconnect: Some(ReconnectOptions {
+                initial_backoff: Duration::from_millis(10),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let client = Client::connect(address, options).await.unwrap();
+        let mut handle = client.query_handle("SELECT 1").await.unwrap();
+        let result = handle.next().await.unwrap();
+        assert!(matches!(result, Err(KlickhouseError::ConnectionLost(_))));
+
+        client.ping(Duration::from_secs(5)).await.unwrap();
+        assert!(!client.is_closed());
     }
 
     #[test]
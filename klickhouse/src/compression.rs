@@ -1,3 +1,4 @@
+#[cfg(feature = "compression")]
 use std::ffi::c_char;
 use std::future::Future;
 use std::io::ErrorKind;
@@ -10,21 +11,68 @@ use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
 use crate::block::Block;
 use crate::internal_client_in::MAX_COMPRESSION_SIZE;
 use crate::io::ClickhouseRead;
-use crate::protocol::CompressionMethod;
+use crate::protocol::{Compression, CompressionMethod};
 use crate::{KlickhouseError, Result};
 
-pub async fn compress_block(block: Block, revision: u64) -> Result<(Vec<u8>, usize)> {
+/// Serializes and compresses `block` into a checksummed frame. Blocks of at least `blocking_threshold` bytes are
+/// compressed on the blocking thread pool.
+pub async fn compress_block(
+    block: Block,
+    revision: u64,
+    compression: Compression,
+    blocking_threshold: Option<usize>,
+) -> Result<Vec<u8>> {
     let mut raw = vec![];
     block.write(&mut raw, revision).await?;
-    let raw_len = raw.len();
-    if raw.len() > i32::MAX as usize {
+    match blocking_threshold {
+        Some(threshold) if raw.len() >= threshold => {
+            tokio::task::spawn_blocking(move || compress_frame(&raw, compression))
+                .await
+                .map_err(|e| {
+                    KlickhouseError::CompressionError(format!("compression task failed: {e}"))
+                })?
+        }
+        _ => compress_frame(&raw, compression),
+    }
+}
+
+fn compress_frame(raw: &[u8], compression: Compression) -> Result<Vec<u8>> {
+    let compressed = match compression {
+        Compression::Lz4 => compress_lz4(raw)?,
+        #[cfg(feature = "compression")]
+        Compression::Lz4Hc(level) => compress_lz4_ffi(raw, Some(level))?,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd(level) => zstd::bulk::compress(raw, level).map_err(|e| {
+            KlickhouseError::CompressionError(format!("ZSTD compression failed: {e}"))
+        })?,
+        other => {
+            return Err(KlickhouseError::CompressionError(format!(
+                "unsupported compression: {other:?}"
+            )))
+        }
+    };
+    let mut frame = Vec::with_capacity(compressed.len() + 25);
+    frame.extend_from_slice(&[0u8; 16]);
+    frame.push(compression.method().byte());
+    frame.extend_from_slice(&(compressed.len() as u32 + 9).to_le_bytes()[..]);
+    frame.extend_from_slice(&(raw.len() as u32).to_le_bytes()[..]);
+    frame.extend(compressed);
+    let checksum = cityhash_rs::cityhash_102_128(&frame[16..]);
+    frame[..8].copy_from_slice(&((checksum >> 64) as u64).to_le_bytes()[..]);
+    frame[8..16].copy_from_slice(&(checksum as u64).to_le_bytes()[..]);
+    Ok(frame)
+}
+
+/// Returns the worst case size of `input_len` bytes compressed with LZ4, checking that it fits the frame format.
+fn lz4_capacity(input_len: usize) -> Result<usize> {
+    if input_len > i32::MAX as usize {
         return Err(KlickhouseError::CompressionError(format!(
             "input too large for LZ4: {} > {}",
-            raw.len(),
+            input_len,
             i32::MAX
         )));
     }
-    let capacity = raw.len() + (raw.len() / 255) + 16 + 1;
+    let capacity = input_len + (input_len / 255) + 16 + 1;
     if capacity > i32::MAX as usize {
         return Err(KlickhouseError::CompressionError(format!(
             "output buffer too large for LZ4: {} > {}",
@@ -32,14 +80,42 @@ pub async fn compress_block(block: Block, revision: u64) -> Result<(Vec<u8>, usi
             i32::MAX
         )));
     }
+    Ok(capacity)
+}
+
+/// Compresses `raw` with the pure Rust LZ4 implementation.
+#[cfg(feature = "lz4_flex")]
+fn compress_lz4(raw: &[u8]) -> Result<Vec<u8>> {
+    lz4_capacity(raw.len())?;
+    Ok(lz4_flex::block::compress(raw))
+}
+
+#[cfg(not(feature = "lz4_flex"))]
+fn compress_lz4(raw: &[u8]) -> Result<Vec<u8>> {
+    compress_lz4_ffi(raw, None)
+}
+
+/// Compresses `raw` with liblz4, or LZ4HC at the given level.
+#[cfg(feature = "compression")]
+fn compress_lz4_ffi(raw: &[u8], hc_level: Option<i32>) -> Result<Vec<u8>> {
+    let capacity = lz4_capacity(raw.len())?;
     let mut compressed = Vec::<u8>::with_capacity(capacity);
     let out_len = unsafe {
-        lz4::liblz4::LZ4_compress_default(
-            raw.as_ptr() as *const c_char,
-            compressed.as_mut_ptr() as *mut c_char,
-            raw.len() as i32,
-            compressed.capacity() as i32,
-        )
+        match hc_level {
+            Some(level) => lz4::liblz4::LZ4_compress_HC(
+                raw.as_ptr() as *const c_char,
+                compressed.as_mut_ptr() as *mut c_char,
+                raw.len() as i32,
+                compressed.capacity() as i32,
+                level,
+            ),
+            None => lz4::liblz4::LZ4_compress_default(
+                raw.as_ptr() as *const c_char,
+                compressed.as_mut_ptr() as *mut c_char,
+                raw.len() as i32,
+                compressed.capacity() as i32,
+            ),
+        }
     };
     if out_len <= 0 {
         return Err(KlickhouseError::CompressionError(
@@ -53,13 +129,23 @@ pub async fn compress_block(block: Block, revision: u64) -> Result<(Vec<u8>, usi
             compressed.capacity()
         )));
     }
-    // SAFETY: LZ4_compress_default wrote exactly `out_len` bytes into the buffer,
+    // SAFETY: LZ4_compress_default and LZ4_compress_HC wrote exactly `out_len` bytes into the buffer,
     // and we verified out_len <= capacity above.
     unsafe { compressed.set_len(out_len as usize) };
 
-    Ok((compressed, raw_len))
+    Ok(compressed)
 }
 
+#[cfg(feature = "lz4_flex")]
+pub fn decompress_block(data: &[u8], decompressed_size: u32) -> Result<Vec<u8>> {
+    lz4_flex::block::decompress(data, decompressed_size as usize).map_err(|e| {
+        KlickhouseError::CompressionError(format!(
+            "LZ4 decompression failed: malformed compressed block: {e}"
+        ))
+    })
+}
+
+#[cfg(not(feature = "lz4_flex"))]
 pub fn decompress_block(data: &[u8], decompressed_size: u32) -> Result<Vec<u8>> {
     if data.len() > i32::MAX as usize {
         return Err(KlickhouseError::CompressionError(format!(
@@ -98,21 +184,36 @@ pub fn decompress_block(data: &[u8], decompressed_size: u32) -> Result<Vec<u8>>
     Ok(output)
 }
 
+#[cfg(feature = "zstd")]
+fn decompress_zstd(data: &[u8], decompressed_size: u32) -> Result<Vec<u8>> {
+    let output = zstd::bulk::decompress(data, decompressed_size as usize).map_err(|e| {
+        KlickhouseError::CompressionError(format!("ZSTD decompression failed: {e}"))
+    })?;
+    if output.len() != decompressed_size as usize {
+        return Err(KlickhouseError::CompressionError(format!(
+            "ZSTD decompressed output ({}) doesn't match the expected size ({})",
+            output.len(),
+            decompressed_size
+        )));
+    }
+    Ok(output)
+}
+
+/// Reads a compressed frame, whose codec is given by its method byte. Frames of at least `blocking_threshold`
+/// decompressed bytes are verified and decompressed on the blocking thread pool.
 async fn read_compressed_blob(
     reader: &mut impl ClickhouseRead,
-    compression: CompressionMethod,
+    blocking_threshold: Option<usize>,
 ) -> Result<Vec<u8>> {
     let checksum =
         ((reader.read_u64_le().await? as u128) << 64u128) | (reader.read_u64_le().await? as u128);
     let type_byte = reader.read_u8().await?;
-    if type_byte != compression.byte() {
-        return Err(KlickhouseError::ProtocolError(format!(
-            "unexpected compression algorithm identifier: '{:02X}', expected {:02X} ({:?})",
-            type_byte,
-            compression.byte(),
-            compression
-        )));
-    }
+    let method = CompressionMethod::from_byte(type_byte).ok_or_else(|| {
+        KlickhouseError::ProtocolError(format!(
+            "unexpected compression algorithm identifier: '{:02X}'",
+            type_byte
+        ))
+    })?;
     let compressed_size = reader.read_u32_le().await?;
     if compressed_size > MAX_COMPRESSION_SIZE {
         // 1 GB
@@ -127,11 +228,38 @@ async fn read_compressed_blob(
         )));
     }
     let decompressed_size = reader.read_u32_le().await?;
+    if decompressed_size > MAX_COMPRESSION_SIZE {
+        return Err(KlickhouseError::ProtocolError(format!(
+            "decompressed payload too large! {} > {}",
+            decompressed_size, MAX_COMPRESSION_SIZE
+        )));
+    }
     let mut compressed = vec![0u8; compressed_size as usize];
     reader.read_exact(&mut compressed[9..]).await?;
     compressed[0] = type_byte;
     compressed[1..5].copy_from_slice(&compressed_size.to_le_bytes()[..]);
     compressed[5..9].copy_from_slice(&decompressed_size.to_le_bytes()[..]);
+    match blocking_threshold {
+        Some(threshold) if decompressed_size as usize >= threshold => {
+            tokio::task::spawn_blocking(move || {
+                decompress_frame(compressed, checksum, method, decompressed_size)
+            })
+            .await
+            .map_err(|e| {
+                KlickhouseError::CompressionError(format!("decompression task failed: {e}"))
+            })?
+        }
+        _ => decompress_frame(compressed, checksum, method, decompressed_size),
+    }
+}
+
+/// Verifies the checksum of a frame read by [`read_compressed_blob`], and decompresses it.
+fn decompress_frame(
+    mut compressed: Vec<u8>,
+    checksum: u128,
+    method: CompressionMethod,
+    decompressed_size: u32,
+) -> Result<Vec<u8>> {
     let calc_checksum = cityhash_rs::cityhash_102_128(&compressed[..]);
     if calc_checksum != checksum {
         return Err(KlickhouseError::ProtocolError(format!(
@@ -139,7 +267,17 @@ async fn read_compressed_blob(
             calc_checksum, checksum
         )));
     }
-    let raw_block = crate::compression::decompress_block(&compressed[9..], decompressed_size)?;
+    let raw_block = match method {
+        CompressionMethod::None => compressed.split_off(9),
+        CompressionMethod::LZ4 => decompress_block(&compressed[9..], decompressed_size)?,
+        #[cfg(feature = "zstd")]
+        CompressionMethod::ZSTD => decompress_zstd(&compressed[9..], decompressed_size)?,
+        #[cfg(not(feature = "zstd"))]
+        CompressionMethod::ZSTD => return Err(KlickhouseError::CompressionError(
+            "received ZSTD compressed data, but klickhouse was compiled without the `zstd` feature"
+                .to_string(),
+        )),
+    };
     Ok(raw_block)
 }
 
@@ -147,18 +285,18 @@ type BlockReadingFuture<R> =
     Pin<Box<dyn Future<Output = Result<(Vec<u8>, &'static mut R)>> + Send + Sync>>;
 
 pub struct DecompressionReader<'a, R: ClickhouseRead + 'static> {
-    mode: CompressionMethod,
     inner: Option<&'a mut R>,
+    blocking_threshold: Option<usize>,
     decompressed: Vec<u8>,
     position: usize,
     block_reading_future: Option<BlockReadingFuture<R>>,
 }
 
 impl<'a, R: ClickhouseRead + 'static> DecompressionReader<'a, R> {
-    pub fn new(mode: CompressionMethod, inner: &'a mut R) -> Self {
+    pub fn new(inner: &'a mut R, blocking_threshold: Option<usize>) -> Self {
         Self {
-            mode,
             inner: Some(inner),
+            blocking_threshold,
             decompressed: vec![],
             position: 0,
             block_reading_future: None,
@@ -230,6 +368,7 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "compression")]
     #[test]
     fn test_lz4_compress_decompress_roundtrip() {
         use std::ffi::c_char;
@@ -254,6 +393,114 @@ mod tests {
         let decompressed = decompress_block(&compressed, original.len() as u32).unwrap();
         assert_eq!(&decompressed[..], &original[..]);
     }
+
+    /// Frames `raw` as the server would, compressed with `compression`.
+    fn frame(raw: &[u8], compression: Compression) -> Vec<u8> {
+        let compressed = match compression {
+            Compression::None => raw.to_vec(),
+            Compression::Lz4 => compress_lz4(raw).unwrap(),
+            #[cfg(feature = "compression")]
+            Compression::Lz4Hc(level) => compress_lz4_ffi(raw, Some(level)).unwrap(),
+            #[cfg(not(feature = "compression"))]
+            Compression::Lz4Hc(_) => unreachable!(),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => zstd::bulk::compress(raw, level).unwrap(),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd(_) => vec![0x28, 0xb5, 0x2f, 0xfd],
+        };
+        let mut body = vec![compression.method().byte()];
+        body.extend_from_slice(&(compressed.len() as u32 + 9).to_le_bytes());
+        body.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+        body.extend(compressed);
+        let checksum = cityhash_rs::cityhash_102_128(&body);
+        let mut frame = vec![];
+        frame.extend_from_slice(&((checksum >> 64) as u64).to_le_bytes());
+        frame.extend_from_slice(&(checksum as u64).to_le_bytes());
+        frame.extend(body);
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_read_compressed_blob() {
+        let raw = b"Hello, ClickHouse! Hello, ClickHouse! Hello, ClickHouse!".repeat(10);
+        for compression in [
+            Compression::None,
+            Compression::Lz4,
+            #[cfg(feature = "compression")]
+            Compression::Lz4Hc(9),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(3),
+        ] {
+            let frame = frame(&raw, compression);
+            let decompressed = read_compressed_blob(&mut &frame[..], None).await.unwrap();
+            assert_eq!(decompressed, raw, "{compression:?}");
+        }
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[tokio::test]
+    async fn test_read_zstd_blob_without_feature() {
+        let frame = frame(b"data", Compression::Zstd(1));
+        assert!(matches!(
+            read_compressed_blob(&mut &frame[..], None).await,
+            Err(KlickhouseError::CompressionError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_blocking_threshold() {
+        let raw = b"Hello, ClickHouse! Hello, ClickHouse! Hello, ClickHouse!".repeat(10);
+        let compressed = compress_frame(&raw, Compression::Lz4).unwrap();
+        assert_eq!(compressed, frame(&raw, Compression::Lz4));
+        for threshold in [None, Some(0), Some(raw.len() + 1)] {
+            let decompressed = read_compressed_blob(&mut &compressed[..], threshold)
+                .await
+                .unwrap();
+            assert_eq!(decompressed, raw, "{threshold:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compress_block_offloaded() {
+        let rows = (0..1000u64)
+            .map(|id| {
+                let mut row = crate::RawRow::default();
+                row.set("id", id);
+                row
+            })
+            .collect::<Vec<_>>();
+        let column_types = [("id".to_string(), crate::Type::UInt64)]
+            .into_iter()
+            .collect();
+        let block = Block::from_rows(rows, column_types).unwrap();
+        let inline = compress_block(block.clone(), 0, Compression::Lz4, None)
+            .await
+            .unwrap();
+        let offloaded = compress_block(block, 0, Compression::Lz4, Some(0))
+            .await
+            .unwrap();
+        assert_eq!(inline, offloaded);
+    }
+
+    #[tokio::test]
+    async fn test_read_decompressed_size_limit() {
+        let mut frame = frame(b"data", Compression::Lz4);
+        frame[21..25].copy_from_slice(&(MAX_COMPRESSION_SIZE + 1).to_le_bytes());
+        assert!(matches!(
+            read_compressed_blob(&mut &frame[..], None).await,
+            Err(KlickhouseError::ProtocolError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_unknown_method() {
+        let mut frame = frame(b"data", Compression::Lz4);
+        frame[16] = 0x42;
+        assert!(matches!(
+            read_compressed_blob(&mut &frame[..], None).await,
+            Err(KlickhouseError::ProtocolError(_))
+        ));
+    }
 }
 
 impl<R: ClickhouseRead + 'static> AsyncRead for DecompressionReader<'_, R> {
@@ -290,9 +537,9 @@ impl<R: ClickhouseRead + 'static> AsyncRead for DecompressionReader<'_, R> {
             //   4. While the future is alive, `self.inner` is `None`, preventing aliasing.
             // This pattern avoids requiring `R: 'static` on the public API.
             let static_inner: &'static mut R = unsafe { std::mem::transmute(inner) };
-            let mode = self.mode;
+            let blocking_threshold = self.blocking_threshold;
             self.block_reading_future = Some(Box::pin(async move {
-                let value = read_compressed_blob(static_inner, mode).await?;
+                let value = read_compressed_blob(static_inner, blocking_threshold).await?;
                 Ok((value, static_inner))
             }));
             match self.run_decompression(cx) {
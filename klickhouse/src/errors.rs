@@ -2,7 +2,7 @@ use std::{borrow::Cow, string::FromUtf8Error};
 
 use thiserror::Error;
 
-use crate::Type;
+use crate::{ErrorCode, ServerException, Type};
 
 #[derive(Error, Debug)]
 pub enum KlickhouseError {
@@ -26,13 +26,8 @@ pub enum KlickhouseError {
     SerializeError(String),
     #[error("deserialize error for column {0}: {1}")]
     DeserializeErrorWithColumn(&'static str, String),
-    #[error("server exception: {code} {name}: {message}\n{stack_trace}")]
-    ServerException {
-        code: i32,
-        name: String,
-        message: String,
-        stack_trace: String,
-    },
+    #[error("server exception: {0}")]
+    ServerException(ServerException),
     #[error("unexpected type: {0}")]
     UnexpectedType(Type),
     #[error("unexpected type for column {0}: {1}")]
@@ -49,6 +44,17 @@ pub enum KlickhouseError {
     CompressionError(String),
     #[error("not implemented: {0}")]
     NotImplemented(String),
+    #[error("query queue overflow: {0}")]
+    QueueOverflow(String),
+    #[error("connection lost: {0}")]
+    ConnectionLost(String),
+    #[error("invalid connection string: {0}")]
+    InvalidDsn(String),
+    #[error("failed after {attempts} attempts: {source}")]
+    RetriesExhausted {
+        attempts: u32,
+        source: Box<KlickhouseError>,
+    },
 }
 
 impl KlickhouseError {
@@ -63,6 +69,27 @@ impl KlickhouseError {
             x => x,
         }
     }
+
+    /// Code of the exception sent by the server, if this error is one, or the last error of
+    /// [`KlickhouseError::RetriesExhausted`].
+    pub fn server_code(&self) -> Option<ErrorCode> {
+        match self {
+            KlickhouseError::ServerException(exception) => Some(exception.code),
+            KlickhouseError::RetriesExhausted { source, .. } => source.server_code(),
+            _ => None,
+        }
+    }
+
+    /// Whether the operation may succeed if attempted again: lost connections, IO errors and retryable server
+    /// exceptions (see [`ErrorCode::is_retryable`]). Like `TIMEOUT_EXCEEDED` on the server, a query that timed out
+    /// would likely time out again and isn't retryable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            KlickhouseError::ServerException(exception) => exception.is_retryable(),
+            KlickhouseError::ConnectionLost(_) | KlickhouseError::Io(_) => true,
+            _ => false,
+        }
+    }
 }
 
 impl Clone for KlickhouseError {
@@ -80,17 +107,7 @@ impl Clone for KlickhouseError {
             Self::DeserializeErrorWithColumn(arg0, arg1) => {
                 Self::DeserializeErrorWithColumn(arg0, arg1.clone())
             }
-            Self::ServerException {
-                code,
-                name,
-                message,
-                stack_trace,
-            } => Self::ServerException {
-                code: *code,
-                name: name.clone(),
-                message: message.clone(),
-                stack_trace: stack_trace.clone(),
-            },
+            Self::ServerException(arg0) => Self::ServerException(arg0.clone()),
             Self::UnexpectedType(arg0) => Self::UnexpectedType(arg0.clone()),
             Self::UnexpectedTypeWithColumn(arg0, arg1) => {
                 Self::UnexpectedTypeWithColumn(arg0.clone(), arg1.clone())
@@ -101,6 +118,13 @@ impl Clone for KlickhouseError {
             Self::ConnectionError(arg0) => Self::ConnectionError(arg0.clone()),
             Self::CompressionError(arg0) => Self::CompressionError(arg0.clone()),
             Self::NotImplemented(arg0) => Self::NotImplemented(arg0.clone()),
+            Self::QueueOverflow(arg0) => Self::QueueOverflow(arg0.clone()),
+            Self::ConnectionLost(arg0) => Self::ConnectionLost(arg0.clone()),
+            Self::InvalidDsn(arg0) => Self::InvalidDsn(arg0.clone()),
+            Self::RetriesExhausted { attempts, source } => Self::RetriesExhausted {
+                attempts: *attempts,
+                source: source.clone(),
+            },
         }
     }
 }
@@ -149,6 +173,31 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_is_retryable() {
+        let exception = |code| {
+            KlickhouseError::ServerException(ServerException {
+                code,
+                name: "DB::Exception".to_string(),
+                message: String::new(),
+                stack_trace: String::new(),
+                nested: None,
+            })
+        };
+        assert!(exception(ErrorCode::TooManySimultaneousQueries).is_retryable());
+        assert!(!exception(ErrorCode::SyntaxError).is_retryable());
+        assert_eq!(
+            exception(ErrorCode::SyntaxError).server_code(),
+            Some(ErrorCode::SyntaxError)
+        );
+        assert!(KlickhouseError::ConnectionLost("reset".into()).is_retryable());
+        assert!(!exception(ErrorCode::TimeoutExceeded).is_retryable());
+        assert!(!KlickhouseError::Timeout("t".into()).is_retryable());
+        assert!(!KlickhouseError::ConnectionError("closed".into()).is_retryable());
+        assert!(!KlickhouseError::MissingRow.is_retryable());
+        assert!(KlickhouseError::MissingRow.server_code().is_none());
+    }
+
     #[test]
     fn test_with_column_name_passthrough_for_new_variants() {
         // New variants should pass through with_column_name unchanged
@@ -0,0 +1,263 @@
+use std::fmt;
+
+use crate::KlickhouseError;
+
+macro_rules! error_codes {
+    ($($name:ident = $code:literal,)+) => {
+        pastey::paste! {
+            /// Code of a Clickhouse exception, as in `system.errors`. Codes without a variant are kept in
+            /// [`ErrorCode::Other`].
+            ///
+            /// See https://github.com/ClickHouse/ClickHouse/blob/master/src/Common/ErrorCodes.cpp
+            #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+            #[non_exhaustive]
+            pub enum ErrorCode {
+                $(
+                    #[doc = concat!("`", stringify!($name), "` (", stringify!($code), ")")]
+                    [<$name:camel>],
+                )+
+                Other(i32),
+            }
+
+            impl ErrorCode {
+                pub fn from_code(code: i32) -> Self {
+                    match code {
+                        $($code => ErrorCode::[<$name:camel>],)+
+                        code => ErrorCode::Other(code),
+                    }
+                }
+
+                pub fn code(&self) -> i32 {
+                    match self {
+                        $(ErrorCode::[<$name:camel>] => $code,)+
+                        ErrorCode::Other(code) => *code,
+                    }
+                }
+
+                /// Name of the code in Clickhouse, i.e. `TABLE_ALREADY_EXISTS`.
+                pub fn name(&self) -> Option<&'static str> {
+                    match self {
+                        $(ErrorCode::[<$name:camel>] => Some(stringify!($name)),)+
+                        ErrorCode::Other(_) => None,
+                    }
+                }
+            }
+        }
+    };
+}
+
+error_codes! {
+    OK = 0,
+    UNSUPPORTED_METHOD = 1,
+    UNSUPPORTED_PARAMETER = 2,
+    UNEXPECTED_END_OF_FILE = 3,
+    CANNOT_PARSE_TEXT = 6,
+    THERE_IS_NO_COLUMN = 8,
+    NOT_FOUND_COLUMN_IN_BLOCK = 10,
+    NO_SUCH_COLUMN_IN_TABLE = 16,
+    ATTEMPT_TO_READ_AFTER_EOF = 32,
+    CANNOT_READ_ALL_DATA = 33,
+    BAD_ARGUMENTS = 36,
+    CANNOT_PARSE_DATE = 38,
+    CANNOT_PARSE_DATETIME = 41,
+    NUMBER_OF_ARGUMENTS_DOESNT_MATCH = 42,
+    ILLEGAL_TYPE_OF_ARGUMENT = 43,
+    ILLEGAL_COLUMN = 44,
+    UNKNOWN_FUNCTION = 46,
+    UNKNOWN_IDENTIFIER = 47,
+    NOT_IMPLEMENTED = 48,
+    LOGICAL_ERROR = 49,
+    UNKNOWN_TYPE = 50,
+    TYPE_MISMATCH = 53,
+    TABLE_ALREADY_EXISTS = 57,
+    UNKNOWN_TABLE = 60,
+    SYNTAX_ERROR = 62,
+    UNKNOWN_AGGREGATE_FUNCTION = 63,
+    CANNOT_CONVERT_TYPE = 70,
+    UNKNOWN_FORMAT = 73,
+    INCORRECT_QUERY = 80,
+    UNKNOWN_DATABASE = 81,
+    DATABASE_ALREADY_EXISTS = 82,
+    UNEXPECTED_PACKET_FROM_CLIENT = 101,
+    UNEXPECTED_PACKET_FROM_SERVER = 102,
+    UNKNOWN_SETTING = 115,
+    INCORRECT_DATA = 117,
+    TIMEOUT_EXCEEDED = 159,
+    TOO_SLOW = 160,
+    READONLY = 164,
+    CANNOT_ALLOCATE_MEMORY = 173,
+    UNKNOWN_USER = 192,
+    WRONG_PASSWORD = 193,
+    REQUIRED_PASSWORD = 194,
+    QUOTA_EXCEEDED = 201,
+    TOO_MANY_SIMULTANEOUS_QUERIES = 202,
+    NO_FREE_CONNECTION = 203,
+    SOCKET_TIMEOUT = 209,
+    NETWORK_ERROR = 210,
+    QUERY_WITH_SAME_ID_IS_ALREADY_RUNNING = 216,
+    CLIENT_HAS_CONNECTED_TO_WRONG_PORT = 217,
+    TABLE_IS_DROPPED = 218,
+    DATABASE_NOT_EMPTY = 219,
+    NO_ZOOKEEPER = 225,
+    ABORTED = 236,
+    MEMORY_LIMIT_EXCEEDED = 241,
+    TABLE_IS_READ_ONLY = 242,
+    TOO_MANY_PARTS = 252,
+    ALL_CONNECTION_TRIES_FAILED = 279,
+    TOO_FEW_LIVE_REPLICAS = 285,
+    UNSATISFIED_QUORUM_FOR_PREVIOUS_WRITE = 286,
+    UNKNOWN_STATUS_OF_INSERT = 319,
+    UNFINISHED = 341,
+    SESSION_NOT_FOUND = 372,
+    SESSION_IS_LOCKED = 373,
+    INSERT_WAS_DEDUPLICATED = 389,
+    QUERY_WAS_CANCELLED = 394,
+    SYSTEM_ERROR = 425,
+    CANNOT_SCHEDULE_TASK = 439,
+    DEADLOCK_AVOIDED = 473,
+    ACCESS_DENIED = 497,
+    AUTHENTICATION_FAILED = 516,
+    KEEPER_EXCEPTION = 999,
+    POCO_EXCEPTION = 1000,
+    STD_EXCEPTION = 1001,
+    UNKNOWN_EXCEPTION = 1002,
+}
+
+impl ErrorCode {
+    /// Whether the error is transient, i.e. caused by the load of the server or the state of the cluster rather
+    /// than by the query, so that the same query may succeed later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::TooManySimultaneousQueries
+                | ErrorCode::NoFreeConnection
+                | ErrorCode::SocketTimeout
+                | ErrorCode::NetworkError
+                | ErrorCode::NoZookeeper
+                | ErrorCode::Aborted
+                | ErrorCode::TableIsReadOnly
+                | ErrorCode::TooManyParts
+                | ErrorCode::AllConnectionTriesFailed
+                | ErrorCode::TooFewLiveReplicas
+                | ErrorCode::UnsatisfiedQuorumForPreviousWrite
+                | ErrorCode::UnknownStatusOfInsert
+                | ErrorCode::SessionIsLocked
+                | ErrorCode::CannotScheduleTask
+                | ErrorCode::DeadlockAvoided
+                | ErrorCode::KeeperException
+        )
+    }
+}
+
+impl From<i32> for ErrorCode {
+    fn from(code: i32) -> Self {
+        ErrorCode::from_code(code)
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "{}", self.code()),
+        }
+    }
+}
+
+/// An exception sent by the server, with the exceptions that caused it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerException {
+    pub code: ErrorCode,
+    /// Class of the exception, i.e. `DB::Exception`.
+    pub name: String,
+    pub message: String,
+    pub stack_trace: String,
+    /// Exception that caused this one.
+    pub nested: Option<Box<ServerException>>,
+}
+
+impl ServerException {
+    /// This exception, then the exceptions that caused it.
+    pub fn chain(&self) -> impl Iterator<Item = &ServerException> {
+        std::iter::successors(Some(self), |exception| exception.nested.as_deref())
+    }
+
+    /// Whether the query may succeed if sent again, see [`ErrorCode::is_retryable`].
+    pub fn is_retryable(&self) -> bool {
+        self.code.is_retryable()
+    }
+}
+
+impl fmt::Display for ServerException {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {}\n{}",
+            self.code.code(),
+            self.name,
+            self.message,
+            self.stack_trace
+        )?;
+        if let Some(nested) = &self.nested {
+            write!(f, "\ncaused by: {nested}")?;
+        }
+        Ok(())
+    }
+}
+
+impl From<ServerException> for KlickhouseError {
+    fn from(exception: ServerException) -> Self {
+        KlickhouseError::ServerException(exception)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_code() {
+        let code = ErrorCode::from_code(57);
+        assert_eq!(code, ErrorCode::TableAlreadyExists);
+        assert_eq!(code.code(), 57);
+        assert_eq!(code.name(), Some("TABLE_ALREADY_EXISTS"));
+        assert_eq!(code.to_string(), "TABLE_ALREADY_EXISTS");
+        assert_eq!(ErrorCode::from_code(0), ErrorCode::Ok);
+
+        let code = ErrorCode::from_code(123456);
+        assert_eq!(code, ErrorCode::Other(123456));
+        assert_eq!(code.code(), 123456);
+        assert_eq!(code.to_string(), "123456");
+    }
+
+    #[test]
+    fn test_retryable() {
+        assert!(ErrorCode::TooManySimultaneousQueries.is_retryable());
+        assert!(ErrorCode::KeeperException.is_retryable());
+        assert!(!ErrorCode::SyntaxError.is_retryable());
+        assert!(!ErrorCode::TableAlreadyExists.is_retryable());
+        assert!(!ErrorCode::Other(123456).is_retryable());
+    }
+
+    #[test]
+    fn test_chain_display() {
+        let exception = ServerException {
+            code: ErrorCode::UnknownTable,
+            name: "DB::Exception".to_string(),
+            message: "Table t does not exist".to_string(),
+            stack_trace: String::new(),
+            nested: Some(Box::new(ServerException {
+                code: ErrorCode::Other(123456),
+                name: "DB::Exception".to_string(),
+                message: "cause".to_string(),
+                stack_trace: String::new(),
+                nested: None,
+            })),
+        };
+        assert_eq!(exception.chain().count(), 2);
+        assert_eq!(
+            exception.to_string(),
+            "60 DB::Exception: Table t does not exist\n\ncaused by: 123456 DB::Exception: cause\n"
+        );
+    }
+}
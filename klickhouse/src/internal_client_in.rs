@@ -1,67 +1,94 @@
 use crate::Result;
 use crate::{
     block::Block,
+    exception::{ErrorCode, ServerException},
     io::ClickhouseRead,
     progress::Progress,
     protocol::{
-        self, BlockStreamProfileInfo, CompressionMethod, ServerData, ServerException, ServerHello,
-        ServerPacket, TableColumns, TableStatus, TablesStatusResponse,
-        DBMS_MIN_REVISION_WITH_CLIENT_WRITE_INFO, DBMS_MIN_REVISION_WITH_SERVER_DISPLAY_NAME,
-        DBMS_MIN_REVISION_WITH_SERVER_TIMEZONE, DBMS_MIN_REVISION_WITH_VERSION_PATCH,
-        MAX_STRING_SIZE,
+        self, BlockStreamProfileInfo, CompressionMethod, ServerData, ServerHello, ServerPacket,
+        TableColumns, TableStatus, TablesStatusResponse,
+        DBMS_MIN_PROTOCOL_VERSION_WITH_PASSWORD_COMPLEXITY_RULES,
+        DBMS_MIN_PROTOCOL_VERSION_WITH_SERVER_QUERY_TIME_IN_PROGRESS,
+        DBMS_MIN_PROTOCOL_VERSION_WITH_TOTAL_BYTES_IN_PROGRESS,
+        DBMS_MIN_REVISION_WITH_CLIENT_WRITE_INFO, DBMS_MIN_REVISION_WITH_INTERSERVER_SECRET_V2,
+        DBMS_MIN_REVISION_WITH_SERVER_DISPLAY_NAME, DBMS_MIN_REVISION_WITH_SERVER_TIMEZONE,
+        DBMS_MIN_REVISION_WITH_VERSION_PATCH, MAX_STRING_SIZE,
     },
     KlickhouseError,
 };
 use indexmap::IndexMap;
 use log::trace;
 use protocol::ServerPacketId;
-use tokio::io::AsyncReadExt;
+use tokio::{io::AsyncReadExt, select, sync::mpsc};
 use uuid::Uuid;
 
-#[cfg(feature = "compression")]
+#[cfg(any(feature = "compression", feature = "lz4_flex"))]
 pub(crate) const MAX_COMPRESSION_SIZE: u32 = 0x40000000;
 
 pub struct InternalClientIn<R: ClickhouseRead> {
     reader: R,
     pub server_hello: ServerHello,
+    /// Whether data blocks are compressed, as requested in the queries. The codec of each frame is read from it.
+    compression: CompressionMethod,
+    /// Size from which blocks are decompressed on the blocking thread pool.
+    #[cfg_attr(not(any(feature = "compression", feature = "lz4_flex")), allow(unused))]
+    blocking_threshold: Option<usize>,
 }
 
 impl<R: ClickhouseRead + 'static> InternalClientIn<R> {
-    pub fn new(reader: R) -> Self {
+    pub fn new(
+        reader: R,
+        compression: CompressionMethod,
+        blocking_threshold: Option<usize>,
+    ) -> Self {
         InternalClientIn {
             reader,
             server_hello: ServerHello::default(),
+            compression,
+            blocking_threshold,
         }
     }
 
+    /// Reads an exception and the chain of nested exceptions following it.
     async fn read_exception(&mut self) -> Result<ServerException> {
-        let code = self.reader.read_i32_le().await?;
-        let name = self.reader.read_utf8_string().await?;
-        let message = self.reader.read_utf8_string().await?;
-        let stack_trace = self.reader.read_utf8_string().await?;
-        let has_nested = self.reader.read_u8().await? != 0;
-
-        Ok(ServerException {
-            code,
-            name,
-            message,
-            stack_trace,
-            has_nested,
-        })
+        let mut chain = vec![];
+        loop {
+            let code = self.reader.read_i32_le().await?;
+            let name = self.reader.read_utf8_string().await?;
+            let message = self.reader.read_utf8_string().await?;
+            let stack_trace = self.reader.read_utf8_string().await?;
+            let has_nested = self.reader.read_u8().await? != 0;
+            chain.push(ServerException {
+                code: ErrorCode::from_code(code),
+                name,
+                message,
+                stack_trace,
+                nested: None,
+            });
+            if !has_nested {
+                break;
+            }
+        }
+        let mut exception = chain.pop().unwrap();
+        while let Some(mut parent) = chain.pop() {
+            parent.nested = Some(Box::new(exception));
+            exception = parent;
+        }
+        Ok(exception)
     }
 
-    #[cfg(feature = "compression")]
-    async fn decompress_data(&mut self, compression: CompressionMethod) -> Result<Block> {
+    #[cfg(any(feature = "compression", feature = "lz4_flex"))]
+    async fn decompress_data(&mut self) -> Result<Block> {
         let mut reader =
-            crate::compression::DecompressionReader::new(compression, &mut self.reader);
+            crate::compression::DecompressionReader::new(&mut self.reader, self.blocking_threshold);
 
         let block = Block::read(&mut reader, self.server_hello.revision_version).await?;
 
         Ok(block)
     }
 
-    #[cfg(not(feature = "compression"))]
-    async fn decompress_data(&mut self, _compression: CompressionMethod) -> Result<Block> {
+    #[cfg(not(any(feature = "compression", feature = "lz4_flex")))]
+    async fn decompress_data(&mut self) -> Result<Block> {
         Err(KlickhouseError::CompressionError(
             "attempted to use compression when not compiled with `compression` feature in klickhouse".to_string(),
         ))
@@ -74,7 +101,7 @@ impl<R: ClickhouseRead + 'static> InternalClientIn<R> {
             CompressionMethod::None => {
                 Block::read(&mut self.reader, self.server_hello.revision_version).await?
             }
-            _ => self.decompress_data(compression).await?,
+            _ => self.decompress_data().await?,
         };
 
         Ok(ServerData { table_name, block })
@@ -92,7 +119,11 @@ impl<R: ClickhouseRead + 'static> InternalClientIn<R> {
                 let server_name = self.reader.read_utf8_string().await?;
                 let major_version = self.reader.read_var_uint().await?;
                 let minor_version = self.reader.read_var_uint().await?;
-                let revision_version = self.reader.read_var_uint().await?;
+                let revision_version = self
+                    .reader
+                    .read_var_uint()
+                    .await?
+                    .min(protocol::DBMS_TCP_PROTOCOL_VERSION);
                 let timezone = if revision_version > DBMS_MIN_REVISION_WITH_SERVER_TIMEZONE {
                     Some(self.reader.read_utf8_string().await?)
                 } else {
@@ -109,6 +140,26 @@ impl<R: ClickhouseRead + 'static> InternalClientIn<R> {
                 } else {
                     revision_version
                 };
+                let mut password_complexity_rules = vec![];
+                if revision_version >= DBMS_MIN_PROTOCOL_VERSION_WITH_PASSWORD_COMPLEXITY_RULES {
+                    let len = self.reader.read_var_uint().await?;
+                    if len as usize > MAX_STRING_SIZE {
+                        return Err(KlickhouseError::ProtocolError(format!(
+                            "password complexity rules size too large. {} > {}",
+                            len, MAX_STRING_SIZE
+                        )));
+                    }
+                    for _ in 0..len {
+                        let pattern = self.reader.read_utf8_string().await?;
+                        let message = self.reader.read_utf8_string().await?;
+                        password_complexity_rules.push((pattern, message));
+                    }
+                }
+                let nonce = if revision_version >= DBMS_MIN_REVISION_WITH_INTERSERVER_SECRET_V2 {
+                    Some(self.reader.read_u64_le().await?)
+                } else {
+                    None
+                };
                 Ok(ServerPacket::Hello(ServerHello {
                     server_name,
                     major_version,
@@ -117,16 +168,25 @@ impl<R: ClickhouseRead + 'static> InternalClientIn<R> {
                     timezone,
                     display_name,
                     patch_version,
+                    password_complexity_rules,
+                    nonce,
                 }))
             }
             ServerPacketId::Data => Ok(ServerPacket::Data(
-                self.receive_data(CompressionMethod::default()).await?,
+                self.receive_data(self.compression).await?,
             )),
             ServerPacketId::Exception => Ok(ServerPacket::Exception(self.read_exception().await?)),
             ServerPacketId::Progress => {
                 let read_rows = self.reader.read_var_uint().await?;
                 let read_bytes = self.reader.read_var_uint().await?;
                 let new_total_rows_to_read = self.reader.read_var_uint().await?;
+                let new_total_bytes_to_read = if self.server_hello.revision_version
+                    >= DBMS_MIN_PROTOCOL_VERSION_WITH_TOTAL_BYTES_IN_PROGRESS
+                {
+                    Some(self.reader.read_var_uint().await?)
+                } else {
+                    None
+                };
                 let new_written_rows = if self.server_hello.revision_version
                     >= DBMS_MIN_REVISION_WITH_CLIENT_WRITE_INFO
                 {
@@ -141,12 +201,21 @@ impl<R: ClickhouseRead + 'static> InternalClientIn<R> {
                 } else {
                     None
                 };
+                let elapsed_ns = if self.server_hello.revision_version
+                    >= DBMS_MIN_PROTOCOL_VERSION_WITH_SERVER_QUERY_TIME_IN_PROGRESS
+                {
+                    Some(self.reader.read_var_uint().await?)
+                } else {
+                    None
+                };
                 Ok(ServerPacket::Progress(Progress {
                     read_rows,
                     read_bytes,
                     new_total_rows_to_read,
+                    new_total_bytes_to_read,
                     new_written_rows,
                     new_written_bytes,
+                    elapsed_ns,
                 }))
             }
             ServerPacketId::Pong => Ok(ServerPacket::Pong),
@@ -168,10 +237,10 @@ impl<R: ClickhouseRead + 'static> InternalClientIn<R> {
                 }))
             }
             ServerPacketId::Totals => Ok(ServerPacket::Totals(
-                self.receive_data(CompressionMethod::default()).await?,
+                self.receive_data(self.compression).await?,
             )),
             ServerPacketId::Extremes => Ok(ServerPacket::Extremes(
-                self.receive_data(CompressionMethod::default()).await?,
+                self.receive_data(self.compression).await?,
             )),
             ServerPacketId::TablesStatusResponse => {
                 let mut response = TablesStatusResponse {
@@ -234,6 +303,16 @@ impl<R: ClickhouseRead + 'static> InternalClientIn<R> {
                 Ok(ServerPacket::PartUUIDs(out))
             }
             ServerPacketId::ReadTaskRequest => Ok(ServerPacket::ReadTaskRequest),
+            ServerPacketId::ProfileEvents => Ok(ServerPacket::ProfileEvents(
+                // Profile events are sent uncompressed, like log data.
+                self.receive_data(CompressionMethod::None).await?,
+            )),
+            ServerPacketId::TimezoneUpdate => Ok(ServerPacket::TimezoneUpdate(
+                self.reader.read_utf8_string().await?,
+            )),
+            ServerPacketId::SshChallenge => {
+                Ok(ServerPacket::SshChallenge(self.reader.read_string().await?))
+            }
         };
         let packet = packet?;
 
@@ -241,14 +320,96 @@ impl<R: ClickhouseRead + 'static> InternalClientIn<R> {
         Ok(packet)
     }
 
-    pub async fn receive_hello(&mut self) -> Result<ServerHello> {
+    /// Receives a packet of the handshake: the SSH challenge of SSH key authentication, or the server hello.
+    async fn receive_handshake_packet(&mut self) -> Result<ServerPacket> {
         match self.receive_packet().await? {
-            ServerPacket::Hello(hello) => Ok(hello),
-            ServerPacket::Exception(e) => Err(e.emit()),
+            ServerPacket::Hello(hello) => {
+                self.server_hello = hello.clone();
+                Ok(ServerPacket::Hello(hello))
+            }
+            packet @ ServerPacket::SshChallenge(_) => Ok(packet),
+            ServerPacket::Exception(e) => Err(e.into()),
             packet => Err(KlickhouseError::ProtocolError(format!(
                 "unexpected packet {:?}, expected server hello",
                 packet
             ))),
         }
     }
+
+    /// Reads packets into `sender` until the connection fails or `sender` is closed.
+    /// The handshake packets are read and forwarded first, the revision of the server hello is used to parse the
+    /// following packets.
+    ///
+    /// Runs in its own task, so that reading a packet is never interrupted halfway by the client task.
+    pub async fn run(mut self, sender: mpsc::Sender<Result<ServerPacket>>) {
+        loop {
+            let packet = self.receive_handshake_packet().await;
+            let failed = packet.is_err();
+            let hello = matches!(packet, Ok(ServerPacket::Hello(_)));
+            if sender.send(packet).await.is_err() || failed {
+                return;
+            }
+            if hello {
+                break;
+            }
+        }
+        loop {
+            let packet = select! {
+                packet = self.receive_packet() => packet,
+                _ = sender.closed() => return,
+            };
+            let failed = packet.is_err();
+            if sender.send(packet).await.is_err() || failed {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::ClickhouseWrite;
+    use tokio::io::AsyncWriteExt;
+
+    async fn write_exception(to: &mut Vec<u8>, code: i32, message: &str, has_nested: bool) {
+        to.write_i32_le(code).await.unwrap();
+        to.write_string("DB::Exception").await.unwrap();
+        to.write_string(message).await.unwrap();
+        to.write_string("").await.unwrap();
+        to.write_u8(has_nested as u8).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_nested_exception() {
+        let mut packets = vec![];
+        packets.push(ServerPacketId::Exception as u8);
+        write_exception(&mut packets, 57, "outer", true).await;
+        write_exception(&mut packets, 241, "middle", true).await;
+        write_exception(&mut packets, 123456, "inner", false).await;
+        packets.push(ServerPacketId::Pong as u8);
+
+        let mut client =
+            InternalClientIn::new(std::io::Cursor::new(packets), CompressionMethod::None, None);
+        let ServerPacket::Exception(exception) = client.receive_packet().await.unwrap() else {
+            panic!("expected an exception");
+        };
+        let chain = exception
+            .chain()
+            .map(|e| (e.code, e.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            chain,
+            vec![
+                (ErrorCode::TableAlreadyExists, "outer"),
+                (ErrorCode::MemoryLimitExceeded, "middle"),
+                (ErrorCode::Other(123456), "inner"),
+            ]
+        );
+        // The stream stays in sync after the chain.
+        assert!(matches!(
+            client.receive_packet().await.unwrap(),
+            ServerPacket::Pong
+        ));
+    }
 }
@@ -2,15 +2,59 @@ use crate::{
     block::Block,
     io::ClickhouseWrite,
     protocol::{
-        self, CompressionMethod, ServerHello, DBMS_MIN_PROTOCOL_VERSION_WITH_DISTRIBUTED_DEPTH,
+        self, Compression, ServerHello, DBMS_MIN_PROTOCOL_VERSION_WITH_DISTRIBUTED_DEPTH,
+        DBMS_MIN_PROTOCOL_VERSION_WITH_INITIAL_QUERY_START_TIME,
+        DBMS_MIN_PROTOCOL_VERSION_WITH_PARAMETERS, DBMS_MIN_PROTOCOL_VERSION_WITH_QUOTA_KEY,
         DBMS_MIN_REVISION_WITH_CLIENT_INFO, DBMS_MIN_REVISION_WITH_INTERSERVER_SECRET,
-        DBMS_MIN_REVISION_WITH_OPENTELEMETRY, DBMS_MIN_REVISION_WITH_QUOTA_KEY_IN_CLIENT_INFO,
-        DBMS_MIN_REVISION_WITH_VERSION_PATCH,
+        DBMS_MIN_REVISION_WITH_OPENTELEMETRY, DBMS_MIN_REVISION_WITH_PARALLEL_REPLICAS,
+        DBMS_MIN_REVISION_WITH_QUOTA_KEY_IN_CLIENT_INFO, DBMS_MIN_REVISION_WITH_VERSION_PATCH,
     },
-    Result,
+    settings::QuerySettings,
+    KlickhouseError, Result, TraceContext, Value,
 };
+use indexmap::IndexMap;
 use tokio::io::AsyncWriteExt;
-use uuid::Uuid;
+
+/// Setting flag marking query parameters, which the server stores as custom settings.
+const PARAMETER_FLAG_CUSTOM: u64 = 0x02;
+
+/// How the data blocks sent on a connection are encoded.
+#[derive(Clone, Copy, Debug)]
+pub struct BlockEncoding {
+    pub revision: u64,
+    pub compression: Compression,
+    /// Size from which blocks are compressed on the blocking thread pool.
+    #[cfg_attr(not(any(feature = "compression", feature = "lz4_flex")), allow(unused))]
+    pub blocking_threshold: Option<usize>,
+}
+
+impl BlockEncoding {
+    /// Serializes and compresses `block`, as sent after the table name of a Data packet.
+    /// Runs in the task sending the block, so that the connection task only writes it.
+    pub async fn encode(&self, block: Block) -> Result<Vec<u8>> {
+        match self.compression {
+            Compression::None => {
+                let mut out = vec![];
+                block.write(&mut out, self.revision).await?;
+                Ok(out)
+            }
+            #[cfg(any(feature = "compression", feature = "lz4_flex"))]
+            compression => {
+                crate::compression::compress_block(
+                    block,
+                    self.revision,
+                    compression,
+                    self.blocking_threshold,
+                )
+                .await
+            }
+            #[cfg(not(any(feature = "compression", feature = "lz4_flex")))]
+            _ => Err(KlickhouseError::CompressionError(
+                "attempted to use compression when not compiled with `compression` feature in klickhouse".to_string(),
+            )),
+        }
+    }
+}
 
 pub struct InternalClientOut<W: ClickhouseWrite> {
     writer: W,
@@ -21,6 +65,8 @@ pub struct ClientHello<'a> {
     pub default_database: &'a str,
     pub username: &'a str,
     pub password: &'a str,
+    /// Cluster name and salt of an interserver secret connection.
+    pub interserver: Option<(&'a str, &'a [u8])>,
 }
 
 #[repr(u8)]
@@ -52,7 +98,7 @@ pub struct ClientInfo<'a> {
     // if DBMS_MIN_REVISION_WITH_VERSION_PATCH
     pub client_version_patch: u64,
     // if DBMS_MIN_REVISION_WITH_OPENTELEMETRY
-    pub open_telemetry: Option<OpenTelemetry<'a>>,
+    pub open_telemetry: Option<&'a TraceContext>,
 }
 
 impl ClientInfo<'_> {
@@ -64,6 +110,10 @@ impl ClientInfo<'_> {
         to.write_string(self.initial_user).await?;
         to.write_string(self.initial_query_id).await?;
         to.write_string(self.initial_address).await?;
+        if revision >= DBMS_MIN_PROTOCOL_VERSION_WITH_INITIAL_QUERY_START_TIME {
+            // 0 lets the server use its own start time for initial queries
+            to.write_u64_le(0).await?;
+        }
         to.write_u8(1).await?;
         to.write_string(self.os_user).await?;
         to.write_string(self.client_hostname).await?;
@@ -81,28 +131,24 @@ impl ClientInfo<'_> {
             to.write_var_uint(self.client_version_patch).await?;
         }
         if revision >= DBMS_MIN_REVISION_WITH_OPENTELEMETRY {
-            if let Some(telemetry) = &self.open_telemetry {
+            if let Some(trace_context) = self.open_telemetry {
                 to.write_u8(1u8).await?;
-                to.write_all(&telemetry.trace_id.as_bytes()[..]).await?;
-                to.write_u64(telemetry.span_id).await?;
-                to.write_string(telemetry.tracestate).await?;
-                to.write_u8(telemetry.trace_flags).await?;
+                trace_context.write(to).await?;
             } else {
                 to.write_u8(0u8).await?;
             }
         }
+        if revision >= DBMS_MIN_REVISION_WITH_PARALLEL_REPLICAS {
+            // collaborate_with_initiator, count_participating_replicas, number_of_current_replica
+            to.write_var_uint(0).await?;
+            to.write_var_uint(0).await?;
+            to.write_var_uint(0).await?;
+        }
 
         Ok(())
     }
 }
 
-pub struct OpenTelemetry<'a> {
-    trace_id: Uuid,
-    span_id: u64,
-    tracestate: &'a str,
-    trace_flags: u8,
-}
-
 #[repr(u64)]
 #[derive(Clone, Copy, Debug)]
 #[allow(unused)]
@@ -116,10 +162,13 @@ pub enum QueryProcessingStage {
 pub struct Query<'a> {
     pub id: &'a str,
     pub info: ClientInfo<'a>,
-    // pub settings: (), //TODO
-    //todo: interserver secret
+    pub settings: &'a QuerySettings,
+    /// Typed query parameters (`{name:Type}`), by name, in Clickhouse's text format
+    pub parameters: &'a IndexMap<String, String>,
+    /// Hash proving the knowledge of the cluster secret, on interserver secret connections.
+    pub interserver_hash: Option<&'a [u8]>,
     pub stage: QueryProcessingStage,
-    pub compression: CompressionMethod,
+    pub compression: Compression,
     pub query: &'a str,
     //todo: data
 }
@@ -144,59 +193,52 @@ impl<W: ClickhouseWrite> InternalClientOut<W> {
                 .write(&mut self.writer, self.server_hello.revision_version)
                 .await?;
         }
-        //todo: settings
-        self.writer.write_string("").await?;
+        params
+            .settings
+            .write(&mut self.writer, self.server_hello.revision_version)
+            .await?;
         if self.server_hello.revision_version >= DBMS_MIN_REVISION_WITH_INTERSERVER_SECRET {
-            //todo interserver secret
-            self.writer.write_string("").await?;
+            self.writer
+                .write_string(params.interserver_hash.unwrap_or_default())
+                .await?;
+        } else if params.interserver_hash.is_some() {
+            return Err(KlickhouseError::ProtocolError(format!(
+                "server revision {} is too old for interserver secret authentication",
+                self.server_hello.revision_version
+            )));
         }
         self.writer.write_var_uint(params.stage as u64).await?;
         self.writer
-            .write_u8(if matches!(params.compression, CompressionMethod::None) {
+            .write_u8(if params.compression == Compression::None {
                 0
             } else {
                 1
             })
             .await?;
         self.writer.write_string(params.query).await?;
+        if self.server_hello.revision_version >= DBMS_MIN_PROTOCOL_VERSION_WITH_PARAMETERS {
+            for (name, value) in params.parameters.iter() {
+                self.writer.write_string(name).await?;
+                self.writer.write_var_uint(PARAMETER_FLAG_CUSTOM).await?;
+                // The server reads parameters as custom settings, which are quoted field dumps.
+                self.writer
+                    .write_string(Value::String(value.as_bytes().to_vec()).to_string())
+                    .await?;
+            }
+            self.writer.write_string("").await?;
+        } else if !params.parameters.is_empty() {
+            return Err(KlickhouseError::ProtocolError(format!(
+                "server revision {} is too old to receive query parameters",
+                self.server_hello.revision_version
+            )));
+        }
 
         self.writer.flush().await?;
         Ok(())
     }
 
-    #[cfg(feature = "compression")]
-    async fn compress_data(&mut self, byte: u8, block: Block) -> Result<()> {
-        let (out, decompressed_size) =
-            crate::compression::compress_block(block, self.server_hello.revision_version).await?;
-        let mut new_out = Vec::with_capacity(out.len() + 5);
-        new_out.push(byte);
-        new_out.extend_from_slice(&(out.len() as u32 + 9).to_le_bytes()[..]);
-        new_out.extend_from_slice(&(decompressed_size as u32).to_le_bytes()[..]);
-        new_out.extend(out);
-
-        let hash = cityhash_rs::cityhash_102_128(&new_out[..]);
-        self.writer.write_u64_le((hash >> 64) as u64).await?;
-        self.writer.write_u64_le(hash as u64).await?;
-        // self.writer.write_u8(byte).await?;
-        // self.writer.write_u32_le(out.len() as u32).await?;
-        self.writer.write_all(&new_out[..]).await?;
-        Ok(())
-    }
-
-    #[cfg(not(feature = "compression"))]
-    async fn compress_data(&mut self, _byte: u8, _block: &Block) -> Result<()> {
-        Err(KlickhouseError::CompressionError(
-            "attempted to use compression when not compiled with `compression` feature in klickhouse".to_string(),
-        ))
-    }
-
-    pub async fn send_data(
-        &mut self,
-        block: Block,
-        compression: CompressionMethod,
-        name: &str,
-        scalar: bool,
-    ) -> Result<()> {
+    /// Sends a block encoded with [`BlockEncoding::encode`].
+    pub async fn send_data(&mut self, data: &[u8], name: &str, scalar: bool) -> Result<()> {
         if scalar {
             self.writer
                 .write_var_uint(protocol::ClientPacketId::Scalar as u64)
@@ -207,17 +249,7 @@ impl<W: ClickhouseWrite> InternalClientOut<W> {
                 .await?;
         }
         self.writer.write_string(name).await?;
-        match compression {
-            CompressionMethod::None => {
-                block
-                    .write(&mut self.writer, self.server_hello.revision_version)
-                    .await?;
-            }
-            CompressionMethod::LZ4 => {
-                self.compress_data(CompressionMethod::LZ4.byte(), block)
-                    .await?;
-            }
-        }
+        self.writer.write_all(data).await?;
 
         self.writer.flush().await?;
 
@@ -243,12 +275,132 @@ impl<W: ClickhouseWrite> InternalClientOut<W> {
         self.writer.write_string(params.default_database).await?;
         self.writer.write_string(params.username).await?;
         self.writer.write_string(params.password).await?;
+        if let Some((cluster, salt)) = params.interserver {
+            self.writer.write_string(cluster).await?;
+            self.writer.write_string(salt).await?;
+        }
         self.writer.flush().await?;
         Ok(())
     }
 
-    // pub async fn send_ping(&mut self) -> Result<()> {
-    //     self.writer.write_var_uint(protocol::ClientPacketId::Ping as u64).await?;
-    //     Ok(())
-    // }
+    /// Asks for the challenge to sign with the SSH key of the user, right after the hello.
+    #[cfg(feature = "ssh")]
+    pub async fn send_ssh_challenge_request(&mut self) -> Result<()> {
+        self.writer
+            .write_var_uint(protocol::ClientPacketId::SshChallengeRequest as u64)
+            .await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "ssh")]
+    pub async fn send_ssh_challenge_response(&mut self, signature: &[u8]) -> Result<()> {
+        self.writer
+            .write_var_uint(protocol::ClientPacketId::SshChallengeResponse as u64)
+            .await?;
+        self.writer.write_string(signature).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    pub async fn send_cancel(&mut self) -> Result<()> {
+        self.writer
+            .write_var_uint(protocol::ClientPacketId::Cancel as u64)
+            .await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Sent right after the server hello on newer revisions.
+    pub async fn send_addendum(&mut self, quota_key: &str) -> Result<()> {
+        if self.server_hello.revision_version >= DBMS_MIN_PROTOCOL_VERSION_WITH_QUOTA_KEY {
+            self.writer.write_string(quota_key).await?;
+        }
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    pub async fn send_tables_status_request(&mut self, tables: &[(String, String)]) -> Result<()> {
+        self.writer
+            .write_var_uint(protocol::ClientPacketId::TablesStatusRequest as u64)
+            .await?;
+        self.writer.write_var_uint(tables.len() as u64).await?;
+        for (database, table) in tables {
+            self.writer.write_string(database).await?;
+            self.writer.write_string(table).await?;
+        }
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    pub async fn send_ping(&mut self) -> Result<()> {
+        self.writer
+            .write_var_uint(protocol::ClientPacketId::Ping as u64)
+            .await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::USER_INTERSERVER_MARKER;
+
+    #[tokio::test]
+    async fn test_interserver_hello() {
+        let mut out = InternalClientOut::new(vec![]);
+        out.send_hello(ClientHello {
+            default_database: "",
+            username: USER_INTERSERVER_MARKER,
+            password: "",
+            interserver: Some(("cluster", &[7u8; 32])),
+        })
+        .await
+        .unwrap();
+        let mut expected = vec![7];
+        expected.extend_from_slice(b"cluster");
+        expected.push(32);
+        expected.extend_from_slice(&[7u8; 32]);
+        assert!(out.writer.ends_with(&expected));
+        let marker = [&[20u8][..], USER_INTERSERVER_MARKER.as_bytes(), &[0]].concat();
+        assert!(out
+            .writer
+            .windows(marker.len())
+            .any(|window| window == marker));
+    }
+
+    #[tokio::test]
+    async fn test_interserver_hash_old_revision() {
+        let mut out = InternalClientOut::new(vec![]);
+        out.server_hello.revision_version = DBMS_MIN_REVISION_WITH_INTERSERVER_SECRET - 1;
+        let result = out
+            .send_query(Query {
+                id: "",
+                info: ClientInfo {
+                    kind: QueryKind::SecondaryQuery,
+                    initial_user: "alice",
+                    initial_query_id: "",
+                    initial_address: "0.0.0.0:0",
+                    os_user: "",
+                    client_hostname: "",
+                    client_name: "",
+                    client_version_major: 0,
+                    client_version_minor: 0,
+                    client_tcp_protocol_version: 0,
+                    quota_key: "",
+                    distributed_depth: 0,
+                    client_version_patch: 0,
+                    open_telemetry: None,
+                },
+                settings: &QuerySettings::new(),
+                parameters: &IndexMap::new(),
+                interserver_hash: Some(&[0u8; 32]),
+                stage: QueryProcessingStage::Complete,
+                compression: Compression::None,
+                query: "SELECT 1",
+            })
+            .await;
+        assert!(matches!(result, Err(KlickhouseError::ProtocolError(_))));
+    }
 }
@@ -1,6 +1,15 @@
-use std::future::Future;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
 
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::Sleep,
+};
 
 use crate::{KlickhouseError, Result};
 
@@ -80,6 +89,70 @@ impl<T: AsyncWrite + Unpin + Send + Sync + 'static> ClickhouseWrite for T {
     }
 }
 
+/// Fails writes that stay pending for longer than `timeout`, i.e. when a hung peer stopped reading.
+pub(crate) struct WriteTimeout<W> {
+    inner: W,
+    timeout: Duration,
+    /// Started when a write becomes pending, reset once it completes.
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> WriteTimeout<W> {
+    pub(crate) fn new(inner: W, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            sleep: None,
+        }
+    }
+
+    fn poll_timeout<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.sleep = None;
+            return poll;
+        }
+        let timeout = self.timeout;
+        let sleep = self
+            .sleep
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.sleep = None;
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("write blocked for {timeout:?}"),
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for WriteTimeout<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.poll_timeout(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.poll_timeout(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_shutdown(cx);
+        self.poll_timeout(cx, poll)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -161,6 +234,16 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_write_timeout() {
+        // Nothing reads the other end, writes block once the buffer is full.
+        let (stream, _peer) = tokio::io::duplex(16);
+        let mut writer = WriteTimeout::new(stream, Duration::from_millis(50));
+        writer.write_all(&[0u8; 16]).await.unwrap();
+        let error = writer.write_all(&[0u8; 16]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
     #[tokio::test]
     async fn test_write_string_roundtrip() {
         let test_strings: Vec<&[u8]> = vec![b"", b"a", b"hello world", &[0xFF; 300]];
@@ -5,13 +5,19 @@ pub const VERSION_MAJOR: u64 = 22;
 /// Clickhouse minor version
 pub const VERSION_MINOR: u64 = 9;
 
+mod auth;
+pub use auth::Authentication;
 pub mod block;
 mod client;
-#[cfg(feature = "compression")]
+#[cfg(any(feature = "compression", feature = "lz4_flex"))]
 mod compression;
 mod convert;
+mod dsn;
+pub use dsn::Dsn;
 /// Error generator functions used by `klickhouse_derive`
 mod errors;
+mod exception;
+pub use exception::{ErrorCode, ServerException};
 mod internal_client_in;
 mod internal_client_out;
 mod io;
@@ -21,11 +27,31 @@ mod manager;
 mod migrate;
 #[cfg(feature = "refinery")]
 pub use migrate::*;
+mod profile_events;
+pub use profile_events::{ProfileEvent, ProfileEventKind};
 mod progress;
 pub use progress::*;
 mod protocol;
+pub use protocol::{BlockStreamProfileInfo, Compression, TableStatus, TablesStatusResponse};
 mod query;
+mod query_handle;
+pub use query_handle::*;
 pub mod query_parser;
+mod retry;
+pub use retry::RetryPolicy;
+mod server_log;
+pub use server_log::*;
+mod settings;
+pub use settings::*;
+/// Mock server for tests without a Clickhouse server
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tls")]
+pub use tls::{Pem, TlsOptions};
+mod trace_context;
+pub use trace_context::TraceContext;
 mod types;
 mod values;
 pub use query::*;
@@ -33,7 +59,7 @@ pub use query::*;
 #[cfg(feature = "bb8")]
 pub use bb8;
 #[cfg(feature = "bb8")]
-pub use manager::ConnectionManager;
+pub use manager::{ConnectionManager, LoadBalancing};
 
 pub use uuid::Uuid;
 
@@ -2,7 +2,12 @@ use std::time::Duration;
 
 use log::{error, warn};
 
-use crate::{Client, KlickhouseError};
+use crate::{Client, ErrorCode, KlickhouseError};
+
+/// Whether creating the lock table failed because it exists, i.e. the lock is held.
+fn is_locked(error: &KlickhouseError) -> bool {
+    error.server_code() == Some(ErrorCode::TableAlreadyExists)
+}
 
 /// A hack implementation of a global lock for things like migrations
 #[derive(Clone)]
@@ -45,14 +50,8 @@ impl ClickhouseLock {
 
         match self.client.execute(&query).await {
             Ok(()) => (),
-            Err(e) => {
-                let error = e.to_string();
-                if error.contains("already exists") {
-                    return Ok(None);
-                } else {
-                    return Err(e);
-                }
-            }
+            Err(e) if is_locked(&e) => return Ok(None),
+            Err(e) => return Err(e),
         }
 
         Ok(Some(ClickhouseLockHandle { lock: Some(self) }))
@@ -68,14 +67,8 @@ impl ClickhouseLock {
         loop {
             match self.client.execute(&query).await {
                 Ok(()) => break,
-                Err(e) => {
-                    let error = e.to_string();
-                    if error.contains("already exists") {
-                        tokio::time::sleep(Duration::from_millis(100)).await;
-                    } else {
-                        return Err(e);
-                    }
-                }
+                Err(e) if is_locked(&e) => tokio::time::sleep(Duration::from_millis(100)).await,
+                Err(e) => return Err(e),
             }
         }
 
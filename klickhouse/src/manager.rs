@@ -1,31 +1,224 @@
-use std::net::SocketAddr;
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 use tokio::net::ToSocketAddrs;
 
-use crate::{convert::UnitValue, Client, ClientOptions, KlickhouseError};
+use crate::{client::host_name, Client, ClientOptions, KlickhouseError, RetryPolicy};
 
+/// Default interval after which host names are resolved again.
+const DEFAULT_DNS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Default time during which a host that failed to connect is only tried after the healthy ones.
+const DEFAULT_EJECTION_DURATION: Duration = Duration::from_secs(30);
+
+/// Order in which a multi-host [`ConnectionManager`] tries its hosts for each new connection,
+/// like Clickhouse's `load_balancing` setting.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum LoadBalancing {
+    /// Each connection starts with the host after the one the previous connection started with.
+    #[default]
+    RoundRobin,
+    /// Hosts are tried in a random order.
+    Random,
+    /// Hosts are tried in the order they were given, so connections go to the first healthy host.
+    FirstHealthy,
+    /// Hosts whose name differs from the given host name in the fewest characters are tried first,
+    /// i.e. the local host name to prefer replicas of the same rack or datacenter.
+    NearestHostname(String),
+}
+
+/// Number of characters that differ between two host names, the distance used by Clickhouse's `nearest_hostname`.
+fn hostname_distance(a: &str, b: &str) -> usize {
+    let mismatches = a.chars().zip(b.chars()).filter(|(a, b)| a != b).count();
+    mismatches + a.chars().count().abs_diff(b.chars().count())
+}
+
+struct Host {
+    /// `host:port` to resolve, `None` for addresses resolved once by [`ConnectionManager::new`].
+    address: Option<String>,
+    resolved: Vec<SocketAddr>,
+    resolved_at: Option<Instant>,
+    /// Set when connecting failed, the host is tried after the healthy ones until then.
+    ejected_until: Option<Instant>,
+}
+
+/// A [`bb8`] connection manager, opening [`Client`]s to one or many Clickhouse hosts.
+///
+/// With [`ConnectionManager::with_hosts`], each new connection goes to a host chosen by the [`LoadBalancing`]
+/// policy. Host names are resolved again periodically, and hosts that fail to connect are ejected for a while:
+/// they are only tried once every healthy host failed.
 #[derive(Clone)]
 pub struct ConnectionManager {
-    destination: Vec<SocketAddr>,
+    hosts: Arc<Mutex<Vec<Host>>>,
     options: ClientOptions,
     prequel: Option<String>,
+    load_balancing: LoadBalancing,
+    dns_refresh_interval: Option<Duration>,
+    ejection_duration: Duration,
+    /// Number of connections opened, to rotate hosts with [`LoadBalancing::RoundRobin`].
+    connections: Arc<AtomicUsize>,
 }
 
 impl ConnectionManager {
+    /// Connects to a single destination, resolved once. Every connection tries all of its addresses in order.
     pub async fn new<A: ToSocketAddrs>(
         destination: A,
         options: ClientOptions,
     ) -> std::io::Result<Self> {
-        Ok(Self {
-            destination: tokio::net::lookup_host(destination).await?.collect(),
+        let host = Host {
+            address: None,
+            resolved: tokio::net::lookup_host(destination).await?.collect(),
+            resolved_at: None,
+            ejected_until: None,
+        };
+        Ok(Self::from_hosts(vec![host], options))
+    }
+
+    /// Connects to several `host:port` addresses, i.e. the replicas of a cluster. Names are resolved on first use.
+    pub fn with_hosts(
+        hosts: impl IntoIterator<Item = impl Into<String>>,
+        options: ClientOptions,
+    ) -> Self {
+        let hosts = hosts
+            .into_iter()
+            .map(|address| Host {
+                address: Some(address.into()),
+                resolved: vec![],
+                resolved_at: None,
+                ejected_until: None,
+            })
+            .collect();
+        Self::from_hosts(hosts, options)
+    }
+
+    fn from_hosts(hosts: Vec<Host>, options: ClientOptions) -> Self {
+        Self {
+            hosts: Arc::new(Mutex::new(hosts)),
             options,
             prequel: None,
-        })
+            load_balancing: LoadBalancing::default(),
+            dns_refresh_interval: Some(DEFAULT_DNS_REFRESH_INTERVAL),
+            ejection_duration: DEFAULT_EJECTION_DURATION,
+            connections: Arc::new(AtomicUsize::new(0)),
+        }
     }
 
     pub fn with_prequel(mut self, prequel: impl Into<String>) -> Self {
         self.prequel = Some(prequel.into());
         self
     }
+
+    /// Sets the order in which hosts are tried. Defaults to [`LoadBalancing::RoundRobin`].
+    pub fn with_load_balancing(mut self, load_balancing: LoadBalancing) -> Self {
+        self.load_balancing = load_balancing;
+        self
+    }
+
+    /// Sets how often host names are resolved again. `None` resolves them only once. Defaults to 60 seconds.
+    pub fn with_dns_refresh_interval(mut self, interval: Option<Duration>) -> Self {
+        self.dns_refresh_interval = interval;
+        self
+    }
+
+    /// Sets the retry policy of the connections, see [`ClientOptions::retry`].
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.options.retry = Some(policy);
+        self
+    }
+
+    /// Sets how long a host that failed to connect is ejected. Defaults to 30 seconds.
+    pub fn with_ejection_duration(mut self, duration: Duration) -> Self {
+        self.ejection_duration = duration;
+        self
+    }
+
+    /// Returns the indices of the hosts in the order they must be tried: healthy hosts ordered by the load
+    /// balancing policy, then ejected hosts by end of ejection.
+    fn host_order(&self, hosts: &[Host], now: Instant) -> Vec<usize> {
+        let (mut healthy, mut ejected): (Vec<usize>, Vec<usize>) = (0..hosts.len())
+            .partition(|i| hosts[*i].ejected_until.is_none_or(|until| until <= now));
+        match &self.load_balancing {
+            LoadBalancing::RoundRobin => {
+                if !healthy.is_empty() {
+                    let start = self.connections.fetch_add(1, Ordering::Relaxed) % healthy.len();
+                    healthy.rotate_left(start);
+                }
+            }
+            LoadBalancing::Random => {
+                let random = RandomState::new();
+                healthy.sort_by_cached_key(|i| random.hash_one(i));
+            }
+            LoadBalancing::FirstHealthy => (),
+            LoadBalancing::NearestHostname(local) => {
+                healthy.sort_by_cached_key(|i| match &hosts[*i].address {
+                    Some(address) => hostname_distance(local, host_name(address)),
+                    None => usize::MAX,
+                });
+            }
+        }
+        ejected.sort_by_key(|i| hosts[*i].ejected_until);
+        healthy.extend(ejected);
+        healthy
+    }
+
+    /// Returns the addresses of a host, resolving its name again if they are missing or stale.
+    async fn resolve(&self, index: usize) -> std::io::Result<Vec<SocketAddr>> {
+        let (address, resolved) = {
+            let hosts = self.hosts.lock().unwrap();
+            let host = &hosts[index];
+            let stale = match (host.resolved_at, self.dns_refresh_interval) {
+                (None, _) => true,
+                (Some(at), Some(interval)) => at.elapsed() >= interval,
+                (Some(_), None) => false,
+            };
+            match &host.address {
+                Some(address) if stale || host.resolved.is_empty() => {
+                    (address.clone(), host.resolved.clone())
+                }
+                _ => return Ok(host.resolved.clone()),
+            }
+        };
+        match tokio::net::lookup_host(address.clone()).await {
+            Ok(addresses) => {
+                let addresses: Vec<SocketAddr> = addresses.collect();
+                let mut hosts = self.hosts.lock().unwrap();
+                hosts[index].resolved = addresses.clone();
+                hosts[index].resolved_at = Some(Instant::now());
+                Ok(addresses)
+            }
+            // Keep using the previous addresses while the resolver is unavailable.
+            Err(e) if !resolved.is_empty() => {
+                log::warn!("failed to resolve {address}, using previous addresses: {e}");
+                Ok(resolved)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn connect_host(&self, index: usize) -> Result<Client, KlickhouseError> {
+        let addresses = self.resolve(index).await?;
+        #[allow(unused_mut)]
+        let mut options = self.options.clone();
+        // The certificate of a host is checked against its name rather than its IP address.
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut options.tls {
+            if tls.server_name.is_none() {
+                let hosts = self.hosts.lock().unwrap();
+                tls.server_name = hosts[index]
+                    .address
+                    .as_deref()
+                    .map(|address| host_name(address).to_string());
+            }
+        }
+        Client::connect(&addresses[..], options).await
+    }
 }
 
 impl bb8::ManageConnection for ConnectionManager {
@@ -33,19 +226,118 @@ impl bb8::ManageConnection for ConnectionManager {
     type Error = KlickhouseError;
 
     async fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        let client = Client::connect(&self.destination[..], self.options.clone()).await?;
-        if let Some(prequel) = &self.prequel {
-            client.execute(prequel).await?;
+        let order = {
+            let hosts = self.hosts.lock().unwrap();
+            self.host_order(&hosts, Instant::now())
+        };
+        let mut last_error = None;
+        for index in order {
+            match self.connect_host(index).await {
+                Ok(client) => {
+                    self.hosts.lock().unwrap()[index].ejected_until = None;
+                    if let Some(prequel) = &self.prequel {
+                        client.execute(prequel).await?;
+                    }
+                    return Ok(client);
+                }
+                Err(e) => {
+                    let mut hosts = self.hosts.lock().unwrap();
+                    let host = &mut hosts[index];
+                    log::warn!(
+                        "failed to connect to clickhouse host {}, ejecting it for {:?}: {e}",
+                        host.address.as_deref().unwrap_or("(resolved)"),
+                        self.ejection_duration
+                    );
+                    host.ejected_until = Some(Instant::now() + self.ejection_duration);
+                    last_error = Some(e);
+                }
+            }
         }
-        Ok(client)
+        Err(last_error.unwrap_or_else(|| {
+            KlickhouseError::ConnectionError("no clickhouse host configured".to_string())
+        }))
     }
 
     async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
-        conn.query_one::<UnitValue<String>>("select '';").await?;
-        Ok(())
+        conn.ping(self.options.ping_timeout).await
     }
 
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
         conn.is_closed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager(load_balancing: LoadBalancing) -> ConnectionManager {
+        ConnectionManager::with_hosts(
+            ["ch-dc1-1:9000", "ch-dc2-1:9000", "ch-dc1-2:9000"],
+            ClientOptions::default(),
+        )
+        .with_load_balancing(load_balancing)
+    }
+
+    fn order(manager: &ConnectionManager) -> Vec<usize> {
+        let hosts = manager.hosts.lock().unwrap();
+        manager.host_order(&hosts, Instant::now())
+    }
+
+    #[test]
+    fn test_hostname_distance() {
+        assert_eq!(hostname_distance("ch-dc1-1", "ch-dc1-1"), 0);
+        assert_eq!(hostname_distance("ch-dc1-1", "ch-dc1-2"), 1);
+        assert_eq!(hostname_distance("ch-dc1-1", "ch-dc2-10"), 2);
+        assert_eq!(host_name("ch-dc1-1:9000"), "ch-dc1-1");
+        assert_eq!(host_name("[::1]:9000"), "::1");
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let manager = manager(LoadBalancing::RoundRobin);
+        assert_eq!(order(&manager), vec![0, 1, 2]);
+        assert_eq!(order(&manager), vec![1, 2, 0]);
+        assert_eq!(order(&manager.clone()), vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn test_nearest_hostname() {
+        let nearest = manager(LoadBalancing::NearestHostname("ch-dc1-3".to_string()));
+        assert_eq!(order(&nearest), vec![0, 2, 1]);
+    }
+
+    #[test]
+    fn test_random() {
+        let mut sorted = order(&manager(LoadBalancing::Random));
+        sorted.sort();
+        assert_eq!(sorted, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_ejected_hosts_last() {
+        let manager = manager(LoadBalancing::FirstHealthy);
+        {
+            let mut hosts = manager.hosts.lock().unwrap();
+            hosts[0].ejected_until = Some(Instant::now() + Duration::from_secs(60));
+            hosts[1].ejected_until = Some(Instant::now() + Duration::from_secs(30));
+            // Expired ejection.
+            hosts[2].ejected_until = Some(Instant::now() - Duration::from_secs(1));
+        }
+        assert_eq!(order(&manager), vec![2, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_host_is_ejected() {
+        use bb8::ManageConnection;
+
+        // Nothing listens on port 1, the connection is refused.
+        let manager = ConnectionManager::with_hosts(["127.0.0.1:1"], ClientOptions::default());
+        assert!(manager.connect().await.is_err());
+        assert!(manager.hosts.lock().unwrap()[0].ejected_until.is_some());
+        assert_eq!(
+            manager.hosts.lock().unwrap()[0].resolved,
+            vec!["127.0.0.1:1".parse::<SocketAddr>().unwrap()]
+        );
+    }
+}
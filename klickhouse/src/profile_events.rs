@@ -0,0 +1,112 @@
+use chrono::{DateTime, Utc};
+use indexmap::IndexMap;
+
+use crate::{block::Block, convert::Row, KlickhouseError, RawRow, Result};
+
+/// Thread id of the rows summing the counters of all threads of the query on a host.
+const THREAD_GROUP_ID: u64 = 0;
+
+/// How the value of a [`ProfileEvent`] must be interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProfileEventKind {
+    /// The value is the increase of the counter since the previous ProfileEvents packet.
+    Increment,
+    /// The value is the current value of the counter, i.e. `MemoryTrackerUsage`.
+    Gauge,
+}
+
+/// A single counter from a ProfileEvents packet, as in `system.events`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileEvent {
+    pub host_name: String,
+    pub current_time: DateTime<Utc>,
+    /// Id of the server thread the counter was measured on, or 0 for the sum over all threads of the query.
+    pub thread_id: u64,
+    pub kind: ProfileEventKind,
+    /// Name of the counter, i.e. `SelectedMarks`, `ReadCompressedBytes`.
+    pub name: String,
+    pub value: i64,
+}
+
+impl ProfileEvent {
+    /// Parses the rows of a ProfileEvents packet block.
+    pub(crate) fn from_block(mut block: Block) -> Result<Vec<Self>> {
+        block
+            .take_iter_rows()
+            .map(|row| Self::from_row(RawRow::deserialize_row(row)?))
+            .collect()
+    }
+
+    fn from_row(mut row: RawRow) -> Result<Self> {
+        let kind = match row.try_get::<_, i8>("type")? {
+            1 => ProfileEventKind::Increment,
+            2 => ProfileEventKind::Gauge,
+            other => {
+                return Err(KlickhouseError::DeserializeError(format!(
+                    "invalid profile event type: {other}"
+                )))
+            }
+        };
+        Ok(Self {
+            host_name: row.try_get("host_name")?,
+            current_time: row.try_get("current_time")?,
+            thread_id: row.try_get("thread_id")?,
+            kind,
+            name: row.try_get("name")?,
+            value: row.try_get("value")?,
+        })
+    }
+}
+
+/// Adds the query-level counters of `events` to `totals`. Increments are summed, gauges keep their latest value.
+pub(crate) fn accumulate_profile_events(
+    totals: &mut IndexMap<String, i64>,
+    events: &[ProfileEvent],
+) {
+    for event in events.iter().filter(|e| e.thread_id == THREAD_GROUP_ID) {
+        let total = totals.entry(event.name.clone()).or_default();
+        match event.kind {
+            ProfileEventKind::Increment => *total += event.value,
+            ProfileEventKind::Gauge => *total = event.value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(thread_id: u64, kind: ProfileEventKind, name: &str, value: i64) -> ProfileEvent {
+        ProfileEvent {
+            host_name: "host".to_string(),
+            current_time: DateTime::default(),
+            thread_id,
+            kind,
+            name: name.to_string(),
+            value,
+        }
+    }
+
+    #[test]
+    fn test_accumulate_profile_events() {
+        let mut totals = IndexMap::new();
+        accumulate_profile_events(
+            &mut totals,
+            &[
+                event(0, ProfileEventKind::Increment, "SelectedMarks", 3),
+                event(0, ProfileEventKind::Gauge, "MemoryTrackerUsage", 100),
+                // Per-thread rows are already included in the thread group rows.
+                event(12, ProfileEventKind::Increment, "SelectedMarks", 3),
+            ],
+        );
+        accumulate_profile_events(
+            &mut totals,
+            &[
+                event(0, ProfileEventKind::Increment, "SelectedMarks", 2),
+                event(0, ProfileEventKind::Gauge, "MemoryTrackerUsage", 50),
+            ],
+        );
+        assert_eq!(totals.get("SelectedMarks"), Some(&5));
+        assert_eq!(totals.get("MemoryTrackerUsage"), Some(&50));
+    }
+}
@@ -7,8 +7,12 @@ pub struct Progress {
     pub read_rows: u64,
     pub read_bytes: u64,
     pub new_total_rows_to_read: u64,
+    /// Only sent by servers since protocol revision 54463.
+    pub new_total_bytes_to_read: Option<u64>,
     pub new_written_rows: Option<u64>,
     pub new_written_bytes: Option<u64>,
+    /// Server-side execution time in nanoseconds. Only sent by servers since protocol revision 54460.
+    pub elapsed_ns: Option<u64>,
 }
 impl std::ops::Add for Progress {
     type Output = Progress;
@@ -24,8 +28,13 @@ impl std::ops::Add for Progress {
             read_rows: self.read_rows + rhs.read_rows,
             read_bytes: self.read_bytes + rhs.read_bytes,
             new_total_rows_to_read: self.new_total_rows_to_read + rhs.new_total_rows_to_read,
+            new_total_bytes_to_read: sum_opt(
+                self.new_total_bytes_to_read,
+                rhs.new_total_bytes_to_read,
+            ),
             new_written_rows: sum_opt(self.new_written_rows, rhs.new_written_rows),
             new_written_bytes: sum_opt(self.new_written_bytes, rhs.new_written_bytes),
+            elapsed_ns: sum_opt(self.elapsed_ns, rhs.elapsed_ns),
         }
     }
 }
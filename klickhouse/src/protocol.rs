@@ -1,12 +1,14 @@
 use indexmap::IndexMap;
 use uuid::Uuid;
 
-use crate::{block::Block, progress::Progress, KlickhouseError, Result};
+use crate::{
+    block::Block, progress::Progress, KlickhouseError, QuerySettings, Result, ServerException,
+};
 
 pub const DBMS_MIN_REVISION_WITH_CLIENT_INFO: u64 = 54032;
 pub const DBMS_MIN_REVISION_WITH_SERVER_TIMEZONE: u64 = 54058;
 pub const DBMS_MIN_REVISION_WITH_QUOTA_KEY_IN_CLIENT_INFO: u64 = 54060;
-// pub const DBMS_MIN_REVISION_WITH_TABLES_STATUS: u64 = 54226;
+pub const DBMS_MIN_REVISION_WITH_TABLES_STATUS: u64 = 54226;
 // pub const DBMS_MIN_REVISION_WITH_TIME_ZONE_PARAMETER_IN_DATETIME_DATA_TYPE: u64 = 54337;
 pub const DBMS_MIN_REVISION_WITH_SERVER_DISPLAY_NAME: u64 = 54372;
 pub const DBMS_MIN_REVISION_WITH_VERSION_PATCH: u64 = 54401;
@@ -16,14 +18,30 @@ pub const DBMS_MIN_REVISION_WITH_VERSION_PATCH: u64 = 54401;
 // pub const DBMS_MIN_REVISION_WITH_COLUMN_DEFAULTS_METADATA: u64 = 54410;
 // pub const DBMS_MIN_REVISION_WITH_LOW_CARDINALITY_TYPE: u64 = 54405;
 pub const DBMS_MIN_REVISION_WITH_CLIENT_WRITE_INFO: u64 = 54420;
-// pub const DBMS_MIN_REVISION_WITH_SETTINGS_SERIALIZED_AS_STRINGS: u64 = 54429;
+pub const DBMS_MIN_REVISION_WITH_SETTINGS_SERIALIZED_AS_STRINGS: u64 = 54429;
 pub const DBMS_MIN_REVISION_WITH_OPENTELEMETRY: u64 = 54442;
 pub const DBMS_MIN_REVISION_WITH_INTERSERVER_SECRET: u64 = 54441;
 // pub const DBMS_MIN_REVISION_WITH_X_FORWARDED_FOR_IN_CLIENT_INFO: u64 = 54443;
 // pub const DBMS_MIN_REVISION_WITH_REFERER_IN_CLIENT_INFO: u64 = 54447;
 pub const DBMS_MIN_PROTOCOL_VERSION_WITH_DISTRIBUTED_DEPTH: u64 = 54448;
+pub const DBMS_MIN_PROTOCOL_VERSION_WITH_INITIAL_QUERY_START_TIME: u64 = 54449;
+// pub const DBMS_MIN_PROTOCOL_VERSION_WITH_INCREMENTAL_PROFILE_EVENTS: u64 = 54451;
+pub const DBMS_MIN_REVISION_WITH_PARALLEL_REPLICAS: u64 = 54453;
+pub const DBMS_MIN_REVISION_WITH_CUSTOM_SERIALIZATION: u64 = 54454;
+// pub const DBMS_MIN_PROTOCOL_VERSION_WITH_PROFILE_EVENTS_IN_INSERT: u64 = 54456;
+pub const DBMS_MIN_PROTOCOL_VERSION_WITH_ADDENDUM: u64 = 54458;
+pub const DBMS_MIN_PROTOCOL_VERSION_WITH_QUOTA_KEY: u64 = 54458;
+pub const DBMS_MIN_PROTOCOL_VERSION_WITH_PARAMETERS: u64 = 54459;
+pub const DBMS_MIN_PROTOCOL_VERSION_WITH_SERVER_QUERY_TIME_IN_PROGRESS: u64 = 54460;
+pub const DBMS_MIN_PROTOCOL_VERSION_WITH_PASSWORD_COMPLEXITY_RULES: u64 = 54461;
+pub const DBMS_MIN_REVISION_WITH_INTERSERVER_SECRET_V2: u64 = 54462;
+pub const DBMS_MIN_PROTOCOL_VERSION_WITH_TOTAL_BYTES_IN_PROGRESS: u64 = 54463;
+// pub const DBMS_MIN_PROTOCOL_VERSION_WITH_TIMEZONE_UPDATES: u64 = 54464;
+// Allows the server to send sparse columns, expanded by `Block::read`.
+// pub const DBMS_MIN_REVISION_WITH_SPARSE_SERIALIZATION: u64 = 54465;
+// pub const DBMS_MIN_PROTOCOL_VERSION_WITH_SSH_AUTHENTICATION: u64 = 54466;
 
-pub const DBMS_TCP_PROTOCOL_VERSION: u64 = 54448;
+pub const DBMS_TCP_PROTOCOL_VERSION: u64 = 54466;
 
 pub const MAX_STRING_SIZE: usize = 1 << 30;
 
@@ -41,6 +59,36 @@ pub enum ClientPacketId {
     Scalar,
     IgnoredPartUUIDs,
     ReadTaskResponse,
+    MergeTreeReadTaskResponse,
+    SshChallengeRequest,
+    SshChallengeResponse,
+}
+
+#[cfg_attr(not(feature = "testing"), allow(unused))]
+impl ClientPacketId {
+    pub fn from_u64(i: u64) -> Result<Self> {
+        Ok(match i {
+            0 => ClientPacketId::Hello,
+            1 => ClientPacketId::Query,
+            2 => ClientPacketId::Data,
+            3 => ClientPacketId::Cancel,
+            4 => ClientPacketId::Ping,
+            5 => ClientPacketId::TablesStatusRequest,
+            6 => ClientPacketId::KeepAlive,
+            7 => ClientPacketId::Scalar,
+            8 => ClientPacketId::IgnoredPartUUIDs,
+            9 => ClientPacketId::ReadTaskResponse,
+            10 => ClientPacketId::MergeTreeReadTaskResponse,
+            11 => ClientPacketId::SshChallengeRequest,
+            12 => ClientPacketId::SshChallengeResponse,
+            x => {
+                return Err(KlickhouseError::ProtocolError(format!(
+                    "invalid packet id from client: {}",
+                    x
+                )))
+            }
+        })
+    }
 }
 
 #[repr(u64)]
@@ -60,6 +108,9 @@ pub enum ServerPacketId {
     TableColumns,
     PartUUIDs,
     ReadTaskRequest,
+    ProfileEvents,
+    TimezoneUpdate = 17,
+    SshChallenge = 18,
 }
 
 impl ServerPacketId {
@@ -79,6 +130,9 @@ impl ServerPacketId {
             11 => ServerPacketId::TableColumns,
             12 => ServerPacketId::PartUUIDs,
             13 => ServerPacketId::ReadTaskRequest,
+            14 => ServerPacketId::ProfileEvents,
+            17 => ServerPacketId::TimezoneUpdate,
+            18 => ServerPacketId::SshChallenge,
             x => {
                 return Err(KlickhouseError::ProtocolError(format!(
                     "invalid packet id from server: {}",
@@ -95,10 +149,15 @@ pub struct ServerHello {
     pub server_name: String,
     pub major_version: u64,
     pub minor_version: u64,
+    /// Negotiated protocol revision, the lowest of the server revision and [`DBMS_TCP_PROTOCOL_VERSION`].
     pub revision_version: u64,
     pub timezone: Option<String>,
     pub display_name: Option<String>,
     pub patch_version: u64,
+    /// Password rules of the server, as `(regexp, message)`, for clients creating users.
+    pub password_complexity_rules: Vec<(String, String)>,
+    /// Nonce used to sign queries with the interserver secret.
+    pub nonce: Option<u64>,
 }
 
 #[derive(Debug, Clone)]
@@ -108,34 +167,17 @@ pub struct ServerData {
     pub block: Block,
 }
 
-#[derive(Debug, Clone)]
-#[allow(dead_code)]
-pub struct ServerException {
-    pub code: i32,
-    pub name: String,
-    pub message: String,
-    pub stack_trace: String,
-    pub has_nested: bool,
-}
-
-impl ServerException {
-    pub fn emit(&self) -> KlickhouseError {
-        KlickhouseError::ServerException {
-            code: self.code,
-            name: self.name.clone(),
-            message: self.message.clone(),
-            stack_trace: self.stack_trace.clone(),
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
-#[allow(dead_code)]
+/// Summary of a query result, sent by the server once all data blocks are sent.
+///
+/// See https://clickhouse.com/codebrowser/ClickHouse/src/QueryPipeline/ProfileInfo.h.html
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct BlockStreamProfileInfo {
     pub rows: u64,
     pub blocks: u64,
     pub bytes: u64,
+    /// Whether a `LIMIT` was applied to the result
     pub applied_limit: bool,
+    /// Number of rows the result would have had without `LIMIT`, if `calculated_rows_before_limit` is set
     pub rows_before_limit: u64,
     pub calculated_rows_before_limit: bool,
 }
@@ -147,18 +189,26 @@ pub struct TableColumns {
     pub description: String,
 }
 
-#[derive(Debug, Clone)]
-#[allow(dead_code)]
+/// Replication status of a table, returned by [`crate::Client::tables_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TableStatus {
     pub is_replicated: bool,
+    /// Replication delay of the table in seconds, always 0 for non-replicated tables.
     pub absolute_delay: u32,
 }
 
-#[derive(Debug, Clone)]
+/// Status of the requested tables, by database and table name. Tables that don't exist on the server are omitted.
+#[derive(Debug, Clone, Default)]
 pub struct TablesStatusResponse {
     pub database_tables: IndexMap<String, IndexMap<String, TableStatus>>,
 }
 
+impl TablesStatusResponse {
+    pub fn get(&self, database: &str, table: &str) -> Option<&TableStatus> {
+        self.database_tables.get(database)?.get(table)
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub enum ServerPacket {
@@ -176,22 +226,94 @@ pub enum ServerPacket {
     TableColumns(TableColumns),
     PartUUIDs(Vec<Uuid>),
     ReadTaskRequest,
+    ProfileEvents(ServerData),
+    /// The session timezone changed, i.e. with `SET session_timezone`.
+    TimezoneUpdate(String),
+    /// Random bytes to sign with the SSH key of the user, during the handshake.
+    SshChallenge(Vec<u8>),
 }
 
-#[derive(Clone, Copy, Debug, Default)]
-#[allow(unused)]
+/// Codec of a compressed frame, identified by its method byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum CompressionMethod {
-    #[cfg_attr(not(feature = "compression"), default)]
     None,
-    #[cfg_attr(feature = "compression", default)]
     LZ4,
+    ZSTD,
 }
 
+#[cfg_attr(not(any(feature = "compression", feature = "lz4_flex")), allow(unused))]
 impl CompressionMethod {
     pub fn byte(&self) -> u8 {
         match self {
             CompressionMethod::None => 0x02,
             CompressionMethod::LZ4 => 0x82,
+            CompressionMethod::ZSTD => 0x90,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x02 => Some(CompressionMethod::None),
+            0x82 => Some(CompressionMethod::LZ4),
+            0x90 => Some(CompressionMethod::ZSTD),
+            _ => None,
+        }
+    }
+}
+
+/// Compression of the blocks exchanged with the server, see [`crate::ClientOptions::compression`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[cfg_attr(not(any(feature = "compression", feature = "lz4_flex")), default)]
+    None,
+    /// LZ4, the default of Clickhouse. Requires the `compression` or `lz4_flex` feature.
+    #[cfg_attr(any(feature = "compression", feature = "lz4_flex"), default)]
+    Lz4,
+    /// LZ4 with the high compression algorithm at the given level (1 to 12), for the blocks sent to the server.
+    /// Blocks received from the server are compressed with LZ4. Requires the `compression` feature, as `lz4_flex`
+    /// has no high compression mode.
+    Lz4Hc(i32),
+    /// ZSTD at the given level (1 to 22), in both directions. Requires the `zstd` feature.
+    Zstd(i32),
+}
+
+impl Compression {
+    /// Codec of the frames sent to the server.
+    pub(crate) fn method(&self) -> CompressionMethod {
+        match self {
+            Compression::None => CompressionMethod::None,
+            Compression::Lz4 | Compression::Lz4Hc(_) => CompressionMethod::LZ4,
+            Compression::Zstd(_) => CompressionMethod::ZSTD,
+        }
+    }
+
+    /// Settings making the server compress its blocks with the same codec.
+    pub(crate) fn settings(&self) -> QuerySettings {
+        match self {
+            Compression::Zstd(level) => QuerySettings::new()
+                .with("network_compression_method", "ZSTD")
+                .with("network_zstd_compression_level", *level),
+            _ => QuerySettings::new(),
+        }
+    }
+
+    /// Fails if the codec was not compiled in.
+    pub(crate) fn check_supported(&self) -> Result<()> {
+        let (supported, feature) = match self {
+            Compression::None => (true, ""),
+            Compression::Lz4 => (
+                cfg!(any(feature = "compression", feature = "lz4_flex")),
+                "compression` or `lz4_flex",
+            ),
+            Compression::Lz4Hc(_) => (cfg!(feature = "compression"), "compression"),
+            Compression::Zstd(_) => (cfg!(feature = "zstd"), "zstd"),
+        };
+        if supported {
+            return Ok(());
         }
+        Err(KlickhouseError::CompressionError(format!(
+            "{self:?} compression requires the `{feature}` feature of klickhouse"
+        )))
     }
 }
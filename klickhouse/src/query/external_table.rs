@@ -0,0 +1,123 @@
+use indexmap::IndexMap;
+
+use crate::{block::Block, convert::Row, KlickhouseError, Result, Type};
+
+/// A temporary table sent along with a query, and usable in it by name, i.e. `WHERE id IN _ids`.
+///
+/// Attach it with [`crate::ParsedQuery::with_external_table`] or [`crate::QueryBuilder::external_table`].
+#[derive(Debug, Clone)]
+pub struct ExternalTable {
+    pub(crate) name: String,
+    pub(crate) block: Block,
+}
+
+impl ExternalTable {
+    /// Wraps a block, whose `column_types` define the structure of the table. The block needs at least one column.
+    pub fn new(name: impl Into<String>, block: Block) -> Result<Self> {
+        let name = name.into();
+        if name.is_empty() {
+            return Err(KlickhouseError::SerializeError(
+                "external table name can't be empty".to_string(),
+            ));
+        }
+        if block.column_types.is_empty() {
+            return Err(KlickhouseError::SerializeError(format!(
+                "external table {name} has no columns"
+            )));
+        }
+        for (column, values) in block.column_data.iter() {
+            if !block.column_types.contains_key(column) {
+                return Err(KlickhouseError::SerializeError(format!(
+                    "missing type for column {column} of external table {name}"
+                )));
+            }
+            if values.len() as u64 != block.rows {
+                return Err(KlickhouseError::SerializeError(format!(
+                    "column {column} of external table {name} has {} rows, expected {}",
+                    values.len(),
+                    block.rows
+                )));
+            }
+        }
+        Ok(Self { name, block })
+    }
+
+    /// Serializes `rows` into a table with the given structure. Every column of `structure` must be set by the rows.
+    pub fn from_rows<T: Row>(
+        name: impl Into<String>,
+        structure: impl IntoIterator<Item = (impl Into<String>, Type)>,
+        rows: Vec<T>,
+    ) -> Result<Self> {
+        let name = name.into();
+        let column_types = structure
+            .into_iter()
+            .map(|(column, type_)| (column.into(), type_))
+            .collect::<IndexMap<String, Type>>();
+        let mut block = Block::from_rows(rows, column_types)?;
+        for column in block.column_types.keys() {
+            if block.rows == 0 {
+                block.column_data.insert(column.clone(), vec![]);
+            } else if !block.column_data.contains_key(column) {
+                return Err(KlickhouseError::SerializeError(format!(
+                    "missing column {column} in rows of external table {name}"
+                )));
+            }
+        }
+        Self::new(name, block)
+    }
+
+    /// Name of the table in the query.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn block(&self) -> &Block {
+        &self.block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RawRow;
+
+    fn row(id: u64) -> RawRow {
+        let mut row = RawRow::default();
+        row.set("id", id);
+        row
+    }
+
+    #[test]
+    fn test_from_rows() {
+        let table =
+            ExternalTable::from_rows("_ids", [("id", Type::UInt64)], vec![row(1), row(2)]).unwrap();
+        assert_eq!(table.name(), "_ids");
+        assert_eq!(table.block().rows, 2);
+        assert_eq!(table.block().column_data.get("id").unwrap().len(), 2);
+
+        let table =
+            ExternalTable::from_rows::<RawRow>("_ids", [("id", Type::UInt64)], vec![]).unwrap();
+        assert_eq!(table.block().rows, 0);
+        assert_eq!(table.block().column_data.get("id"), Some(&vec![]));
+    }
+
+    #[test]
+    fn test_from_rows_missing_column() {
+        let structure = [("id", Type::UInt64), ("name", Type::String)];
+        assert!(ExternalTable::from_rows("_ids", structure.clone(), vec![row(1)]).is_err());
+        assert!(ExternalTable::from_rows("", structure, Vec::<RawRow>::new()).is_err());
+    }
+
+    #[test]
+    fn test_no_columns() {
+        let block = Block {
+            info: Default::default(),
+            rows: 0,
+            column_types: IndexMap::new(),
+            column_data: IndexMap::new(),
+        };
+        assert!(ExternalTable::new("_ids", block).is_err());
+        let no_structure: [(&str, Type); 0] = [];
+        assert!(ExternalTable::from_rows::<RawRow>("_ids", no_structure, vec![]).is_err());
+    }
+}
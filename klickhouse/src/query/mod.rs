@@ -1,16 +1,128 @@
-use std::fmt;
+use std::{fmt, time::Duration};
 
-use crate::{KlickhouseError, Result, ToSql, Value};
+use indexmap::IndexMap;
 
+use crate::{
+    KlickhouseError, QuerySettings, Result, ServerLogLevel, SettingValue, ToSql, TraceContext,
+    Value,
+};
+
+mod external_table;
+pub use external_table::*;
 mod select;
 pub use select::*;
 
+/// A query ready to be sent to Clickhouse, along with its per-query options.
 #[derive(Debug, Clone)]
-pub struct ParsedQuery(pub(crate) String);
+pub struct ParsedQuery {
+    pub(crate) query: String,
+    /// Settings applied on top of [`crate::ClientOptions::settings`] for this query only.
+    pub(crate) settings: QuerySettings,
+    /// Server-side query parameters (`{name:Type}`), in Clickhouse's parameter text format.
+    pub(crate) parameters: IndexMap<String, String>,
+    /// Query id sent to the server. A random one is generated when not set.
+    pub(crate) id: Option<String>,
+    /// Temporary tables sent along with the query.
+    pub(crate) external_tables: Vec<ExternalTable>,
+    /// Overrides [`crate::ClientOptions::query_timeout`] for this query.
+    pub(crate) timeout: Option<Duration>,
+    /// Trace context sent to the server, so that the spans of the query are part of the caller's trace.
+    pub(crate) trace_context: Option<TraceContext>,
+    /// Overrides [`crate::ClientOptions::quota_key`] for this query.
+    pub(crate) quota_key: Option<String>,
+}
+
+impl ParsedQuery {
+    /// Wraps raw SQL text without any argument substitution.
+    pub fn new(query: impl Into<String>) -> Self {
+        ParsedQuery {
+            query: query.into(),
+            settings: QuerySettings::default(),
+            parameters: IndexMap::new(),
+            id: None,
+            external_tables: vec![],
+            timeout: None,
+            trace_context: None,
+            quota_key: None,
+        }
+    }
+
+    /// The SQL text of this query.
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// The query id set with [`ParsedQuery::with_query_id`], if any.
+    pub fn query_id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Sets the id of this query, as seen in `system.query_log` and `system.processes`.
+    /// Useful to correlate queries with the caller's own request ids, or to stop them with [`crate::Client::kill_query`].
+    pub fn with_query_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Cancels the query and fails it with [`KlickhouseError::Timeout`] if it didn't finish `timeout` after being
+    /// sent to the server. Overrides [`crate::ClientOptions::query_timeout`].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sends `trace_context` along with this query, making the server spans of the query children of its span.
+    /// With the `opentelemetry` feature, the context of the current `tracing` span is used when not set.
+    pub fn with_trace_context(mut self, trace_context: TraceContext) -> Self {
+        self.trace_context = Some(trace_context);
+        self
+    }
+
+    /// Accounts this query to the quota of `quota_key`, i.e. a tenant id. Overrides
+    /// [`crate::ClientOptions::quota_key`].
+    pub fn with_quota_key(mut self, quota_key: impl Into<String>) -> Self {
+        self.quota_key = Some(quota_key.into());
+        self
+    }
+
+    /// Overrides a Clickhouse setting for this query only.
+    pub fn with_setting(mut self, name: impl Into<String>, value: impl Into<SettingValue>) -> Self {
+        self.settings.set(name, value);
+        self
+    }
+
+    /// Binds a server-side query parameter, referenced in the query as `{name:Type}`.
+    /// The value is sent separately from the query text, and parsed by the server according to `Type`.
+    pub fn with_param(mut self, name: impl Into<String>, value: impl ToSql) -> Result<Self> {
+        let value = value.to_sql(None)?;
+        self.parameters
+            .insert(name.into(), value.to_parameter_text());
+        Ok(self)
+    }
+
+    /// Sends `table` along with this query, usable in it by name, i.e. `SELECT * FROM t WHERE id IN _ids`.
+    pub fn with_external_table(mut self, table: ExternalTable) -> Self {
+        self.external_tables.push(table);
+        self
+    }
+
+    /// Requests the server logs of this query at `level` and above (the `send_logs_level` setting).
+    /// They are received as [`crate::QueryEvent::Log`], and forwarded to the `log` crate when
+    /// [`crate::ClientOptions::forward_server_logs`] is set.
+    pub fn with_logs_level(self, level: ServerLogLevel) -> Self {
+        self.with_setting("send_logs_level", level.as_str())
+    }
+
+    /// Overrides several Clickhouse settings for this query only.
+    pub fn with_settings(mut self, settings: &QuerySettings) -> Self {
+        self.settings = self.settings.merged(settings);
+        self
+    }
+}
 
 impl fmt::Display for ParsedQuery {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self.query)
     }
 }
 
@@ -18,7 +130,7 @@ impl TryInto<ParsedQuery> for String {
     type Error = KlickhouseError;
 
     fn try_into(self) -> Result<ParsedQuery> {
-        Ok(ParsedQuery(self))
+        Ok(ParsedQuery::new(self))
     }
 }
 
@@ -26,7 +138,7 @@ impl TryInto<ParsedQuery> for &str {
     type Error = KlickhouseError;
 
     fn try_into(self) -> Result<ParsedQuery> {
-        Ok(ParsedQuery(self.to_string()))
+        Ok(ParsedQuery::new(self))
     }
 }
 
@@ -34,7 +146,15 @@ impl TryInto<ParsedQuery> for &String {
     type Error = KlickhouseError;
 
     fn try_into(self) -> Result<ParsedQuery> {
-        Ok(ParsedQuery(self.clone()))
+        Ok(ParsedQuery::new(self.clone()))
+    }
+}
+
+impl TryInto<ParsedQuery> for &ParsedQuery {
+    type Error = KlickhouseError;
+
+    fn try_into(self) -> Result<ParsedQuery> {
+        Ok(self.clone())
     }
 }
 
@@ -42,6 +162,13 @@ impl TryInto<ParsedQuery> for &String {
 pub struct QueryBuilder<'a> {
     base: &'a str,
     arguments: Vec<Result<Value>>,
+    settings: QuerySettings,
+    parameters: Vec<(String, Result<Value>)>,
+    id: Option<String>,
+    external_tables: Vec<ExternalTable>,
+    timeout: Option<Duration>,
+    trace_context: Option<TraceContext>,
+    quota_key: Option<String>,
 }
 
 impl<'a> QueryBuilder<'a> {
@@ -49,9 +176,64 @@ impl<'a> QueryBuilder<'a> {
         Self {
             base: query,
             arguments: vec![],
+            settings: QuerySettings::default(),
+            parameters: vec![],
+            id: None,
+            external_tables: vec![],
+            timeout: None,
+            trace_context: None,
+            quota_key: None,
         }
     }
 
+    /// Binds a server-side query parameter, referenced in the query as `{name:Type}`.
+    /// Unlike `$1` arguments, the value is not substituted into the query text, but sent to the server separately.
+    pub fn param(mut self, name: impl Into<String>, value: impl ToSql) -> Self {
+        self.parameters.push((name.into(), value.to_sql(None)));
+        self
+    }
+
+    /// Sets the id of this query, as seen in `system.query_log` and `system.processes`.
+    pub fn query_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Sets the timeout of this query, see [`ParsedQuery::with_timeout`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the trace context of this query, see [`ParsedQuery::with_trace_context`].
+    pub fn trace_context(mut self, trace_context: TraceContext) -> Self {
+        self.trace_context = Some(trace_context);
+        self
+    }
+
+    /// Sets the quota key of this query, see [`ParsedQuery::with_quota_key`].
+    pub fn quota_key(mut self, quota_key: impl Into<String>) -> Self {
+        self.quota_key = Some(quota_key.into());
+        self
+    }
+
+    /// Overrides a Clickhouse setting for this query only.
+    pub fn setting(mut self, name: impl Into<String>, value: impl Into<SettingValue>) -> Self {
+        self.settings.set(name, value);
+        self
+    }
+
+    /// Sends `table` along with this query, see [`ParsedQuery::with_external_table`].
+    pub fn external_table(mut self, table: ExternalTable) -> Self {
+        self.external_tables.push(table);
+        self
+    }
+
+    /// Requests the server logs of this query at `level` and above, see [`ParsedQuery::with_logs_level`].
+    pub fn logs_level(self, level: ServerLogLevel) -> Self {
+        self.setting("send_logs_level", level.as_str())
+    }
+
     pub fn arg(mut self, arg: impl ToSql) -> Self {
         self.arguments.push(arg.to_sql(None));
         self
@@ -73,9 +255,20 @@ impl TryInto<ParsedQuery> for QueryBuilder<'_> {
 
     fn try_into(self) -> Result<ParsedQuery> {
         let arguments = self.arguments.into_iter().collect::<Result<Vec<_>>>()?;
-        Ok(ParsedQuery(crate::query_parser::parse_query_arguments(
-            self.base,
-            &arguments[..],
-        )))
+        let parameters = self
+            .parameters
+            .into_iter()
+            .map(|(name, value)| Ok((name, value?.to_parameter_text())))
+            .collect::<Result<IndexMap<_, _>>>()?;
+        Ok(ParsedQuery {
+            query: crate::query_parser::parse_query_arguments(self.base, &arguments[..]),
+            settings: self.settings,
+            parameters,
+            id: self.id,
+            external_tables: self.external_tables,
+            timeout: self.timeout,
+            trace_context: self.trace_context,
+            quota_key: self.quota_key,
+        })
     }
 }
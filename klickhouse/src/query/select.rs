@@ -193,7 +193,8 @@ impl SelectBuilder {
         self
     }
 
-    /// Builds this SelectBuilder into a ParsedQuery
+    /// Builds this SelectBuilder into a ParsedQuery, with the parameters, settings and external tables bound on
+    /// its parts.
     pub fn build(self) -> Result<ParsedQuery> {
         self.try_into()
     }
@@ -204,13 +205,13 @@ impl TryInto<ParsedQuery> for SelectBuilder {
 
     fn try_into(mut self) -> Result<ParsedQuery> {
         let mut out = String::new();
+        let mut merged = ParsedQuery::new("");
 
         if !self.withs.is_empty() {
             out.push_str("WITH ");
             self.withs.reverse();
             while let Some(last) = self.withs.pop() {
-                let last = last?;
-                out.push_str(&last.0);
+                out.push_str(&merge_part(&mut merged, last)?);
                 if !self.withs.is_empty() {
                     out.push(',');
                 }
@@ -226,8 +227,7 @@ impl TryInto<ParsedQuery> for SelectBuilder {
             out.push_str("DISTINCT ON (");
             self.distinct_on.reverse();
             while let Some(last) = self.distinct_on.pop() {
-                let last = last?;
-                out.push_str(&last.0);
+                out.push_str(&merge_part(&mut merged, last)?);
                 if !self.distinct_on.is_empty() {
                     out.push(',');
                 }
@@ -237,8 +237,7 @@ impl TryInto<ParsedQuery> for SelectBuilder {
 
         self.exprs.reverse();
         while let Some(last) = self.exprs.pop() {
-            let last = last?;
-            out.push_str(&last.0);
+            out.push_str(&merge_part(&mut merged, last)?);
             if !self.exprs.is_empty() {
                 out.push_str(",\n");
             } else {
@@ -247,19 +246,18 @@ impl TryInto<ParsedQuery> for SelectBuilder {
         }
 
         out.push_str("FROM ");
-        out.push_str(&self.from?.0);
+        out.push_str(&merge_part(&mut merged, self.from)?);
         out.push('\n');
         if let Some(sample) = self.sample {
             out.push_str("SAMPLE ");
-            out.push_str(&sample?.0);
+            out.push_str(&merge_part(&mut merged, sample)?);
             out.push('\n');
         }
 
         if !self.array_joins.is_empty() {
             self.array_joins.reverse();
             while let Some(last) = self.array_joins.pop() {
-                let last = last?;
-                out.push_str(&last.0);
+                out.push_str(&merge_part(&mut merged, last)?);
                 out.push('\n');
             }
         }
@@ -267,8 +265,7 @@ impl TryInto<ParsedQuery> for SelectBuilder {
         if !self.joins.is_empty() {
             self.joins.reverse();
             while let Some(last) = self.joins.pop() {
-                let last = last?;
-                out.push_str(&last.0);
+                out.push_str(&merge_part(&mut merged, last)?);
                 out.push('\n');
             }
         }
@@ -277,8 +274,7 @@ impl TryInto<ParsedQuery> for SelectBuilder {
             self.prewhere.reverse();
             out.push_str("PREWHERE (");
             while let Some(last) = self.prewhere.pop() {
-                let last = last?;
-                out.push_str(&last.0);
+                out.push_str(&merge_part(&mut merged, last)?);
                 if !self.prewhere.is_empty() {
                     out.push_str(") AND\n(");
                 } else {
@@ -291,8 +287,7 @@ impl TryInto<ParsedQuery> for SelectBuilder {
             self.where_.reverse();
             out.push_str("WHERE (");
             while let Some(last) = self.where_.pop() {
-                let last = last?;
-                out.push_str(&last.0);
+                out.push_str(&merge_part(&mut merged, last)?);
                 if !self.where_.is_empty() {
                     out.push_str(") AND\n(");
                 } else {
@@ -305,8 +300,7 @@ impl TryInto<ParsedQuery> for SelectBuilder {
             self.group_by.reverse();
             out.push_str("GROUP BY ");
             while let Some(last) = self.group_by.pop() {
-                let last = last?;
-                out.push_str(&last.0);
+                out.push_str(&merge_part(&mut merged, last)?);
                 if !self.group_by.is_empty() {
                     out.push_str(",\n");
                 } else {
@@ -319,8 +313,7 @@ impl TryInto<ParsedQuery> for SelectBuilder {
             self.having.reverse();
             out.push_str("HAVING (");
             while let Some(last) = self.having.pop() {
-                let last = last?;
-                out.push_str(&last.0);
+                out.push_str(&merge_part(&mut merged, last)?);
                 if !self.having.is_empty() {
                     out.push_str(") AND\n(");
                 } else {
@@ -331,32 +324,89 @@ impl TryInto<ParsedQuery> for SelectBuilder {
 
         if let Some(order_by) = self.order_by {
             out.push_str("ORDER BY ");
-            out.push_str(&order_by?.0);
+            out.push_str(&merge_part(&mut merged, order_by)?);
             out.push('\n');
         }
 
         if let Some(limit) = self.limit {
             out.push_str("LIMIT ");
-            out.push_str(&limit?.0);
+            out.push_str(&merge_part(&mut merged, limit)?);
             out.push('\n');
         }
 
         if let Some(settings) = self.settings {
             out.push_str("SETTINGS ");
-            out.push_str(&settings?.0);
+            out.push_str(&merge_part(&mut merged, settings)?);
             out.push('\n');
         }
 
         if let Some(union) = self.union {
             out.push_str("UNION ");
-            out.push_str(&union?.0);
+            out.push_str(&merge_part(&mut merged, union)?);
             out.push('\n');
         }
 
-        Ok(ParsedQuery(out))
+        merged.query = out;
+        Ok(merged)
     }
 }
 
+/// Merges what is bound on `part` of a [`SelectBuilder`] into `merged`, returning the SQL text of the part.
+/// Two parts binding a parameter, setting or option to different values is an error.
+fn merge_part(merged: &mut ParsedQuery, part: Result<ParsedQuery>) -> Result<String> {
+    let part = part?;
+    for (name, value) in part.parameters {
+        match merged.parameters.get(&name) {
+            Some(existing) if *existing != value => {
+                return Err(conflict("parameter", &name));
+            }
+            _ => {
+                merged.parameters.insert(name, value);
+            }
+        }
+    }
+    for (name, value) in part.settings.iter() {
+        match merged.settings.get(name) {
+            Some(existing) if existing != value => return Err(conflict("setting", name)),
+            _ => merged.settings.set(name, value.clone()),
+        }
+    }
+    for table in part.external_tables {
+        if merged.external_tables.iter().any(|t| t.name == table.name) {
+            return Err(conflict("external table", &table.name));
+        }
+        merged.external_tables.push(table);
+    }
+    merge_option(&mut merged.id, part.id, "query id")?;
+    merge_option(&mut merged.timeout, part.timeout, "timeout")?;
+    merge_option(
+        &mut merged.trace_context,
+        part.trace_context,
+        "trace context",
+    )?;
+    merge_option(&mut merged.quota_key, part.quota_key, "quota key")?;
+    Ok(part.query)
+}
+
+fn merge_option<T: PartialEq>(merged: &mut Option<T>, part: Option<T>, what: &str) -> Result<()> {
+    match (merged.as_ref(), part) {
+        (Some(existing), Some(value)) if *existing != value => Err(
+            KlickhouseError::SerializeError(format!("parts of the select set different {what}s")),
+        ),
+        (_, Some(value)) => {
+            *merged = Some(value);
+            Ok(())
+        }
+        (_, None) => Ok(()),
+    }
+}
+
+fn conflict(what: &str, name: &str) -> KlickhouseError {
+    KlickhouseError::SerializeError(format!(
+        "{what} {name} is bound twice with different values"
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::QueryBuilder;
@@ -376,4 +426,36 @@ mod tests {
         let query = builder.build().unwrap();
         println!("{query}");
     }
+
+    #[test]
+    fn test_select_builder_parameters() {
+        let query = SelectBuilder::new("events")
+            .select("id")
+            .where_(QueryBuilder::new("id = {id:UInt64}").param("id", 5u64))
+            .where_(QueryBuilder::new("kind = {kind:String}").param("kind", "click"))
+            .settings(QueryBuilder::new("max_threads = 1").setting("max_threads", 1u64))
+            .build()
+            .unwrap();
+        assert!(query
+            .query()
+            .contains("WHERE (id = {id:UInt64}) AND\n(kind = {kind:String})"));
+        assert_eq!(query.parameters.get("id").map(String::as_str), Some("5"));
+        assert_eq!(
+            query.parameters.get("kind").map(String::as_str),
+            Some("click")
+        );
+        assert!(query.settings.get("max_threads").is_some());
+
+        // The same value bound on several parts is fine, different ones are not.
+        SelectBuilder::new("events")
+            .where_(QueryBuilder::new("id = {id:UInt64}").param("id", 5u64))
+            .having(QueryBuilder::new("max(id) > {id:UInt64}").param("id", 5u64))
+            .build()
+            .unwrap();
+        let result = SelectBuilder::new("events")
+            .where_(QueryBuilder::new("id = {id:UInt64}").param("id", 5u64))
+            .having(QueryBuilder::new("max(id) > {id:UInt64}").param("id", 6u64))
+            .build();
+        assert!(matches!(result, Err(KlickhouseError::SerializeError(_))));
+    }
 }
@@ -0,0 +1,234 @@
+use std::{
+    future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+};
+
+use futures_util::{Stream, StreamExt};
+use indexmap::IndexMap;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    block::Block,
+    convert::Row,
+    internal_client_out::BlockEncoding,
+    profile_events::{accumulate_profile_events, ProfileEvent},
+    progress::Progress,
+    protocol::BlockStreamProfileInfo,
+    Result, ServerLogRecord,
+};
+
+/// A packet received from Clickhouse for a single query.
+#[derive(Debug, Clone)]
+pub enum QueryEvent {
+    /// A block of result rows. The first block of a query is usually empty, and only describes the result columns.
+    Data(Block),
+    /// Progress of the query since the last progress event.
+    /// Progress events are dropped rather than stalling the connection when the handle isn't read,
+    /// [`QueryHandle::progress`] still accounts for them.
+    Progress(Progress),
+    /// Summary of the result, sent after all data blocks.
+    ProfileInfo(BlockStreamProfileInfo),
+    /// Totals row of a `GROUP BY ... WITH TOTALS` query.
+    Totals(Block),
+    /// Minimum and maximum rows of the result, when the `extremes` setting is enabled.
+    Extremes(Block),
+    /// A server log line, when requested with [`crate::ParsedQuery::with_logs_level`].
+    /// Like progress, log events are dropped rather than stalling the connection when the handle isn't read.
+    Log(ServerLogRecord),
+    /// Server counters for the query, i.e. selected marks, read bytes and memory usage.
+    /// Like progress, these events are dropped rather than stalling the connection when the handle isn't read.
+    ProfileEvents(Vec<ProfileEvent>),
+}
+
+/// Handle to a running query, returned by [`crate::Client::query_handle`].
+///
+/// The handle is a [`Stream`] of every [`QueryEvent`] of the query. Events that are not data blocks are also
+/// recorded, and can be retrieved with [`QueryHandle::progress`], [`QueryHandle::profile_info`],
+/// [`QueryHandle::totals`] and [`QueryHandle::extremes`] once they were received.
+///
+/// Dropping the handle before the end of the query cancels it on the server.
+pub struct QueryHandle {
+    id: String,
+    /// Tags the data blocks sent for the query, see [`QueryHandle::generation`].
+    generation: u64,
+    events: ReceiverStream<Result<QueryEvent>>,
+    /// Summed by the client task, so that progress events dropped from `events` are still counted.
+    progress: Arc<Mutex<Progress>>,
+    profile_info: Option<BlockStreamProfileInfo>,
+    totals: Option<Block>,
+    extremes: Option<Block>,
+    profile_events: IndexMap<String, i64>,
+    /// Encoding of the data blocks sent for the query, i.e. inserted rows.
+    encoding: BlockEncoding,
+}
+
+/// All results of a query, collected by [`QueryHandle::collect`].
+#[derive(Debug, Clone)]
+pub struct QueryOutput<T> {
+    /// Id of the query, as seen in `system.query_log`.
+    pub id: String,
+    pub rows: Vec<T>,
+    /// Totals row of a `GROUP BY ... WITH TOTALS` query.
+    pub totals: Option<T>,
+    /// Minimum and maximum rows, when the `extremes` setting is enabled.
+    pub extremes: Vec<T>,
+    pub profile_info: Option<BlockStreamProfileInfo>,
+    /// Sum of all progress events of the query.
+    pub progress: Progress,
+    /// Query-level counters of the query, see [`QueryHandle::profile_events`].
+    pub profile_events: IndexMap<String, i64>,
+}
+
+fn deserialize_rows<T: Row>(mut block: Block) -> Result<Vec<T>> {
+    block
+        .take_iter_rows()
+        .filter(|x| !x.is_empty())
+        .map(|m| T::deserialize_row(m))
+        .collect()
+}
+
+impl QueryHandle {
+    pub(crate) fn new(
+        id: String,
+        generation: u64,
+        receiver: mpsc::Receiver<Result<QueryEvent>>,
+        progress: Arc<Mutex<Progress>>,
+        encoding: BlockEncoding,
+    ) -> Self {
+        Self {
+            id,
+            generation,
+            events: ReceiverStream::new(receiver),
+            progress,
+            profile_info: None,
+            totals: None,
+            extremes: None,
+            profile_events: IndexMap::new(),
+            encoding,
+        }
+    }
+
+    pub(crate) fn encoding(&self) -> BlockEncoding {
+        self.encoding
+    }
+
+    /// Number of the query among the queries of its client. Data blocks carry it, so that the blocks left over
+    /// from a query that ended early aren't sent as data of the next one.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Id of the query, as seen in `system.query_log` and `system.processes`.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Sum of the progress reported by the server so far, including events dropped from the stream.
+    pub fn progress(&self) -> Progress {
+        *self.progress.lock().unwrap()
+    }
+
+    /// Summary of the result, available once all data blocks were received.
+    pub fn profile_info(&self) -> Option<&BlockStreamProfileInfo> {
+        self.profile_info.as_ref()
+    }
+
+    /// Number of rows the result would have had without `LIMIT`, if calculated by the server.
+    pub fn rows_before_limit(&self) -> Option<u64> {
+        self.profile_info
+            .as_ref()
+            .filter(|info| info.calculated_rows_before_limit)
+            .map(|info| info.rows_before_limit)
+    }
+
+    /// Totals block of a `GROUP BY ... WITH TOTALS` query, available once all data blocks were received.
+    pub fn totals(&self) -> Option<&Block> {
+        self.totals.as_ref()
+    }
+
+    /// Extremes block, when the `extremes` setting is enabled. Available once all data blocks were received.
+    pub fn extremes(&self) -> Option<&Block> {
+        self.extremes.as_ref()
+    }
+
+    /// Query-level ProfileEvents counters received so far, by name (i.e. `SelectedMarks`, `ReadCompressedBytes`).
+    /// Increments are summed over all packets and hosts, gauges (i.e. `MemoryTrackerPeakUsage`) keep their latest value.
+    pub fn profile_events(&self) -> &IndexMap<String, i64> {
+        &self.profile_events
+    }
+
+    fn record(&mut self, event: &QueryEvent) {
+        match event {
+            QueryEvent::Data(_) | QueryEvent::Log(_) | QueryEvent::Progress(_) => (),
+            QueryEvent::ProfileInfo(info) => self.profile_info = Some(info.clone()),
+            QueryEvent::Totals(block) => self.totals = Some(block.clone()),
+            QueryEvent::Extremes(block) => self.extremes = Some(block.clone()),
+            QueryEvent::ProfileEvents(events) => {
+                accumulate_profile_events(&mut self.profile_events, events)
+            }
+        }
+    }
+
+    /// Returns the next data block, recording any other event received before it.
+    pub async fn next_block(&mut self) -> Option<Result<Block>> {
+        while let Some(event) = self.next().await {
+            match event {
+                Ok(QueryEvent::Data(block)) => return Some(Ok(block)),
+                Ok(_) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+
+    /// Converts this handle into a stream of data blocks, discarding other events.
+    pub fn blocks(self) -> impl Stream<Item = Result<Block>> {
+        self.filter_map(|event| {
+            future::ready(match event {
+                Ok(QueryEvent::Data(block)) => Some(Ok(block)),
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            })
+        })
+    }
+
+    /// Reads the query to completion, deserializing data, totals and extremes rows.
+    pub async fn collect<T: Row>(mut self) -> Result<QueryOutput<T>> {
+        let mut rows = vec![];
+        while let Some(block) = self.next_block().await {
+            rows.extend(deserialize_rows(block?)?);
+        }
+        let totals = match self.totals.take() {
+            Some(block) => deserialize_rows(block)?.into_iter().next(),
+            None => None,
+        };
+        let extremes = match self.extremes.take() {
+            Some(block) => deserialize_rows(block)?,
+            None => vec![],
+        };
+        Ok(QueryOutput {
+            id: std::mem::take(&mut self.id),
+            rows,
+            totals,
+            extremes,
+            profile_info: self.profile_info.take(),
+            progress: self.progress(),
+            profile_events: std::mem::take(&mut self.profile_events),
+        })
+    }
+}
+
+impl Stream for QueryHandle {
+    type Item = Result<QueryEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let event = ready!(self.events.poll_next_unpin(cx));
+        if let Some(Ok(event)) = &event {
+            self.record(event);
+        }
+        Poll::Ready(event)
+    }
+}
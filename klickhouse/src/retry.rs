@@ -0,0 +1,274 @@
+use std::{collections::hash_map::RandomState, future::Future, hash::BuildHasher, time::Duration};
+
+use log::{info, warn};
+
+use crate::{KlickhouseError, ParsedQuery, Result};
+
+/// Setting making a retried insert a no-op when the first attempt was written, into `Replicated*MergeTree` tables or
+/// tables with a `non_replicated_deduplication_window`.
+const INSERT_DEDUPLICATION_TOKEN: &str = "insert_deduplication_token";
+
+/// How failed queries are sent again, set in [`crate::ClientOptions::retry`] or with [`RetryPolicy::run`].
+///
+/// Queries are retried on errors for which [`KlickhouseError::is_retryable`] is true: lost connections and
+/// transient server exceptions. Only read-only statements (`SELECT`, `WITH`, `SHOW`, `DESCRIBE`, `EXISTS` and
+/// `EXPLAIN`) are retried, and inserts with an `insert_deduplication_token` setting, as a failed attempt of
+/// other statements may have been applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the second attempt, doubled after each failed attempt.
+    pub initial_backoff: Duration,
+    /// Maximum delay between two attempts.
+    pub max_backoff: Duration,
+    /// Waits a random delay up to the exponential backoff instead of the full backoff, so that clients failing at
+    /// the same time don't retry at the same time.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay after `attempts` failed attempts.
+    fn backoff(&self, attempts: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+            .min(self.max_backoff);
+        if !self.jitter {
+            return backoff;
+        }
+        let random = RandomState::new().hash_one(attempts);
+        backoff.mul_f64(random as f64 / u64::MAX as f64)
+    }
+
+    /// Runs `operation` until it succeeds, fails with an error that isn't retryable, or `max_attempts` attempts
+    /// failed. `operation` receives the number of the attempt, starting at 1.
+    ///
+    /// When several attempts failed, the last error is returned in [`KlickhouseError::RetriesExhausted`].
+    pub async fn run<T, F, Fut>(&self, operation: F) -> Result<T>
+    where
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let (value, _) = self.run_while(operation, || true).await?;
+        Ok(value)
+    }
+
+    /// Like [`RetryPolicy::run`], also returning the number of attempts made, including the successful one.
+    pub async fn run_with_attempts<T, F, Fut>(&self, operation: F) -> Result<(T, u32)>
+    where
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.run_while(operation, || true).await
+    }
+
+    /// Like [`RetryPolicy::run_with_attempts`], giving up as soon as `can_retry` returns false.
+    pub(crate) async fn run_while<T, F, Fut>(
+        &self,
+        mut operation: F,
+        can_retry: impl Fn() -> bool,
+    ) -> Result<(T, u32)>
+    where
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let error = match operation(attempts).await {
+                Ok(value) => {
+                    if attempts > 1 {
+                        info!("succeeded after {attempts} attempts");
+                    }
+                    return Ok((value, attempts));
+                }
+                Err(e) => e,
+            };
+            if attempts >= self.max_attempts || !error.is_retryable() || !can_retry() {
+                if attempts == 1 {
+                    return Err(error);
+                }
+                return Err(KlickhouseError::RetriesExhausted {
+                    attempts,
+                    source: Box::new(error),
+                });
+            }
+            let backoff = self.backoff(attempts);
+            warn!("attempt {attempts} failed, retrying in {backoff:?}: {error}");
+            tokio::time::sleep(backoff).await;
+        }
+    }
+}
+
+/// Statements that only read, sent again without other effect.
+const READ_ONLY_STATEMENTS: &[&str] = &[
+    "SELECT", "WITH", "SHOW", "DESCRIBE", "DESC", "EXISTS", "EXPLAIN",
+];
+
+/// Whether sending `query` again can't have more effects than sending it once: read-only statements, and inserts
+/// with a deduplication token. Other statements, i.e. a `CREATE TABLE` that succeeded before the connection was
+/// lost, would fail or apply twice.
+pub(crate) fn is_idempotent(query: &ParsedQuery) -> bool {
+    let keyword = query
+        .query
+        .trim_start_matches(|c: char| c.is_whitespace() || c == '(')
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .next()
+        .unwrap_or_default();
+    if keyword.eq_ignore_ascii_case("insert") {
+        return query.settings.get(INSERT_DEDUPLICATION_TOKEN).is_some();
+    }
+    READ_ONLY_STATEMENTS
+        .iter()
+        .any(|statement| keyword.eq_ignore_ascii_case(statement))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::{ErrorCode, ServerException};
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            jitter: false,
+        }
+    }
+
+    fn exception(code: ErrorCode) -> KlickhouseError {
+        KlickhouseError::ServerException(ServerException {
+            code,
+            name: "DB::Exception".to_string(),
+            message: String::new(),
+            stack_trace: String::new(),
+            nested: None,
+        })
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = policy();
+        assert_eq!(policy.backoff(1), Duration::from_millis(1));
+        assert_eq!(policy.backoff(2), Duration::from_millis(2));
+        assert_eq!(policy.backoff(10), Duration::from_millis(5));
+        let policy = RetryPolicy {
+            jitter: true,
+            ..policy
+        };
+        for attempts in 1..10 {
+            assert!(policy.backoff(attempts) <= Duration::from_millis(5));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_retries_until_success() {
+        let attempts = AtomicU32::new(0);
+        let result = policy()
+            .run(|attempt| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    match attempt {
+                        1 => Err(KlickhouseError::ConnectionLost("reset".to_string())),
+                        _ => Ok(attempt),
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_with_attempts() {
+        let result = policy()
+            .run_with_attempts(|attempt| async move {
+                match attempt {
+                    1 | 2 => Err(KlickhouseError::ConnectionLost("reset".to_string())),
+                    _ => Ok("done"),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), ("done", 3));
+        let result = policy().run_with_attempts(|_| async { Ok(()) }).await;
+        assert_eq!(result.unwrap(), ((), 1));
+    }
+
+    #[tokio::test]
+    async fn test_run_exhausted() {
+        let result = policy()
+            .run(|_| async { Err::<(), _>(exception(ErrorCode::TooManySimultaneousQueries)) })
+            .await;
+        match result {
+            Err(KlickhouseError::RetriesExhausted { attempts, source }) => {
+                assert_eq!(attempts, 3);
+                assert_eq!(
+                    source.server_code(),
+                    Some(ErrorCode::TooManySimultaneousQueries)
+                );
+            }
+            result => panic!("unexpected result {result:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_not_retryable() {
+        let attempts = AtomicU32::new(0);
+        let result = policy()
+            .run(|_| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async { Err::<(), _>(exception(ErrorCode::SyntaxError)) }
+            })
+            .await;
+        assert_eq!(
+            result.unwrap_err().server_code(),
+            Some(ErrorCode::SyntaxError)
+        );
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_is_idempotent() {
+        assert!(is_idempotent(&ParsedQuery::new("SELECT 1")));
+        assert!(is_idempotent(&ParsedQuery::new(
+            "\n with x AS (SELECT 1) SELECT * FROM x"
+        )));
+        assert!(is_idempotent(&ParsedQuery::new(
+            "(SELECT 1) UNION ALL (SELECT 2)"
+        )));
+        assert!(is_idempotent(&ParsedQuery::new("SHOW TABLES")));
+        assert!(is_idempotent(&ParsedQuery::new("desc t")));
+        assert!(is_idempotent(&ParsedQuery::new("EXISTS TABLE t")));
+        assert!(is_idempotent(&ParsedQuery::new("EXPLAIN SELECT 1")));
+        assert!(!is_idempotent(&ParsedQuery::new("SELECTED")));
+        assert!(!is_idempotent(&ParsedQuery::new(
+            "CREATE TABLE t (id UInt64) ENGINE = Memory"
+        )));
+        assert!(!is_idempotent(&ParsedQuery::new(
+            "ALTER TABLE t DELETE WHERE 1"
+        )));
+        assert!(!is_idempotent(&ParsedQuery::new("SYSTEM FLUSH LOGS")));
+        assert!(!is_idempotent(&ParsedQuery::new(
+            "  insert INTO t FORMAT Native"
+        )));
+        assert!(is_idempotent(
+            &ParsedQuery::new("INSERT INTO t FORMAT Native")
+                .with_setting(INSERT_DEDUPLICATION_TOKEN, "batch-1")
+        ));
+    }
+}
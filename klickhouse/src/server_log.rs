@@ -0,0 +1,183 @@
+use std::fmt;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{block::Block, convert::Row, KlickhouseError, RawRow, Result};
+
+/// Target of the `log` records emitted for server logs when [`crate::ClientOptions::forward_server_logs`] is set.
+pub const SERVER_LOG_TARGET: &str = "klickhouse::server";
+
+/// Minimum priority of the server logs sent to the client, through the `send_logs_level` setting.
+///
+/// Set it per query with [`crate::ParsedQuery::with_logs_level`] or [`crate::QueryBuilder::logs_level`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ServerLogLevel {
+    Fatal = 1,
+    Critical,
+    Error,
+    Warning,
+    Notice,
+    Information,
+    Debug,
+    Trace,
+    Test,
+}
+
+impl ServerLogLevel {
+    /// Name of the level, as accepted by the `send_logs_level` setting.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServerLogLevel::Fatal => "fatal",
+            ServerLogLevel::Critical => "critical",
+            ServerLogLevel::Error => "error",
+            ServerLogLevel::Warning => "warning",
+            ServerLogLevel::Notice => "notice",
+            ServerLogLevel::Information => "information",
+            ServerLogLevel::Debug => "debug",
+            ServerLogLevel::Trace => "trace",
+            ServerLogLevel::Test => "test",
+        }
+    }
+
+    fn from_priority(priority: i8) -> Option<Self> {
+        Some(match priority {
+            1 => ServerLogLevel::Fatal,
+            2 => ServerLogLevel::Critical,
+            3 => ServerLogLevel::Error,
+            4 => ServerLogLevel::Warning,
+            5 => ServerLogLevel::Notice,
+            6 => ServerLogLevel::Information,
+            7 => ServerLogLevel::Debug,
+            8 => ServerLogLevel::Trace,
+            9 => ServerLogLevel::Test,
+            _ => return None,
+        })
+    }
+
+    /// The closest `log` crate level.
+    pub fn to_log_level(&self) -> log::Level {
+        match self {
+            ServerLogLevel::Fatal | ServerLogLevel::Critical | ServerLogLevel::Error => {
+                log::Level::Error
+            }
+            ServerLogLevel::Warning => log::Level::Warn,
+            ServerLogLevel::Notice | ServerLogLevel::Information => log::Level::Info,
+            ServerLogLevel::Debug => log::Level::Debug,
+            ServerLogLevel::Trace | ServerLogLevel::Test => log::Level::Trace,
+        }
+    }
+}
+
+impl fmt::Display for ServerLogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A log line emitted by the server while executing a query, as in `system.text_log`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerLogRecord {
+    /// Time of the event, with microsecond precision.
+    pub event_time: DateTime<Utc>,
+    pub host_name: String,
+    pub query_id: String,
+    pub thread_id: u64,
+    pub priority: ServerLogLevel,
+    /// Logger name, i.e. `executeQuery`.
+    pub source: String,
+    pub text: String,
+}
+
+impl ServerLogRecord {
+    /// Parses the rows of a `Log` packet block.
+    pub(crate) fn from_block(mut block: Block) -> Result<Vec<Self>> {
+        block
+            .take_iter_rows()
+            .map(|row| Self::from_row(RawRow::deserialize_row(row)?))
+            .collect()
+    }
+
+    fn from_row(mut row: RawRow) -> Result<Self> {
+        let event_time: DateTime<Utc> = row.try_get("event_time")?;
+        let microseconds: u32 = row.try_get("event_time_microseconds")?;
+        let priority: i8 = row.try_get("priority")?;
+        Ok(Self {
+            event_time: event_time + Duration::microseconds(microseconds as i64),
+            host_name: row.try_get("host_name")?,
+            query_id: row.try_get("query_id")?,
+            thread_id: row.try_get("thread_id")?,
+            priority: ServerLogLevel::from_priority(priority).ok_or_else(|| {
+                KlickhouseError::DeserializeError(format!("invalid log priority: {priority}"))
+            })?,
+            source: row.try_get("source")?,
+            text: row.try_get("text")?,
+        })
+    }
+
+    /// Emits this record through the `log` crate, under [`SERVER_LOG_TARGET`].
+    pub(crate) fn forward(&self) {
+        log::log!(
+            target: SERVER_LOG_TARGET,
+            self.priority.to_log_level(),
+            "[{}] {{{}}} <{}> {}: {}",
+            self.host_name,
+            self.query_id,
+            self.priority,
+            self.source,
+            self.text
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+
+    use super::*;
+    use crate::{block::BlockInfo, Type, Value};
+
+    #[test]
+    fn test_from_block() {
+        let mut column_types = IndexMap::new();
+        let mut column_data = IndexMap::new();
+        let mut column = |name: &str, type_: Type, value: Value| {
+            column_types.insert(name.to_string(), type_);
+            column_data.insert(name.to_string(), vec![value]);
+        };
+        column(
+            "event_time",
+            Type::DateTime(chrono_tz::UTC),
+            Value::DateTime(crate::DateTime(chrono_tz::UTC, 1_700_000_000)),
+        );
+        column("event_time_microseconds", Type::UInt32, Value::UInt32(250));
+        column("host_name", Type::String, Value::string("host"));
+        column("query_id", Type::String, Value::string("abc"));
+        column("thread_id", Type::UInt64, Value::UInt64(42));
+        column("priority", Type::Int8, Value::Int8(4));
+        column("source", Type::String, Value::string("executeQuery"));
+        column("text", Type::String, Value::string("slow"));
+        let block = Block {
+            info: BlockInfo::default(),
+            rows: 1,
+            column_types,
+            column_data,
+        };
+
+        let records = ServerLogRecord::from_block(block).unwrap();
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record.event_time.timestamp(), 1_700_000_000);
+        assert_eq!(record.event_time.timestamp_subsec_micros(), 250);
+        assert_eq!(record.priority, ServerLogLevel::Warning);
+        assert_eq!(record.thread_id, 42);
+        assert_eq!(record.source, "executeQuery");
+        assert_eq!(record.text, "slow");
+    }
+
+    #[test]
+    fn test_log_level_order() {
+        assert!(ServerLogLevel::Error < ServerLogLevel::Trace);
+        assert_eq!(ServerLogLevel::Information.as_str(), "information");
+        assert_eq!(ServerLogLevel::Test.to_log_level(), log::Level::Trace);
+    }
+}
@@ -0,0 +1,237 @@
+use std::fmt;
+
+use indexmap::IndexMap;
+
+use crate::{
+    io::ClickhouseWrite, protocol::DBMS_MIN_REVISION_WITH_SETTINGS_SERIALIZED_AS_STRINGS,
+    KlickhouseError, Result, Value,
+};
+
+/// Setting flag: the server rejects the query if it doesn't know the setting, instead of ignoring it.
+const SETTING_FLAG_IMPORTANT: u64 = 0x01;
+
+/// A typed value for a Clickhouse setting.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettingValue {
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    String(String),
+}
+
+impl fmt::Display for SettingValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingValue::Bool(x) => write!(f, "{}", if *x { 1 } else { 0 }),
+            SettingValue::Int(x) => write!(f, "{x}"),
+            SettingValue::UInt(x) => write!(f, "{x}"),
+            SettingValue::Float(x) => write!(f, "{x}"),
+            SettingValue::String(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+macro_rules! setting_value_from {
+    ($variant:ident, $target:ty, $($t:ty),+) => {
+        $(
+            impl From<$t> for SettingValue {
+                fn from(value: $t) -> Self {
+                    SettingValue::$variant(value as $target)
+                }
+            }
+        )+
+    };
+}
+
+setting_value_from!(Int, i64, i8, i16, i32, i64);
+setting_value_from!(UInt, u64, u8, u16, u32, u64, usize);
+setting_value_from!(Float, f64, f32, f64);
+
+impl From<bool> for SettingValue {
+    fn from(value: bool) -> Self {
+        SettingValue::Bool(value)
+    }
+}
+
+impl From<String> for SettingValue {
+    fn from(value: String) -> Self {
+        SettingValue::String(value)
+    }
+}
+
+impl From<&str> for SettingValue {
+    fn from(value: &str) -> Self {
+        SettingValue::String(value.to_string())
+    }
+}
+
+/// An ordered set of Clickhouse settings (i.e. `max_threads`, `max_execution_time`) sent in the Query packet.
+///
+/// Settings can be set per connection in [`crate::ClientOptions::settings`] and overridden per query with
+/// [`crate::ParsedQuery::with_setting`] or [`crate::QueryBuilder::setting`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QuerySettings {
+    settings: IndexMap<String, SettingValue>,
+}
+
+impl QuerySettings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a setting, replacing any previous value.
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<SettingValue>) {
+        self.settings.insert(name.into(), value.into());
+    }
+
+    /// Builder variant of [`QuerySettings::set`].
+    pub fn with(mut self, name: impl Into<String>, value: impl Into<SettingValue>) -> Self {
+        self.set(name, value);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SettingValue> {
+        self.settings.get(name)
+    }
+
+    /// Removes a setting, returning its value if it was set.
+    pub fn remove(&mut self, name: &str) -> Option<SettingValue> {
+        self.settings.shift_remove(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.settings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.settings.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &SettingValue)> {
+        self.settings.iter().map(|(name, value)| (&**name, value))
+    }
+
+    /// Returns these settings with every setting from `overrides` applied on top.
+    pub fn merged(&self, overrides: &QuerySettings) -> QuerySettings {
+        let mut out = self.clone();
+        for (name, value) in overrides.settings.iter() {
+            out.settings.insert(name.clone(), value.clone());
+        }
+        out
+    }
+
+    /// `SET` statements applying these settings to the session, for servers too old to receive them with queries.
+    pub(crate) fn set_statements(&self) -> Vec<String> {
+        self.settings
+            .iter()
+            .map(|(name, value)| match value {
+                SettingValue::String(x) => {
+                    format!("SET {name} = {}", Value::String(x.clone().into_bytes()))
+                }
+                value => format!("SET {name} = {value}"),
+            })
+            .collect()
+    }
+
+    pub(crate) async fn write<W: ClickhouseWrite>(&self, to: &mut W, revision: u64) -> Result<()> {
+        if !self.is_empty() && revision < DBMS_MIN_REVISION_WITH_SETTINGS_SERIALIZED_AS_STRINGS {
+            return Err(KlickhouseError::ProtocolError(format!(
+                "server revision {} is too old to receive settings",
+                revision
+            )));
+        }
+        for (name, value) in self.settings.iter() {
+            to.write_string(name).await?;
+            to.write_var_uint(SETTING_FLAG_IMPORTANT).await?;
+            to.write_string(value.to_string()).await?;
+        }
+        to.write_string("").await?;
+        Ok(())
+    }
+}
+
+impl<K: Into<String>, V: Into<SettingValue>> FromIterator<(K, V)> for QuerySettings {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        let mut out = QuerySettings::new();
+        for (name, value) in iter {
+            out.set(name, value);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::DBMS_TCP_PROTOCOL_VERSION;
+
+    #[test]
+    fn test_setting_value_display() {
+        assert_eq!(SettingValue::from(true).to_string(), "1");
+        assert_eq!(SettingValue::from(false).to_string(), "0");
+        assert_eq!(SettingValue::from(-3i32).to_string(), "-3");
+        assert_eq!(SettingValue::from(4u64).to_string(), "4");
+        assert_eq!(SettingValue::from(0.5f64).to_string(), "0.5");
+        assert_eq!(SettingValue::from("best_effort").to_string(), "best_effort");
+    }
+
+    #[test]
+    fn test_merged_overrides_keep_order() {
+        let base = QuerySettings::new()
+            .with("max_threads", 8u64)
+            .with("max_execution_time", 30u64);
+        let overrides = QuerySettings::new()
+            .with("max_threads", 2u64)
+            .with("insert_quorum", 2u64);
+        let merged = base.merged(&overrides);
+        let names: Vec<_> = merged.iter().map(|(name, _)| name).collect();
+        assert_eq!(
+            names,
+            vec!["max_threads", "max_execution_time", "insert_quorum"]
+        );
+        assert_eq!(merged.get("max_threads"), Some(&SettingValue::UInt(2)));
+    }
+
+    #[tokio::test]
+    async fn test_write_settings() {
+        let settings = QuerySettings::new().with("max_threads", 4u64);
+        let mut buf: Vec<u8> = vec![];
+        settings
+            .write(&mut buf, DBMS_TCP_PROTOCOL_VERSION)
+            .await
+            .unwrap();
+        let mut expected = vec![11u8];
+        expected.extend_from_slice(b"max_threads");
+        expected.push(SETTING_FLAG_IMPORTANT as u8);
+        expected.extend_from_slice(&[1, b'4']);
+        expected.push(0);
+        assert_eq!(buf, expected);
+    }
+
+    #[tokio::test]
+    async fn test_write_settings_old_revision() {
+        let mut buf: Vec<u8> = vec![];
+        QuerySettings::new().write(&mut buf, 54000).await.unwrap();
+        assert_eq!(buf, vec![0u8]);
+
+        let settings = QuerySettings::new().with("max_threads", 4u64);
+        assert!(settings.write(&mut buf, 54000).await.is_err());
+    }
+
+    #[test]
+    fn test_set_statements() {
+        let settings = QuerySettings::new()
+            .with("max_threads", 4u64)
+            .with("date_time_input_format", "best_effort")
+            .with("log_comment", "it's");
+        assert_eq!(
+            settings.set_statements(),
+            vec![
+                "SET max_threads = 4",
+                "SET date_time_input_format = 'best_effort'",
+                "SET log_comment = 'it\\'s'",
+            ]
+        );
+    }
+}
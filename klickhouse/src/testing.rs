@@ -0,0 +1,1112 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use indexmap::IndexMap;
+use log::{debug, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    select,
+    sync::mpsc,
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::{
+    auth::USER_INTERSERVER_MARKER,
+    block::{Block, BlockInfo},
+    internal_client_out::BlockEncoding,
+    io::{ClickhouseRead, ClickhouseWrite},
+    protocol::{
+        ClientPacketId, ServerPacketId, DBMS_MIN_PROTOCOL_VERSION_WITH_ADDENDUM,
+        DBMS_MIN_PROTOCOL_VERSION_WITH_DISTRIBUTED_DEPTH,
+        DBMS_MIN_PROTOCOL_VERSION_WITH_INITIAL_QUERY_START_TIME,
+        DBMS_MIN_PROTOCOL_VERSION_WITH_PARAMETERS,
+        DBMS_MIN_PROTOCOL_VERSION_WITH_PASSWORD_COMPLEXITY_RULES,
+        DBMS_MIN_PROTOCOL_VERSION_WITH_SERVER_QUERY_TIME_IN_PROGRESS,
+        DBMS_MIN_PROTOCOL_VERSION_WITH_TOTAL_BYTES_IN_PROGRESS, DBMS_MIN_REVISION_WITH_CLIENT_INFO,
+        DBMS_MIN_REVISION_WITH_CLIENT_WRITE_INFO, DBMS_MIN_REVISION_WITH_INTERSERVER_SECRET,
+        DBMS_MIN_REVISION_WITH_INTERSERVER_SECRET_V2, DBMS_MIN_REVISION_WITH_OPENTELEMETRY,
+        DBMS_MIN_REVISION_WITH_PARALLEL_REPLICAS, DBMS_MIN_REVISION_WITH_QUOTA_KEY_IN_CLIENT_INFO,
+        DBMS_MIN_REVISION_WITH_SERVER_DISPLAY_NAME, DBMS_MIN_REVISION_WITH_SERVER_TIMEZONE,
+        DBMS_MIN_REVISION_WITH_VERSION_PATCH, DBMS_TCP_PROTOCOL_VERSION,
+    },
+    Client, ClientOptions, Compression, ErrorCode, KlickhouseError, Progress, Result, Row,
+    ServerException, Type,
+};
+
+/// One step of the answer to an expected query.
+#[derive(Debug, Clone)]
+enum Reply {
+    Data(Block),
+    Progress(Progress),
+    Exception(ServerException),
+    Delay(Duration),
+}
+
+/// A query expected by a [`MockServer`], and the packets answering it.
+///
+/// Once a query matches, the server sends the replies in the order they were added, then ends the query.
+/// Queries ending with an exception don't get the replies added after it.
+pub struct Expectation {
+    description: String,
+    matcher: Box<dyn Fn(&str) -> bool + Send + Sync>,
+    insert_columns: Option<IndexMap<String, Type>>,
+    replies: Vec<Reply>,
+    times: Option<usize>,
+    matched: usize,
+}
+
+impl Expectation {
+    /// Matches queries equal to `query`, ignoring leading and trailing whitespace.
+    pub fn query(query: impl Into<String>) -> Self {
+        let query = query.into();
+        let expected = query.trim().to_string();
+        Self::matching(query, move |received| received.trim() == expected)
+    }
+
+    /// Matches queries containing `pattern`.
+    pub fn query_contains(pattern: impl Into<String>) -> Self {
+        let pattern = pattern.into();
+        let expected = pattern.clone();
+        Self::matching(format!("query containing {pattern}"), move |received| {
+            received.contains(&expected)
+        })
+    }
+
+    /// Matches queries for which `matcher` returns true. `description` names the expectation in
+    /// [`MockServer::verify`] failures.
+    pub fn matching(
+        description: impl Into<String>,
+        matcher: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Expectation {
+            description: description.into(),
+            matcher: Box::new(matcher),
+            insert_columns: None,
+            replies: vec![],
+            times: None,
+            matched: 0,
+        }
+    }
+
+    /// Answers the query like an insert into a table of `columns`: the server sends the header block of the
+    /// columns, and records the blocks sent by the client in [`ReceivedQuery::inserted`]. The replies are sent
+    /// once the client sent all its blocks.
+    pub fn accept_insert(
+        mut self,
+        columns: impl IntoIterator<Item = (impl Into<String>, Type)>,
+    ) -> Self {
+        self.insert_columns = Some(
+            columns
+                .into_iter()
+                .map(|(name, type_)| (name.into(), type_))
+                .collect(),
+        );
+        self
+    }
+
+    /// Sends `block` as a data block of the result.
+    pub fn respond(mut self, block: Block) -> Self {
+        self.replies.push(Reply::Data(block));
+        self
+    }
+
+    pub fn progress(mut self, progress: Progress) -> Self {
+        self.replies.push(Reply::Progress(progress));
+        self
+    }
+
+    /// Waits for `delay` before sending the next replies. A query cancelled by the client meanwhile ends right away.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.replies.push(Reply::Delay(delay));
+        self
+    }
+
+    /// Fails the query with `exception`.
+    pub fn exception(mut self, exception: ServerException) -> Self {
+        self.replies.push(Reply::Exception(exception));
+        self
+    }
+
+    /// Fails the query with a `DB::Exception` of `code`.
+    pub fn fail(self, code: ErrorCode, message: impl Into<String>) -> Self {
+        self.exception(ServerException {
+            code,
+            name: "DB::Exception".to_string(),
+            message: message.into(),
+            stack_trace: String::new(),
+            nested: None,
+        })
+    }
+
+    /// Matches `times` queries at most, and fails [`MockServer::verify`] unless exactly `times` queries matched.
+    /// Expectations match any number of queries otherwise.
+    pub fn times(mut self, times: usize) -> Self {
+        self.times = Some(times);
+        self
+    }
+}
+
+impl fmt::Debug for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Expectation")
+            .field("description", &self.description)
+            .field("insert_columns", &self.insert_columns)
+            .field("replies", &self.replies)
+            .field("times", &self.times)
+            .field("matched", &self.matched)
+            .finish()
+    }
+}
+
+/// A query received by a [`MockServer`].
+#[derive(Debug, Clone)]
+pub struct ReceivedQuery {
+    pub id: String,
+    pub query: String,
+    /// Settings sent with the query, including the ones of [`ClientOptions::settings`], in Clickhouse's text format.
+    pub settings: IndexMap<String, String>,
+    /// Query parameters, in the text format the server parses according to their type.
+    pub parameters: IndexMap<String, String>,
+    pub external_tables: IndexMap<String, Block>,
+    /// Blocks sent by the client, for expectations with [`Expectation::accept_insert`].
+    pub inserted: Vec<Block>,
+    /// Whether an expectation matched the query. Unexpected queries fail with a `NOT_IMPLEMENTED` exception.
+    pub matched: bool,
+}
+
+impl ReceivedQuery {
+    /// Deserializes the rows of the inserted blocks.
+    pub fn inserted_rows<T: Row>(&self) -> Result<Vec<T>> {
+        let mut rows = vec![];
+        for block in &self.inserted {
+            let mut block = block.clone();
+            for row in block.take_iter_rows() {
+                rows.push(T::deserialize_row(row)?);
+            }
+        }
+        Ok(rows)
+    }
+}
+
+/// What the server answers to a query, from the expectation matching it.
+struct Answer {
+    insert_columns: Option<IndexMap<String, Type>>,
+    replies: Vec<Reply>,
+}
+
+#[derive(Default)]
+struct MockState {
+    expectations: Vec<Expectation>,
+    queries: Vec<ReceivedQuery>,
+}
+
+impl MockState {
+    /// Records `query`, returning its index and the answer of the first expectation matching it.
+    fn receive(&mut self, mut query: ReceivedQuery) -> (usize, Option<Answer>) {
+        let expectation = self.expectations.iter_mut().find(|expectation| {
+            expectation
+                .times
+                .is_none_or(|times| expectation.matched < times)
+                && (expectation.matcher)(&query.query)
+        });
+        let answer = expectation.map(|expectation| {
+            expectation.matched += 1;
+            Answer {
+                insert_columns: expectation.insert_columns.clone(),
+                replies: expectation.replies.clone(),
+            }
+        });
+        query.matched = answer.is_some();
+        self.queries.push(query);
+        (self.queries.len() - 1, answer)
+    }
+}
+
+/// An in-process Clickhouse server speaking the native protocol, answering queries with scripted replies, to test
+/// code using [`Client`] without a Clickhouse server.
+///
+/// The server listens on a random port of the loopback interface until it is dropped, and accepts any credentials.
+/// Queries are answered by the first [`Expectation`] matching them, in the order they were added.
+pub struct MockServer {
+    address: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    pub async fn start() -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let task = tokio::spawn(accept(listener, state.clone()));
+        Ok(MockServer {
+            address,
+            state,
+            task,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Connects a client with the default options.
+    pub async fn client(&self) -> Result<Client> {
+        self.client_with_options(ClientOptions::default()).await
+    }
+
+    pub async fn client_with_options(&self, options: ClientOptions) -> Result<Client> {
+        Client::connect(self.address, options).await
+    }
+
+    /// Adds an expectation, checked after the ones added before it.
+    pub fn expect(&self, expectation: Expectation) -> &Self {
+        self.state.lock().unwrap().expectations.push(expectation);
+        self
+    }
+
+    /// Queries received so far, in order, on all connections.
+    pub fn queries(&self) -> Vec<ReceivedQuery> {
+        self.state.lock().unwrap().queries.clone()
+    }
+
+    /// Blocks inserted so far, in order, on all connections.
+    pub fn inserted_blocks(&self) -> Vec<Block> {
+        self.state
+            .lock()
+            .unwrap()
+            .queries
+            .iter()
+            .flat_map(|query| query.inserted.iter().cloned())
+            .collect()
+    }
+
+    /// Panics if a query matched no expectation, or if an expectation with [`Expectation::times`] didn't match
+    /// the expected number of queries.
+    pub fn verify(&self) {
+        let state = self.state.lock().unwrap();
+        let mut failures = vec![];
+        for expectation in &state.expectations {
+            if let Some(times) = expectation.times {
+                if expectation.matched != times {
+                    failures.push(format!(
+                        "expected {times} queries matching {}, received {}",
+                        expectation.description, expectation.matched
+                    ));
+                }
+            }
+        }
+        for query in state.queries.iter().filter(|query| !query.matched) {
+            failures.push(format!("unexpected query: {}", query.query));
+        }
+        assert!(
+            failures.is_empty(),
+            "mock server expectations not met:\n{}",
+            failures.join("\n")
+        );
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Serves the connections of a [`MockServer`], until it is dropped.
+async fn accept(listener: TcpListener, state: Arc<Mutex<MockState>>) {
+    // Dropped with the task, which aborts the sessions.
+    let mut sessions = JoinSet::new();
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                sessions.spawn(serve(stream, state.clone()));
+            }
+            Err(e) => warn!("mock server failed to accept connection: {e}"),
+        }
+        while sessions.try_join_next().is_some() {}
+    }
+}
+
+async fn serve(stream: TcpStream, state: Arc<Mutex<MockState>>) {
+    let result = match Session::handshake(stream, state).await {
+        Ok(mut session) => session.run().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        debug!("mock server connection closed: {e}");
+    }
+}
+
+/// A packet sent by the client after the handshake.
+#[derive(Debug)]
+enum ClientPacket {
+    Query {
+        query: ReceivedQuery,
+        compressed: bool,
+    },
+    Data {
+        table: String,
+        block: Block,
+    },
+    Cancel,
+    Ping,
+    TablesStatusRequest,
+}
+
+struct Session {
+    writer: OwnedWriteHalf,
+    packets: mpsc::Receiver<Result<ClientPacket>>,
+    reader: JoinHandle<()>,
+    revision: u64,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl Session {
+    /// Reads the client hello and answers it, then reads the client packets in a separate task, so that delays
+    /// can be interrupted by cancel packets.
+    async fn handshake(stream: TcpStream, state: Arc<Mutex<MockState>>) -> Result<Self> {
+        stream.set_nodelay(true)?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let packet_id = ClientPacketId::from_u64(reader.read_var_uint().await?)?;
+        if !matches!(packet_id, ClientPacketId::Hello) {
+            return Err(KlickhouseError::ProtocolError(format!(
+                "unexpected packet {packet_id:?}, expected client hello"
+            )));
+        }
+        let _client_name = reader.read_string().await?;
+        let _major_version = reader.read_var_uint().await?;
+        let _minor_version = reader.read_var_uint().await?;
+        let revision = reader.read_var_uint().await?.min(DBMS_TCP_PROTOCOL_VERSION);
+        let _database = reader.read_string().await?;
+        let username = reader.read_utf8_string().await?;
+        let _password = reader.read_string().await?;
+        if username == USER_INTERSERVER_MARKER {
+            let _cluster = reader.read_string().await?;
+            let _salt = reader.read_string().await?;
+        }
+
+        let mut hello = vec![];
+        hello.write_var_uint(ServerPacketId::Hello as u64).await?;
+        hello.write_string("ClickHouse").await?;
+        hello.write_var_uint(crate::VERSION_MAJOR).await?;
+        hello.write_var_uint(crate::VERSION_MINOR).await?;
+        hello.write_var_uint(DBMS_TCP_PROTOCOL_VERSION).await?;
+        if revision > DBMS_MIN_REVISION_WITH_SERVER_TIMEZONE {
+            hello.write_string("UTC").await?;
+        }
+        if revision > DBMS_MIN_REVISION_WITH_SERVER_DISPLAY_NAME {
+            hello.write_string("klickhouse-mock").await?;
+        }
+        if revision > DBMS_MIN_REVISION_WITH_VERSION_PATCH {
+            hello.write_var_uint(0).await?;
+        }
+        if revision >= DBMS_MIN_PROTOCOL_VERSION_WITH_PASSWORD_COMPLEXITY_RULES {
+            hello.write_var_uint(0).await?;
+        }
+        if revision >= DBMS_MIN_REVISION_WITH_INTERSERVER_SECRET_V2 {
+            hello.write_u64_le(0).await?;
+        }
+        writer.write_all(&hello).await?;
+        writer.flush().await?;
+        if revision >= DBMS_MIN_PROTOCOL_VERSION_WITH_ADDENDUM {
+            let _quota_key = reader.read_string().await?;
+        }
+
+        let (sender, packets) = mpsc::channel(16);
+        let reader = tokio::spawn(async move {
+            let mut compressed = false;
+            loop {
+                let packet = read_packet(&mut reader, revision, &mut compressed).await;
+                let failed = packet.is_err();
+                if sender.send(packet).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(Session {
+            writer,
+            packets,
+            reader,
+            revision,
+            state,
+        })
+    }
+
+    async fn run(&mut self) -> Result<()> {
+        loop {
+            match self.next_packet().await? {
+                ClientPacket::Query { query, compressed } => {
+                    self.run_query(query, compressed).await?
+                }
+                ClientPacket::Ping => {
+                    let mut out = vec![];
+                    out.write_var_uint(ServerPacketId::Pong as u64).await?;
+                    self.send(&out).await?;
+                }
+                ClientPacket::TablesStatusRequest => {
+                    let mut out = vec![];
+                    out.write_var_uint(ServerPacketId::TablesStatusResponse as u64)
+                        .await?;
+                    out.write_var_uint(0).await?;
+                    self.send(&out).await?;
+                }
+                // The query was already over when the cancel was sent.
+                ClientPacket::Cancel => (),
+                ClientPacket::Data { .. } => {
+                    return Err(KlickhouseError::ProtocolError(
+                        "received data block, but no executing query".to_string(),
+                    ))
+                }
+            }
+        }
+    }
+
+    async fn next_packet(&mut self) -> Result<ClientPacket> {
+        self.packets.recv().await.unwrap_or_else(|| {
+            Err(KlickhouseError::ConnectionError(
+                "connection reader stopped".to_string(),
+            ))
+        })
+    }
+
+    /// Reads data blocks until the empty block ending them, returning `None` if the query was cancelled.
+    async fn read_blocks(&mut self) -> Result<Option<Vec<(String, Block)>>> {
+        let mut blocks = vec![];
+        loop {
+            match self.next_packet().await? {
+                ClientPacket::Data { block, .. } if block.column_types.is_empty() => {
+                    return Ok(Some(blocks))
+                }
+                ClientPacket::Data { table, block } => blocks.push((table, block)),
+                ClientPacket::Cancel => return Ok(None),
+                packet => {
+                    return Err(KlickhouseError::ProtocolError(format!(
+                        "unexpected packet {packet:?}, expected data block"
+                    )))
+                }
+            }
+        }
+    }
+
+    async fn run_query(&mut self, mut query: ReceivedQuery, compressed: bool) -> Result<()> {
+        let Some(external_tables) = self.read_blocks().await? else {
+            return self.send_end_of_stream().await;
+        };
+        query.external_tables = external_tables.into_iter().collect();
+        let text = query.query.clone();
+        let (index, answer) = self.state.lock().unwrap().receive(query);
+        let Some(Answer {
+            insert_columns,
+            replies,
+        }) = answer
+        else {
+            let exception = ServerException {
+                code: ErrorCode::NotImplemented,
+                name: "DB::Exception".to_string(),
+                message: format!("no expectation matches query: {text}"),
+                stack_trace: String::new(),
+                nested: None,
+            };
+            return self.send_exception(&exception).await;
+        };
+
+        if let Some(columns) = insert_columns {
+            let header = Block {
+                info: BlockInfo::default(),
+                rows: 0,
+                column_data: columns.keys().map(|name| (name.clone(), vec![])).collect(),
+                column_types: columns,
+            };
+            self.send_data(header, compressed).await?;
+            let Some(blocks) = self.read_blocks().await? else {
+                return self.send_end_of_stream().await;
+            };
+            self.state.lock().unwrap().queries[index]
+                .inserted
+                .extend(blocks.into_iter().map(|(_, block)| block));
+        }
+
+        for reply in replies {
+            match reply {
+                Reply::Data(block) => self.send_data(block, compressed).await?,
+                Reply::Progress(progress) => self.send_progress(progress).await?,
+                Reply::Exception(exception) => return self.send_exception(&exception).await,
+                Reply::Delay(delay) => {
+                    select! {
+                        _ = tokio::time::sleep(delay) => (),
+                        packet = self.next_packet() => match packet? {
+                            ClientPacket::Cancel => return self.send_end_of_stream().await,
+                            packet => {
+                                return Err(KlickhouseError::ProtocolError(format!(
+                                    "unexpected packet {packet:?} while executing query"
+                                )))
+                            }
+                        },
+                    }
+                }
+            }
+        }
+        self.send_end_of_stream().await
+    }
+
+    async fn send(&mut self, packet: &[u8]) -> Result<()> {
+        self.writer.write_all(packet).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    async fn send_data(&mut self, block: Block, compressed: bool) -> Result<()> {
+        let encoding = BlockEncoding {
+            revision: self.revision,
+            compression: if compressed {
+                Compression::Lz4
+            } else {
+                Compression::None
+            },
+            blocking_threshold: None,
+        };
+        let mut out = vec![];
+        out.write_var_uint(ServerPacketId::Data as u64).await?;
+        out.write_string("").await?;
+        out.extend(encoding.encode(block).await?);
+        self.send(&out).await
+    }
+
+    async fn send_progress(&mut self, progress: Progress) -> Result<()> {
+        let mut out = vec![];
+        out.write_var_uint(ServerPacketId::Progress as u64).await?;
+        out.write_var_uint(progress.read_rows).await?;
+        out.write_var_uint(progress.read_bytes).await?;
+        out.write_var_uint(progress.new_total_rows_to_read).await?;
+        if self.revision >= DBMS_MIN_PROTOCOL_VERSION_WITH_TOTAL_BYTES_IN_PROGRESS {
+            out.write_var_uint(progress.new_total_bytes_to_read.unwrap_or_default())
+                .await?;
+        }
+        if self.revision >= DBMS_MIN_REVISION_WITH_CLIENT_WRITE_INFO {
+            out.write_var_uint(progress.new_written_rows.unwrap_or_default())
+                .await?;
+            out.write_var_uint(progress.new_written_bytes.unwrap_or_default())
+                .await?;
+        }
+        if self.revision >= DBMS_MIN_PROTOCOL_VERSION_WITH_SERVER_QUERY_TIME_IN_PROGRESS {
+            out.write_var_uint(progress.elapsed_ns.unwrap_or_default())
+                .await?;
+        }
+        self.send(&out).await
+    }
+
+    async fn send_exception(&mut self, exception: &ServerException) -> Result<()> {
+        let mut out = vec![];
+        out.write_var_uint(ServerPacketId::Exception as u64).await?;
+        for exception in exception.chain() {
+            out.write_i32_le(exception.code.code()).await?;
+            out.write_string(&exception.name).await?;
+            out.write_string(&exception.message).await?;
+            out.write_string(&exception.stack_trace).await?;
+            out.write_u8(exception.nested.is_some() as u8).await?;
+        }
+        self.send(&out).await
+    }
+
+    async fn send_end_of_stream(&mut self) -> Result<()> {
+        let mut out = vec![];
+        out.write_var_uint(ServerPacketId::EndOfStream as u64)
+            .await?;
+        self.send(&out).await
+    }
+}
+
+/// Reads a packet sent by the client. `compressed` is the compression of the executing query, set by its Query
+/// packet for the data blocks following it.
+async fn read_packet<R: ClickhouseRead + 'static>(
+    reader: &mut R,
+    revision: u64,
+    compressed: &mut bool,
+) -> Result<ClientPacket> {
+    Ok(
+        match ClientPacketId::from_u64(reader.read_var_uint().await?)? {
+            ClientPacketId::Query => {
+                let (query, query_compressed) = read_query(reader, revision).await?;
+                *compressed = query_compressed;
+                ClientPacket::Query {
+                    query,
+                    compressed: query_compressed,
+                }
+            }
+            ClientPacketId::Data | ClientPacketId::Scalar => {
+                let table = reader.read_utf8_string().await?;
+                let block = read_block(reader, revision, *compressed).await?;
+                ClientPacket::Data { table, block }
+            }
+            ClientPacketId::Cancel => ClientPacket::Cancel,
+            ClientPacketId::Ping => ClientPacket::Ping,
+            ClientPacketId::TablesStatusRequest => {
+                let size = reader.read_var_uint().await?;
+                for _ in 0..size {
+                    let _database = reader.read_string().await?;
+                    let _table = reader.read_string().await?;
+                }
+                ClientPacket::TablesStatusRequest
+            }
+            packet_id => {
+                return Err(KlickhouseError::ProtocolError(format!(
+                    "unsupported packet {packet_id:?} from client"
+                )))
+            }
+        },
+    )
+}
+
+/// Reads a Query packet, returning the query and whether its data blocks are compressed.
+async fn read_query<R: ClickhouseRead>(
+    reader: &mut R,
+    revision: u64,
+) -> Result<(ReceivedQuery, bool)> {
+    let id = reader.read_utf8_string().await?;
+    if revision >= DBMS_MIN_REVISION_WITH_CLIENT_INFO {
+        skip_client_info(reader, revision).await?;
+    }
+    let settings = read_settings(reader).await?;
+    if revision >= DBMS_MIN_REVISION_WITH_INTERSERVER_SECRET {
+        let _interserver_hash = reader.read_string().await?;
+    }
+    let _stage = reader.read_var_uint().await?;
+    let compressed = reader.read_u8().await? != 0;
+    let query = reader.read_utf8_string().await?;
+    let parameters = if revision >= DBMS_MIN_PROTOCOL_VERSION_WITH_PARAMETERS {
+        read_settings(reader)
+            .await?
+            .into_iter()
+            .map(|(name, value)| (name, unquote(&value)))
+            .collect()
+    } else {
+        IndexMap::new()
+    };
+    Ok((
+        ReceivedQuery {
+            id,
+            query,
+            settings,
+            parameters,
+            external_tables: IndexMap::new(),
+            inserted: vec![],
+            matched: false,
+        },
+        compressed,
+    ))
+}
+
+async fn skip_client_info<R: ClickhouseRead>(reader: &mut R, revision: u64) -> Result<()> {
+    // Query kind, no client info follows for `NoQuery`.
+    if reader.read_u8().await? == 0 {
+        return Ok(());
+    }
+    // Initial user, query id and address.
+    for _ in 0..3 {
+        reader.read_string().await?;
+    }
+    if revision >= DBMS_MIN_PROTOCOL_VERSION_WITH_INITIAL_QUERY_START_TIME {
+        reader.read_u64_le().await?;
+    }
+    // Interface, then OS user, client hostname and client name.
+    reader.read_u8().await?;
+    for _ in 0..3 {
+        reader.read_string().await?;
+    }
+    // Client version and revision.
+    for _ in 0..3 {
+        reader.read_var_uint().await?;
+    }
+    if revision >= DBMS_MIN_REVISION_WITH_QUOTA_KEY_IN_CLIENT_INFO {
+        reader.read_string().await?;
+    }
+    if revision >= DBMS_MIN_PROTOCOL_VERSION_WITH_DISTRIBUTED_DEPTH {
+        reader.read_var_uint().await?;
+    }
+    if revision >= DBMS_MIN_REVISION_WITH_VERSION_PATCH {
+        reader.read_var_uint().await?;
+    }
+    if revision >= DBMS_MIN_REVISION_WITH_OPENTELEMETRY && reader.read_u8().await? != 0 {
+        // Trace id, span id, trace state and flags.
+        reader.read_u128_le().await?;
+        reader.read_u64_le().await?;
+        reader.read_string().await?;
+        reader.read_u8().await?;
+    }
+    if revision >= DBMS_MIN_REVISION_WITH_PARALLEL_REPLICAS {
+        for _ in 0..3 {
+            reader.read_var_uint().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads settings serialized as strings, until the empty name ending them.
+async fn read_settings<R: ClickhouseRead>(reader: &mut R) -> Result<IndexMap<String, String>> {
+    let mut settings = IndexMap::new();
+    loop {
+        let name = reader.read_utf8_string().await?;
+        if name.is_empty() {
+            return Ok(settings);
+        }
+        let _flags = reader.read_var_uint().await?;
+        let value = reader.read_utf8_string().await?;
+        settings.insert(name, value);
+    }
+}
+
+/// Reverts the quoting of query parameters, sent as string literals.
+fn unquote(literal: &str) -> String {
+    let inner = literal
+        .strip_prefix('\'')
+        .and_then(|inner| inner.strip_suffix('\''))
+        .unwrap_or(literal);
+    let mut out = vec![];
+    let mut bytes = inner.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            out.push(byte);
+            continue;
+        }
+        let Some(escaped) = bytes.next() else {
+            break;
+        };
+        out.push(match escaped {
+            b'b' => 0x08,
+            b'f' => 0x0C,
+            b'r' => b'\r',
+            b'n' => b'\n',
+            b't' => b'\t',
+            b'0' => b'\0',
+            b'a' => 0x07,
+            b'v' => 0x0B,
+            b'x' => {
+                let digits: Vec<u8> = bytes.by_ref().take(2).collect();
+                std::str::from_utf8(&digits)
+                    .ok()
+                    .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                    .unwrap_or_default()
+            }
+            escaped => escaped,
+        });
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(any(feature = "compression", feature = "lz4_flex"))]
+async fn read_block<R: ClickhouseRead + 'static>(
+    reader: &mut R,
+    revision: u64,
+    compressed: bool,
+) -> Result<Block> {
+    if !compressed {
+        return Block::read(reader, revision).await;
+    }
+    let mut reader = crate::compression::DecompressionReader::new(reader, None);
+    Block::read(&mut reader, revision).await
+}
+
+#[cfg(not(any(feature = "compression", feature = "lz4_flex")))]
+async fn read_block<R: ClickhouseRead + 'static>(
+    reader: &mut R,
+    revision: u64,
+    compressed: bool,
+) -> Result<Block> {
+    if !compressed {
+        return Block::read(reader, revision).await;
+    }
+    Err(KlickhouseError::CompressionError(
+        "attempted to use compression when not compiled with `compression` feature in klickhouse"
+            .to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+
+    use super::*;
+    use crate::{ParsedQuery, QueryEvent, RawRow, RetryPolicy, UnitValue, Value};
+
+    fn numbers(values: &[u64]) -> Block {
+        Block {
+            info: BlockInfo::default(),
+            rows: values.len() as u64,
+            column_types: [("number".to_string(), Type::UInt64)].into_iter().collect(),
+            column_data: [(
+                "number".to_string(),
+                values.iter().map(|value| Value::UInt64(*value)).collect(),
+            )]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let server = MockServer::start().await.unwrap();
+        server.expect(
+            Expectation::query("SELECT number FROM numbers(3)")
+                .respond(numbers(&[0, 1]))
+                .respond(numbers(&[2]))
+                .times(1),
+        );
+        let client = server.client().await.unwrap();
+        let rows = client
+            .query_collect::<UnitValue<u64>>(" SELECT number FROM numbers(3) ")
+            .await
+            .unwrap();
+        assert_eq!(
+            rows.into_iter().map(|row| row.0).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        client.ping(Duration::from_secs(5)).await.unwrap();
+
+        let queries = server.queries();
+        assert_eq!(queries.len(), 1);
+        assert!(queries[0].matched);
+        server.verify();
+    }
+
+    #[tokio::test]
+    async fn test_exception() {
+        let server = MockServer::start().await.unwrap();
+        server.expect(Expectation::query_contains("missing_table").fail(
+            ErrorCode::UnknownTable,
+            "Table default.missing_table does not exist",
+        ));
+        let client = server.client().await.unwrap();
+        let error = client
+            .execute("SELECT * FROM missing_table")
+            .await
+            .unwrap_err();
+        assert_eq!(error.server_code(), Some(ErrorCode::UnknownTable));
+
+        let error = client.execute("SELECT 1").await.unwrap_err();
+        assert_eq!(error.server_code(), Some(ErrorCode::NotImplemented));
+        let result = std::panic::catch_unwind(|| server.verify());
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_progress() {
+        let server = MockServer::start().await.unwrap();
+        let progress = Progress {
+            read_rows: 10,
+            read_bytes: 80,
+            ..Default::default()
+        };
+        server.expect(
+            Expectation::query("SELECT 1")
+                .progress(progress)
+                .progress(progress)
+                .respond(numbers(&[1])),
+        );
+        let client = server.client().await.unwrap();
+        let mut handle = client.query_handle("SELECT 1").await.unwrap();
+        let mut progress_events = 0;
+        while let Some(event) = handle.next().await {
+            if let QueryEvent::Progress(_) = event.unwrap() {
+                progress_events += 1;
+            }
+        }
+        assert_eq!(progress_events, 2);
+        assert_eq!(handle.progress().read_rows, 20);
+    }
+
+    #[tokio::test]
+    async fn test_progress_dropped_events() {
+        let server = MockServer::start().await.unwrap();
+        let progress = Progress {
+            read_rows: 10,
+            ..Default::default()
+        };
+        let mut expectation = Expectation::query("SELECT 1");
+        for _ in 0..5 {
+            expectation = expectation.progress(progress);
+        }
+        server.expect(expectation.respond(numbers(&[1])));
+        let options = ClientOptions {
+            block_channel_size: 1,
+            ..Default::default()
+        };
+        let client = server.client_with_options(options).await.unwrap();
+        let handle = client.query_handle("SELECT 1").await.unwrap();
+        // Let the events overflow the channel before reading any of them.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let output = handle.collect::<RawRow>().await.unwrap();
+        assert_eq!(output.progress.read_rows, 50);
+    }
+
+    #[tokio::test]
+    async fn test_delay_cancelled() {
+        let server = MockServer::start().await.unwrap();
+        server.expect(
+            Expectation::query("SELECT sleep(3)")
+                .delay(Duration::from_secs(60))
+                .respond(numbers(&[0])),
+        );
+        let options = ClientOptions {
+            query_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let client = server.client_with_options(options).await.unwrap();
+        let result = client.execute("SELECT sleep(3)").await;
+        assert!(matches!(result, Err(KlickhouseError::Timeout(_))));
+        // The server ended the query on cancel, the connection is usable again.
+        client.ping(Duration::from_secs(5)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let server = MockServer::start().await.unwrap();
+        server
+            .expect(
+                Expectation::query("SELECT 1")
+                    .fail(ErrorCode::TooManySimultaneousQueries, "too many queries")
+                    .times(2),
+            )
+            .expect(Expectation::query("SELECT 1").respond(numbers(&[1])))
+            .expect(Expectation::query("SELECT 2").fail(ErrorCode::TimeoutExceeded, "timeout"))
+            .expect(
+                Expectation::query_contains("INSERT INTO events")
+                    .fail(ErrorCode::TooManyParts, "too many parts"),
+            );
+        let options = ClientOptions {
+            retry: Some(RetryPolicy {
+                initial_backoff: Duration::from_millis(1),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let client = server.client_with_options(options).await.unwrap();
+        client.execute("SELECT 1").await.unwrap();
+        assert_eq!(server.queries().len(), 3);
+        // Succeeded on the third attempt.
+        assert_eq!(client.retried_attempts(), 2);
+
+        let error = client.execute("SELECT 2").await.unwrap_err();
+        assert_eq!(error.server_code(), Some(ErrorCode::TimeoutExceeded));
+        assert_eq!(server.queries().len(), 4);
+        assert_eq!(client.retried_attempts(), 2);
+
+        // Inserts without deduplication token are not retried.
+        let error = client
+            .execute("INSERT INTO events VALUES (1)")
+            .await
+            .unwrap_err();
+        assert_eq!(error.server_code(), Some(ErrorCode::TooManyParts));
+        assert_eq!(server.queries().len(), 5);
+        server.verify();
+    }
+
+    #[tokio::test]
+    async fn test_retry_insert() {
+        let server = MockServer::start().await.unwrap();
+        server
+            .expect(
+                Expectation::query_contains("INSERT INTO events")
+                    .accept_insert([("number", Type::UInt64)])
+                    .fail(ErrorCode::TooManyParts, "too many parts")
+                    .times(1),
+            )
+            .expect(
+                Expectation::query_contains("INSERT INTO events")
+                    .accept_insert([("number", Type::UInt64)])
+                    .times(1),
+            );
+        let options = ClientOptions {
+            retry: Some(RetryPolicy {
+                initial_backoff: Duration::from_millis(1),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let client = server.client_with_options(options).await.unwrap();
+        let mut row = RawRow::default();
+        row.set("number", 1u64);
+        let query = ParsedQuery::new("INSERT INTO events FORMAT Native")
+            .with_setting("insert_deduplication_token", "batch-1");
+        client.insert_native_block(&query, vec![row]).await.unwrap();
+
+        // The exception sent after the data failed the first attempt.
+        let queries = server.queries();
+        assert_eq!(queries.len(), 2);
+        for query in &queries {
+            let rows = query.inserted_rows::<UnitValue<u64>>().unwrap();
+            assert_eq!(
+                rows.into_iter().map(|row| row.0).collect::<Vec<_>>(),
+                vec![1]
+            );
+        }
+        server.verify();
+    }
+
+    #[tokio::test]
+    async fn test_insert() {
+        let server = MockServer::start().await.unwrap();
+        server.expect(
+            Expectation::query_contains("INSERT INTO events")
+                .accept_insert([("number", Type::UInt64)])
+                .times(1),
+        );
+        let client = server.client().await.unwrap();
+        let blocks = futures_util::stream::iter(vec![numbers(&[1, 2])]);
+        let mut response = client
+            .insert_native_raw("INSERT INTO events FORMAT Native", blocks)
+            .await
+            .unwrap();
+        // The header block, then the end of the query.
+        assert_eq!(response.next().await.unwrap().unwrap().rows, 0);
+        assert!(response.next().await.is_none());
+
+        let queries = server.queries();
+        let rows = queries[0].inserted_rows::<UnitValue<u64>>().unwrap();
+        assert_eq!(
+            rows.into_iter().map(|row| row.0).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(server.inserted_blocks().len(), 1);
+        server.verify();
+    }
+
+    #[tokio::test]
+    async fn test_settings_and_parameters() {
+        let server = MockServer::start().await.unwrap();
+        server.expect(Expectation::query_contains("SELECT"));
+        let options = ClientOptions {
+            compression: Compression::None,
+            ..Default::default()
+        };
+        let client = server.client_with_options(options).await.unwrap();
+        let query = crate::ParsedQuery::new("SELECT {name:String}")
+            .with_setting("max_threads", 2u64)
+            .with_param("name", "it's")
+            .unwrap();
+        client.execute(&query).await.unwrap();
+
+        let queries = server.queries();
+        assert_eq!(
+            queries[0].settings.get("max_threads").map(String::as_str),
+            Some("2")
+        );
+        assert_eq!(
+            queries[0].parameters.get("name").map(String::as_str),
+            Some("it\\'s")
+        );
+        server.verify();
+    }
+}
@@ -0,0 +1,270 @@
+use std::{fmt, net::IpAddr, path::PathBuf, sync::Arc};
+
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::{
+    rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{aws_lc_rs, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+        ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    },
+    TlsConnector,
+};
+
+use crate::{KlickhouseError, Result};
+
+/// PEM encoded certificates or key, read from a file or given in memory.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Pem {
+    File(PathBuf),
+    Data(Vec<u8>),
+}
+
+impl Pem {
+    pub fn file(path: impl Into<PathBuf>) -> Self {
+        Pem::File(path.into())
+    }
+
+    pub fn data(data: impl Into<Vec<u8>>) -> Self {
+        Pem::Data(data.into())
+    }
+
+    fn certificates(&self) -> Result<Vec<CertificateDer<'static>>> {
+        let certificates = match self {
+            Pem::File(path) => CertificateDer::pem_file_iter(path)
+                .map_err(|e| self.error(e))?
+                .collect::<Result<Vec<_>, _>>(),
+            Pem::Data(data) => CertificateDer::pem_slice_iter(data).collect(),
+        }
+        .map_err(|e| self.error(e))?;
+        if certificates.is_empty() {
+            return Err(self.error("no certificate found"));
+        }
+        Ok(certificates)
+    }
+
+    fn private_key(&self) -> Result<PrivateKeyDer<'static>> {
+        match self {
+            Pem::File(path) => PrivateKeyDer::from_pem_file(path),
+            Pem::Data(data) => PrivateKeyDer::from_pem_slice(data),
+        }
+        .map_err(|e| self.error(e))
+    }
+
+    fn error(&self, error: impl fmt::Display) -> KlickhouseError {
+        KlickhouseError::ConnectionError(format!("invalid PEM {self:?}: {error}"))
+    }
+}
+
+// Keys given in memory must not end up in logs.
+impl fmt::Debug for Pem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pem::File(path) => f.debug_tuple("File").field(path).finish(),
+            Pem::Data(data) => write!(f, "Data({} bytes)", data.len()),
+        }
+    }
+}
+
+/// TLS settings of the connections opened with [`crate::Client::connect`] and [`crate::ConnectionManager`], see
+/// [`crate::ClientOptions::tls`]. Clickhouse listens for TLS connections on port 9440 by default.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TlsOptions {
+    /// Certificate authorities trusted to sign the server certificate. The Mozilla root certificates of
+    /// `webpki-roots` when not set.
+    pub ca_certificates: Option<Pem>,
+    /// Certificate chain presented to the server for mutual TLS, along with `client_key`.
+    pub client_certificate: Option<Pem>,
+    pub client_key: Option<Pem>,
+    /// Name sent as SNI and checked against the server certificate. Defaults to the host name of the address
+    /// connected to, and to the IP address of the server when connecting to an IP address.
+    pub server_name: Option<String>,
+    /// Accepts any server certificate. Only meant for testing, as it allows anyone on the path to the server
+    /// to intercept the connection.
+    pub insecure_skip_verify: bool,
+}
+
+impl TlsOptions {
+    /// Builds the rustls connector of these options.
+    pub(crate) fn connector(&self) -> Result<TlsConnector> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_certificates {
+            Some(pem) => {
+                for certificate in pem.certificates()? {
+                    roots.add(certificate).map_err(|e| pem.error(e))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        // `ClientConfig::builder` panics when several providers are compiled in and none was installed.
+        let provider = CryptoProvider::get_default()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(aws_lc_rs::default_provider()));
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| KlickhouseError::ConnectionError(format!("invalid TLS provider: {e}")))?
+            .with_root_certificates(roots);
+        let mut config = match (&self.client_certificate, &self.client_key) {
+            (Some(certificate), Some(key)) => builder
+                .with_client_auth_cert(certificate.certificates()?, key.private_key()?)
+                .map_err(|e| {
+                    KlickhouseError::ConnectionError(format!("invalid client certificate: {e}"))
+                })?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => {
+                return Err(KlickhouseError::ConnectionError(
+                    "mutual TLS requires both a client certificate and a client key".to_string(),
+                ))
+            }
+        };
+        if self.insecure_skip_verify {
+            let provider = config.crypto_provider().clone();
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(SkipServerVerification(provider)));
+        }
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+
+    /// Name of the server to connect to: `server_name` if set, then `host` and `server_ip`.
+    pub(crate) fn server_name(
+        &self,
+        host: Option<&str>,
+        server_ip: IpAddr,
+    ) -> Result<ServerName<'static>> {
+        match self.server_name.as_deref().or(host) {
+            Some(name) => ServerName::try_from(name.to_string()).map_err(|e| {
+                KlickhouseError::ConnectionError(format!("invalid TLS server name {name}: {e}"))
+            }),
+            None => Ok(ServerName::IpAddress(server_ip.into())),
+        }
+    }
+}
+
+/// Certificate verifier of [`TlsOptions::insecure_skip_verify`], that still checks the handshake signatures.
+#[derive(Debug)]
+struct SkipServerVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_connector() {
+        TlsOptions::default().connector().unwrap();
+        TlsOptions {
+            insecure_skip_verify: true,
+            ..Default::default()
+        }
+        .connector()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_invalid_pem() {
+        let options = TlsOptions {
+            ca_certificates: Some(Pem::data("not a certificate")),
+            ..Default::default()
+        };
+        assert!(options.connector().is_err());
+        let options = TlsOptions {
+            ca_certificates: Some(Pem::file("/nonexistent/ca.pem")),
+            ..Default::default()
+        };
+        assert!(options.connector().is_err());
+    }
+
+    #[test]
+    fn test_client_key_without_certificate() {
+        let options = TlsOptions {
+            client_key: Some(Pem::data("key")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            options.connector(),
+            Err(KlickhouseError::ConnectionError(_))
+        ));
+    }
+
+    #[test]
+    fn test_server_name() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let options = TlsOptions::default();
+        assert_eq!(
+            options.server_name(None, ip).unwrap(),
+            ServerName::IpAddress(ip.into())
+        );
+        assert_eq!(
+            options
+                .server_name(Some("ch-1.example.com"), ip)
+                .unwrap()
+                .to_str(),
+            "ch-1.example.com"
+        );
+        let options = TlsOptions {
+            server_name: Some("clickhouse.example.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            options
+                .server_name(Some("ch-1.example.com"), ip)
+                .unwrap()
+                .to_str(),
+            "clickhouse.example.com"
+        );
+        let options = TlsOptions {
+            server_name: Some("not a name".to_string()),
+            ..Default::default()
+        };
+        assert!(options.server_name(None, ip).is_err());
+    }
+
+    #[test]
+    fn test_pem_debug_hides_data() {
+        assert_eq!(format!("{:?}", Pem::data("secret")), "Data(6 bytes)");
+    }
+}
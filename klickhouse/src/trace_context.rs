@@ -0,0 +1,154 @@
+use std::fmt;
+
+use tokio::io::AsyncWriteExt;
+
+use crate::{io::ClickhouseWrite, KlickhouseError, Result};
+
+/// Trace flag of sampled traces.
+const TRACE_FLAG_SAMPLED: u8 = 0x01;
+
+/// A W3C trace context sent with a query, so that its spans in `system.opentelemetry_span_log` are children of
+/// the caller's span.
+///
+/// Attach it with [`crate::ParsedQuery::with_trace_context`] or [`crate::QueryBuilder::trace_context`]. With the
+/// `opentelemetry` feature, queries without a trace context use the one of the current `tracing` span.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    /// Id of the caller's span, parent of the server spans.
+    pub span_id: u64,
+    /// Vendor-specific trace state, as in the `tracestate` header.
+    pub tracestate: String,
+    pub trace_flags: u8,
+}
+
+impl TraceContext {
+    /// A sampled trace context, without trace state.
+    pub fn new(trace_id: u128, span_id: u64) -> Self {
+        Self {
+            trace_id,
+            span_id,
+            tracestate: String::new(),
+            trace_flags: TRACE_FLAG_SAMPLED,
+        }
+    }
+
+    pub fn with_tracestate(mut self, tracestate: impl Into<String>) -> Self {
+        self.tracestate = tracestate.into();
+        self
+    }
+
+    /// Parses a `traceparent` header, i.e. `00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01`.
+    pub fn from_traceparent(traceparent: &str) -> Result<Self> {
+        let invalid =
+            || KlickhouseError::ProtocolError(format!("invalid traceparent: {traceparent}"));
+        let mut parts = traceparent.trim().split('-');
+        let (Some(version), Some(trace_id), Some(span_id), Some(flags)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        if version != "00" || parts.next().is_some() {
+            return Err(invalid());
+        }
+        if trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+            return Err(invalid());
+        }
+        let context = Self {
+            trace_id: u128::from_str_radix(trace_id, 16).map_err(|_| invalid())?,
+            span_id: u64::from_str_radix(span_id, 16).map_err(|_| invalid())?,
+            tracestate: String::new(),
+            trace_flags: u8::from_str_radix(flags, 16).map_err(|_| invalid())?,
+        };
+        if context.trace_id == 0 || context.span_id == 0 {
+            return Err(invalid());
+        }
+        Ok(context)
+    }
+
+    /// Formats this context as a `traceparent` header.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.trace_flags
+        )
+    }
+
+    /// Trace context of the current `tracing` span, if it belongs to a valid OpenTelemetry trace.
+    #[cfg(feature = "opentelemetry")]
+    pub fn current() -> Option<Self> {
+        use opentelemetry::trace::TraceContextExt;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let context = tracing::Span::current().context();
+        let span = context.span();
+        let span_context = span.span_context();
+        if !span_context.is_valid() {
+            return None;
+        }
+        Some(Self {
+            trace_id: u128::from_be_bytes(span_context.trace_id().to_bytes()),
+            span_id: u64::from_be_bytes(span_context.span_id().to_bytes()),
+            tracestate: span_context.trace_state().header(),
+            trace_flags: span_context.trace_flags().to_u8(),
+        })
+    }
+
+    /// Writes the trace context of a ClientInfo. The trace id is laid out like a UUID.
+    pub(crate) async fn write<W: ClickhouseWrite>(&self, to: &mut W) -> Result<()> {
+        to.write_u64_le((self.trace_id >> 64) as u64).await?;
+        to.write_u64_le(self.trace_id as u64).await?;
+        to.write_u64_le(self.span_id).await?;
+        to.write_string(&self.tracestate).await?;
+        to.write_u8(self.trace_flags).await?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.traceparent())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn test_traceparent_roundtrip() {
+        let context = TraceContext::from_traceparent(TRACEPARENT).unwrap();
+        assert_eq!(context.trace_id, 0x0af7651916cd43dd8448eb211c80319c);
+        assert_eq!(context.span_id, 0xb7ad6b7169203331);
+        assert_eq!(context.trace_flags, TRACE_FLAG_SAMPLED);
+        assert_eq!(context.traceparent(), TRACEPARENT);
+    }
+
+    #[test]
+    fn test_invalid_traceparent() {
+        for traceparent in [
+            "",
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b71692033zz-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-00",
+        ] {
+            assert!(TraceContext::from_traceparent(traceparent).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write() {
+        let context = TraceContext::new(0x0102030405060708_090a0b0c0d0e0f10, 0x1112131415161718)
+            .with_tracestate("k=v");
+        let mut buf: Vec<u8> = vec![];
+        context.write(&mut buf).await.unwrap();
+        let mut expected = vec![8, 7, 6, 5, 4, 3, 2, 1, 16, 15, 14, 13, 12, 11, 10, 9];
+        expected.extend_from_slice(&[0x18, 0x17, 0x16, 0x15, 0x14, 0x13, 0x12, 0x11]);
+        expected.extend_from_slice(&[3, b'k', b'=', b'v', TRACE_FLAG_SAMPLED]);
+        assert_eq!(buf, expected);
+    }
+}
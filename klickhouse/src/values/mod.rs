@@ -315,6 +315,143 @@ impl Value {
     }
 }
 
+/// Formats a [`Value`] as the text Clickhouse parses typed query parameters (`{name:Type}`) from.
+/// Top-level values use the escaped text format, values nested in arrays, tuples or maps are quoted.
+struct ParameterText<'a> {
+    value: &'a Value,
+    nested: bool,
+}
+
+impl ParameterText<'_> {
+    fn nested(value: &Value) -> ParameterText<'_> {
+        ParameterText {
+            value,
+            nested: true,
+        }
+    }
+
+    fn write_quoted(&self, f: &mut fmt::Formatter<'_>, text: impl fmt::Display) -> fmt::Result {
+        if self.nested {
+            write!(f, "'{text}'")
+        } else {
+            write!(f, "{text}")
+        }
+    }
+
+    fn write_point(f: &mut fmt::Formatter<'_>, point: &Point) -> fmt::Result {
+        write!(f, "({},{})", point.0[0], point.0[1])
+    }
+
+    fn write_list<T>(
+        f: &mut fmt::Formatter<'_>,
+        items: &[T],
+        mut write_item: impl FnMut(&mut fmt::Formatter<'_>, &T) -> fmt::Result,
+    ) -> fmt::Result {
+        write!(f, "[")?;
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write_item(f, item)?;
+        }
+        write!(f, "]")
+    }
+
+    fn write_ring(f: &mut fmt::Formatter<'_>, ring: &Ring) -> fmt::Result {
+        Self::write_list(f, &ring.0, Self::write_point)
+    }
+
+    fn write_polygon(f: &mut fmt::Formatter<'_>, polygon: &Polygon) -> fmt::Result {
+        Self::write_list(f, &polygon.0, Self::write_ring)
+    }
+}
+
+impl fmt::Display for ParameterText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            Value::String(string) => {
+                if self.nested {
+                    write!(f, "'")?;
+                }
+                escape_string(f, string)?;
+                if self.nested {
+                    write!(f, "'")?;
+                }
+                Ok(())
+            }
+            Value::Uuid(uuid) => self.write_quoted(f, uuid),
+            Value::Date(date) => {
+                let chrono_date: NaiveDate = (*date).into();
+                self.write_quoted(f, chrono_date.format("%Y-%m-%d"))
+            }
+            // Unix timestamps are accepted for any `DateTime` timezone.
+            Value::DateTime(datetime) => self.write_quoted(f, datetime.1),
+            Value::DateTime64(datetime) => {
+                let scale = 10u64.pow(datetime.2 as u32);
+                if datetime.2 == 0 {
+                    self.write_quoted(f, datetime.1)
+                } else {
+                    self.write_quoted(
+                        f,
+                        format_args!(
+                            "{}.{:0width$}",
+                            datetime.1 / scale,
+                            datetime.1 % scale,
+                            width = datetime.2
+                        ),
+                    )
+                }
+            }
+            Value::Ipv4(ipv4) => self.write_quoted(f, ipv4),
+            Value::Ipv6(ipv6) => self.write_quoted(f, ipv6),
+            Value::Null if self.nested => write!(f, "NULL"),
+            Value::Null => write!(f, "\\N"),
+            Value::Array(array) => {
+                Self::write_list(f, array, |f, item| write!(f, "{}", Self::nested(item)))
+            }
+            Value::Tuple(tuple) => {
+                write!(f, "(")?;
+                for (i, item) in tuple.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", Self::nested(item))?;
+                }
+                write!(f, ")")
+            }
+            Value::Map(keys, values) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in keys.iter().zip(values.iter()).enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", Self::nested(key), Self::nested(value))?;
+                }
+                write!(f, "}}")
+            }
+            Value::Point(point) => Self::write_point(f, point),
+            Value::Ring(ring) => Self::write_ring(f, ring),
+            Value::Polygon(polygon) => Self::write_polygon(f, polygon),
+            Value::MultiPolygon(multi_polygon) => {
+                Self::write_list(f, &multi_polygon.0, Self::write_polygon)
+            }
+            // Numbers, decimals and enum values are written the same way as SQL literals.
+            value => write!(f, "{value}"),
+        }
+    }
+}
+
+impl Value {
+    /// Formats this value as the text of a server-side query parameter.
+    pub(crate) fn to_parameter_text(&self) -> String {
+        ParameterText {
+            value: self,
+            nested: false,
+        }
+        .to_string()
+    }
+}
+
 fn escape_string(f: &mut fmt::Formatter<'_>, from: impl AsRef<[u8]>) -> fmt::Result {
     let from = from.as_ref();
     for byte in from.iter().copied() {
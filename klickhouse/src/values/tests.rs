@@ -517,3 +517,43 @@ async fn roundtrip_geo() {
         &roundtrip(multipolygon.clone(), &Type::MultiPolygon)
     );
 }
+
+#[test]
+fn parameter_text() {
+    assert_eq!(Value::UInt64(42).to_parameter_text(), "42");
+    assert_eq!(Value::Int32(-7).to_parameter_text(), "-7");
+    assert_eq!(
+        Value::string("a'b\\c\td").to_parameter_text(),
+        "a\\'b\\\\c\\td"
+    );
+    assert_eq!(Value::Null.to_parameter_text(), "\\N");
+    assert_eq!(Value::Date(Date(19000)).to_parameter_text(), "2022-01-08");
+    assert_eq!(
+        Value::DateTime(DateTime(UTC, 1_700_000_000)).to_parameter_text(),
+        "1700000000"
+    );
+    assert_eq!(
+        Value::DateTime64(DateTime64::<3>(UTC, 1_700_000_000_042).into()).to_parameter_text(),
+        "1700000000.042"
+    );
+    assert_eq!(
+        Value::Uuid(Uuid::nil()).to_parameter_text(),
+        "00000000-0000-0000-0000-000000000000"
+    );
+    assert_eq!(
+        Value::Array(vec![Value::string("x"), Value::Null]).to_parameter_text(),
+        "['x',NULL]"
+    );
+    assert_eq!(
+        Value::Tuple(vec![Value::UInt8(1), Value::Date(Date(0))]).to_parameter_text(),
+        "(1,'1970-01-01')"
+    );
+    assert_eq!(
+        Value::Map(vec![Value::string("k")], vec![Value::UInt32(2)]).to_parameter_text(),
+        "{'k':2}"
+    );
+    assert_eq!(
+        Value::Ring(Ring(vec![Point([1.0, 2.5])])).to_parameter_text(),
+        "[(1,2.5)]"
+    );
+}
@@ -1,6 +1,11 @@
 pub mod test;
 pub mod test_bytes;
+pub mod test_cancel;
+pub mod test_client_info;
+#[cfg(any(feature = "compression", feature = "lz4_flex"))]
+pub mod test_compression;
 pub mod test_decimal;
+pub mod test_external_tables;
 
 pub mod test_bigdecimal;
 pub mod test_flatten;
@@ -9,31 +14,35 @@ pub mod test_geo;
 pub mod test_lock;
 pub mod test_nested;
 pub mod test_ordering;
+pub mod test_ping;
+pub mod test_query_handle;
 pub mod test_raw_string;
 pub mod test_safety;
 pub mod test_serialize;
-
-use klickhouse::{Client, ClientOptions};
-
-pub async fn get_client() -> Client {
-    let mut options = ClientOptions::default();
-
-    if let Ok(user) = std::env::var("KLICKHOUSE_TEST_USER") {
-        options.username = user;
-    }
-
-    if let Ok(password) = std::env::var("KLICKHOUSE_TEST_PASSWORD") {
-        options.password = password;
+pub mod test_settings;
+pub mod test_tables_status;
+pub mod test_timeout;
+
+use klickhouse::{Client, Dsn};
+
+/// Test server and options, from the `KLICKHOUSE_TEST_*` variables, i.e. `KLICKHOUSE_TEST_URL`. The server is
+/// `KLICKHOUSE_TEST_ADDR`, or `127.0.0.1:9000`, unless `KLICKHOUSE_TEST_HOST` or the URL sets it.
+pub fn test_dsn() -> Dsn {
+    let mut dsn = Dsn::from_env_with_prefix("KLICKHOUSE_TEST_").unwrap();
+    if std::env::var_os("KLICKHOUSE_TEST_HOST").is_none()
+        && std::env::var_os("KLICKHOUSE_TEST_URL").is_none()
+    {
+        let address =
+            std::env::var("KLICKHOUSE_TEST_ADDR").unwrap_or_else(|_| "127.0.0.1:9000".into());
+        dsn.hosts = vec![address];
     }
+    dsn
+}
 
-    if let Ok(database) = std::env::var("KLICKHOUSE_TEST_DATABASE") {
-        options.default_database = database;
-    }
-
-    let address = std::env::var("KLICKHOUSE_TEST_ADDR").unwrap_or_else(|_| "127.0.0.1:9000".into());
-
-    Client::connect(address, options).await.unwrap()
+pub async fn get_client() -> Client {
+    test_dsn().connect().await.unwrap()
 }
+
 /// Drop the table if it exists, and create it with the given structure.
 /// Make sure to use distinct table names across tests to avoid conflicts between tests executing
 /// simultaneously.
@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use klickhouse::{KillQueryStatus, ParsedQuery, RawRow, Row, Uuid};
+
+#[derive(Row, Debug)]
+struct NumRow {
+    n: u64,
+}
+
+#[tokio::test]
+async fn test_dropped_stream_cancels_query() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    // An unbounded query that would never finish on its own.
+    let mut stream = client
+        .query::<RawRow>("SELECT number FROM system.numbers")
+        .await
+        .unwrap();
+    stream.next().await.unwrap().unwrap();
+    drop(stream);
+
+    // The next query must not be stalled behind the abandoned one.
+    let row: NumRow = tokio::time::timeout(
+        Duration::from_secs(10),
+        client.query_one("SELECT toUInt64(42) AS n"),
+    )
+    .await
+    .expect("query stalled behind abandoned query")
+    .unwrap();
+    assert_eq!(row.n, 42);
+    assert!(!client.is_closed());
+}
+
+#[tokio::test]
+async fn test_query_one_cancels_remaining_rows() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    for _ in 0..3 {
+        let row: NumRow = tokio::time::timeout(
+            Duration::from_secs(10),
+            client.query_one("SELECT number AS n FROM system.numbers"),
+        )
+        .await
+        .expect("query_one stalled on unbounded query")
+        .unwrap();
+        assert_eq!(row.n, 0);
+    }
+}
+
+#[tokio::test]
+async fn test_kill_query_by_id() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    let id = Uuid::new_v4().to_string();
+    let query =
+        ParsedQuery::new("SELECT sleepEachRow(1) FROM numbers(60) SETTINGS max_block_size = 1")
+            .with_query_id(&id);
+    let mut stream = client.query::<RawRow>(&query).await.unwrap();
+
+    // The query may not be registered in system.processes yet.
+    let status = loop {
+        match client.kill_query(&id).await.unwrap() {
+            KillQueryStatus::NotFound | KillQueryStatus::Pending => {
+                tokio::time::sleep(Duration::from_millis(100)).await
+            }
+            status => break status,
+        }
+    };
+    assert_eq!(status, KillQueryStatus::Waiting);
+
+    let result = tokio::time::timeout(Duration::from_secs(10), async {
+        while let Some(row) = stream.next().await {
+            row?;
+        }
+        Ok::<_, klickhouse::KlickhouseError>(())
+    })
+    .await
+    .expect("killed query kept running");
+    assert!(result.is_err());
+    assert_eq!(
+        client
+            .kill_query(&Uuid::new_v4().to_string())
+            .await
+            .unwrap(),
+        KillQueryStatus::NotFound
+    );
+}
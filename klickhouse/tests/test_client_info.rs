@@ -0,0 +1,44 @@
+use klickhouse::{ClientOptions, Dsn, ParsedQuery, Row};
+
+#[derive(Row, Debug)]
+struct ClientInfoRow {
+    client_name: String,
+    client_hostname: String,
+    os_user: String,
+    quota_key: String,
+}
+
+const QUERY: &str = "SELECT client_name, client_hostname, os_user, quota_key FROM system.processes WHERE query_id = queryID()";
+
+#[tokio::test]
+async fn test_client_info() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let options = ClientOptions {
+        client_name: "klickhouse-test".to_string(),
+        client_hostname: "test-host".to_string(),
+        os_user: "tester".to_string(),
+        quota_key: "tenant-1".to_string(),
+        ..Default::default()
+    };
+    let client = Dsn {
+        options,
+        ..super::test_dsn()
+    }
+    .connect()
+    .await
+    .unwrap();
+
+    let row: ClientInfoRow = client.query_one(QUERY).await.unwrap();
+    assert_eq!(row.client_name, "klickhouse-test");
+    assert_eq!(row.client_hostname, "test-host");
+    assert_eq!(row.os_user, "tester");
+    assert_eq!(row.quota_key, "tenant-1");
+
+    let row: ClientInfoRow = client
+        .query_one(&ParsedQuery::new(QUERY).with_quota_key("tenant-2"))
+        .await
+        .unwrap();
+    assert_eq!(row.quota_key, "tenant-2");
+}
@@ -0,0 +1,58 @@
+use klickhouse::{ClientOptions, Compression, Dsn, Row};
+
+#[derive(Row, Debug, PartialEq, Clone)]
+struct CompressedRow {
+    id: u64,
+    name: String,
+}
+
+async fn roundtrip(compression: Compression, table: &str) {
+    let options = ClientOptions {
+        compression,
+        ..Default::default()
+    };
+    let client = Dsn {
+        options,
+        ..super::test_dsn()
+    }
+    .connect()
+    .await
+    .unwrap();
+    super::prepare_table(table, "id UInt64, name String", &client).await;
+
+    let rows = (0..10_000u64)
+        .map(|id| CompressedRow {
+            id,
+            name: format!("row {id}"),
+        })
+        .collect::<Vec<_>>();
+    client
+        .insert_native_block(
+            format!("INSERT INTO {table} (id, name) FORMAT Native"),
+            rows.clone(),
+        )
+        .await
+        .unwrap();
+
+    let read = client
+        .query_collect::<CompressedRow>(format!("SELECT id, name FROM {table} ORDER BY id"))
+        .await
+        .unwrap();
+    assert_eq!(read, rows);
+}
+
+#[tokio::test]
+async fn test_compression_none() {
+    roundtrip(Compression::None, "test_compression_none").await;
+}
+
+#[tokio::test]
+async fn test_compression_lz4hc() {
+    roundtrip(Compression::Lz4Hc(9), "test_compression_lz4hc").await;
+}
+
+#[cfg(feature = "zstd")]
+#[tokio::test]
+async fn test_compression_zstd() {
+    roundtrip(Compression::Zstd(3), "test_compression_zstd").await;
+}
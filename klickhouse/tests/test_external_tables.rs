@@ -0,0 +1,51 @@
+use klickhouse::{ExternalTable, QueryBuilder, Row, Type};
+
+#[derive(Row, Debug, PartialEq)]
+struct IdRow {
+    id: u64,
+}
+
+#[derive(Row, Debug, PartialEq)]
+struct NumberRow {
+    number: u64,
+}
+
+#[tokio::test]
+async fn test_external_table_filter() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    let ids = ExternalTable::from_rows(
+        "_ids",
+        [("id", Type::UInt64)],
+        vec![IdRow { id: 3 }, IdRow { id: 7 }, IdRow { id: 42 }],
+    )
+    .unwrap();
+    let rows: Vec<NumberRow> = client
+        .query_collect(
+            QueryBuilder::new(
+                "SELECT number FROM numbers(10) WHERE number IN _ids ORDER BY number",
+            )
+            .external_table(ids),
+        )
+        .await
+        .unwrap();
+    assert_eq!(rows, vec![NumberRow { number: 3 }, NumberRow { number: 7 }]);
+}
+
+#[tokio::test]
+async fn test_external_table_empty() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    let ids = ExternalTable::from_rows::<IdRow>("_ids", [("id", Type::UInt64)], vec![]).unwrap();
+    let rows: Vec<IdRow> = client
+        .query_collect(QueryBuilder::new("SELECT id FROM _ids").external_table(ids))
+        .await
+        .unwrap();
+    assert!(rows.is_empty());
+}
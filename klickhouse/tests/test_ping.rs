@@ -0,0 +1,19 @@
+use std::time::Duration;
+
+use klickhouse::RawRow;
+
+#[tokio::test]
+async fn test_ping() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    client.ping(Duration::from_secs(5)).await.unwrap();
+    client
+        .query_collect::<RawRow>("SELECT number FROM numbers(10)")
+        .await
+        .unwrap();
+    client.ping(Duration::from_secs(5)).await.unwrap();
+    assert!(!client.is_closed());
+}
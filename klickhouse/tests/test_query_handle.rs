@@ -0,0 +1,107 @@
+use futures_util::StreamExt;
+use klickhouse::{QueryBuilder, QueryEvent, Row, ServerLogLevel};
+
+#[derive(Row, Debug, PartialEq)]
+struct GroupRow {
+    k: u64,
+    c: u64,
+}
+
+#[tokio::test]
+async fn test_query_handle_totals() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    let handle = client
+        .query_handle(
+            "SELECT number % 3 AS k, count() AS c FROM numbers(10) GROUP BY k WITH TOTALS ORDER BY k",
+        )
+        .await
+        .unwrap();
+    let id = handle.id().to_string();
+    let output = handle.collect::<GroupRow>().await.unwrap();
+    assert_eq!(output.id, id);
+    assert_eq!(
+        output.rows,
+        vec![
+            GroupRow { k: 0, c: 4 },
+            GroupRow { k: 1, c: 3 },
+            GroupRow { k: 2, c: 3 },
+        ]
+    );
+    assert_eq!(output.totals, Some(GroupRow { k: 0, c: 10 }));
+    assert!(output.extremes.is_empty());
+    assert!(output.profile_info.is_some());
+    assert_eq!(output.progress.read_rows, 10);
+}
+
+#[tokio::test]
+async fn test_query_handle_extremes_and_rows_before_limit() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    let mut handle = client
+        .query_handle(
+            QueryBuilder::new(
+                "SELECT number AS k, number * 2 AS c FROM numbers(100) ORDER BY k LIMIT 5",
+            )
+            .setting("extremes", true),
+        )
+        .await
+        .unwrap();
+    let mut rows = 0;
+    while let Some(block) = handle.next_block().await {
+        rows += block.unwrap().rows;
+    }
+    assert_eq!(rows, 5);
+    assert_eq!(handle.rows_before_limit(), Some(100));
+    assert_eq!(handle.extremes().map(|block| block.rows), Some(2));
+}
+
+#[tokio::test]
+async fn test_query_handle_server_logs() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    let mut handle = client
+        .query_handle(
+            QueryBuilder::new("SELECT count() FROM numbers(1000)")
+                .logs_level(ServerLogLevel::Trace),
+        )
+        .await
+        .unwrap();
+    let id = handle.id().to_string();
+    let mut logs = vec![];
+    while let Some(event) = handle.next().await {
+        if let QueryEvent::Log(record) = event.unwrap() {
+            logs.push(record);
+        }
+    }
+    assert!(!logs.is_empty());
+    assert!(logs.iter().all(|record| record.query_id == id));
+}
+
+#[tokio::test]
+async fn test_query_handle_profile_events() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    let output = client
+        .query_handle("SELECT number AS k, number AS c FROM numbers(100000)")
+        .await
+        .unwrap()
+        .collect::<GroupRow>()
+        .await
+        .unwrap();
+    assert_eq!(output.rows.len(), 100000);
+    assert!(output.progress.elapsed_ns.is_some());
+    assert!(output.profile_events.contains_key("MemoryTrackerPeakUsage"));
+}
@@ -1,11 +1,16 @@
 use std::time::Duration;
 
-use klickhouse::{ClickhouseEnum, Client, ClientOptions, Row};
+use klickhouse::{ClickhouseEnum, Client, ClientOptions, Dsn, Row};
 
 /// Helper to get a client with custom options
 async fn get_client_with_options(opts: ClientOptions) -> Client {
-    let address = std::env::var("KLICKHOUSE_TEST_ADDR").unwrap_or_else(|_| "127.0.0.1:9000".into());
-    Client::connect(address, opts).await.unwrap()
+    Dsn {
+        options: opts,
+        ..super::test_dsn()
+    }
+    .connect()
+    .await
+    .unwrap()
 }
 
 // ============================================================
@@ -222,6 +227,8 @@ async fn test_server_error_propagation() {
         matches!(err, klickhouse::KlickhouseError::ServerException { .. }),
         "expected ServerException, got: {err:?}"
     );
+    assert_eq!(err.server_code(), Some(klickhouse::ErrorCode::UnknownTable));
+    assert!(!err.is_retryable());
 }
 
 // ============================================================
@@ -321,16 +328,11 @@ async fn test_nullable_roundtrip() {
 #[cfg(feature = "bb8")]
 #[tokio::test]
 async fn test_bb8_pool() {
-    use klickhouse::ConnectionManager;
-
     let _ = env_logger::builder()
         .filter_level(log::LevelFilter::Info)
         .try_init();
 
-    let address = std::env::var("KLICKHOUSE_TEST_ADDR").unwrap_or_else(|_| "127.0.0.1:9000".into());
-    let manager = ConnectionManager::new(address, ClientOptions::default())
-        .await
-        .unwrap();
+    let manager = super::test_dsn().connection_manager();
 
     let pool = bb8::Pool::builder()
         .max_size(3)
@@ -362,16 +364,11 @@ async fn test_bb8_pool() {
 #[cfg(feature = "bb8")]
 #[tokio::test]
 async fn test_bb8_pool_connection_reuse() {
-    use klickhouse::ConnectionManager;
-
     let _ = env_logger::builder()
         .filter_level(log::LevelFilter::Info)
         .try_init();
 
-    let address = std::env::var("KLICKHOUSE_TEST_ADDR").unwrap_or_else(|_| "127.0.0.1:9000".into());
-    let manager = ConnectionManager::new(address, ClientOptions::default())
-        .await
-        .unwrap();
+    let manager = super::test_dsn().connection_manager();
 
     let pool = bb8::Pool::builder()
         .max_size(1) // Force reuse
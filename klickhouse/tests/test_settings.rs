@@ -0,0 +1,95 @@
+use klickhouse::{ClientOptions, Dsn, QueryBuilder, QuerySettings, Row};
+
+#[derive(Row, Debug)]
+struct SettingRow {
+    n: u64,
+}
+
+const QUERY: &str = "SELECT toUInt64(getSetting('max_block_size')) AS n";
+
+#[tokio::test]
+async fn test_connection_settings() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let options = ClientOptions {
+        settings: QuerySettings::new().with("max_block_size", 1234u64),
+        ..Default::default()
+    };
+    let client = Dsn {
+        options,
+        ..super::test_dsn()
+    }
+    .connect()
+    .await
+    .unwrap();
+
+    let row: SettingRow = client.query_one(QUERY).await.unwrap();
+    assert_eq!(row.n, 1234);
+}
+
+#[tokio::test]
+async fn test_query_settings_override() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    let row: SettingRow = client
+        .query_one(QueryBuilder::new(QUERY).setting("max_block_size", 4321u64))
+        .await
+        .unwrap();
+    assert_eq!(row.n, 4321);
+
+    // Per-query settings don't leak into the following queries.
+    let row: SettingRow = client.query_one(QUERY).await.unwrap();
+    assert_ne!(row.n, 4321);
+}
+
+#[tokio::test]
+async fn test_unknown_setting_is_rejected() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    let result = client
+        .execute(QueryBuilder::new("SELECT 1").setting("not_a_real_setting_xyz", 1u64))
+        .await;
+    assert!(result.is_err());
+}
+
+#[derive(Row, Debug, PartialEq)]
+struct ParamRow {
+    id: u64,
+    name: String,
+    tags: Vec<String>,
+}
+
+#[tokio::test]
+async fn test_query_parameters() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    let row: ParamRow = client
+        .query_one(
+            QueryBuilder::new(
+                "SELECT {id:UInt64} AS id, {name:String} AS name, {tags:Array(String)} AS tags",
+            )
+            .param("id", 42u64)
+            .param("name", "it's a \\ test\n")
+            .param("tags", vec!["a'b".to_string(), "c".to_string()]),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        row,
+        ParamRow {
+            id: 42,
+            name: "it's a \\ test\n".to_string(),
+            tags: vec!["a'b".to_string(), "c".to_string()],
+        }
+    );
+}
@@ -0,0 +1,30 @@
+use super::prepare_table;
+
+#[tokio::test]
+async fn test_tables_status() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    prepare_table("test_tables_status", "id UInt64", &client).await;
+    let database: String = client
+        .query_one::<klickhouse::UnitValue<String>>("SELECT currentDatabase()")
+        .await
+        .unwrap()
+        .0;
+
+    let status = client
+        .tables_status(&[
+            (database.as_str(), "test_tables_status"),
+            (database.as_str(), "test_tables_status_missing"),
+        ])
+        .await
+        .unwrap();
+    let table = status.get(&database, "test_tables_status").unwrap();
+    assert!(!table.is_replicated);
+    assert_eq!(table.absolute_delay, 0);
+    assert!(status
+        .get(&database, "test_tables_status_missing")
+        .is_none());
+}
@@ -0,0 +1,19 @@
+use std::time::Duration;
+
+use klickhouse::{KlickhouseError, ParsedQuery, RawRow};
+
+#[tokio::test]
+async fn test_query_timeout() {
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+    let client = super::get_client().await;
+
+    let query = ParsedQuery::new("SELECT sleep(3)").with_timeout(Duration::from_millis(500));
+    let result = client.query_collect::<RawRow>(&query).await;
+    assert!(matches!(result, Err(KlickhouseError::Timeout(_))));
+
+    // The query was cancelled on the server, and the connection is still usable.
+    let row = client.query_one::<RawRow>("SELECT 1 AS x").await.unwrap();
+    assert_eq!(row.len(), 1);
+}